@@ -0,0 +1,44 @@
+// Runs arbitrary bytes as a program under every quirk preset.
+//
+// The core must never panic, and must stop with a `Chip8Error` (or keep
+// running) rather than looping inside a single cycle, which libFuzzer
+// reports as a timeout.
+
+#![no_main]
+
+use chip8::rom::{self, LoadOptions};
+use chip8::{Chip8, Quirks};
+use libfuzzer_sys::fuzz_target;
+
+// the most that fits below the display
+const PROGRAM_SIZE: usize = 0xF00 - rom::PROGRAM_START as usize;
+
+const FRAMES: u32 = 60;
+const CYCLES_PER_FRAME: u32 = 20;
+
+fuzz_target!(|data: &[u8]| {
+    let program = &data[..data.len().min(PROGRAM_SIZE)];
+    if program.is_empty() {
+        return;
+    }
+
+    for &(_, quirks) in Quirks::PRESETS.iter() {
+        let mut chip = Chip8::with_seed(0);
+        chip.set_quirks(quirks);
+        rom::load(&mut chip, program, &LoadOptions::default()).unwrap();
+
+        'running: for frame in 0..FRAMES {
+            // hold a different key each frame, so key waits make progress
+            for key in 0..16 {
+                chip.write_keypad(key, frame % 16 == key as u32);
+            }
+            for _ in 0..CYCLES_PER_FRAME {
+                if let Err(e) = chip.cycle() {
+                    assert!(!e.to_string().is_empty());
+                    break 'running;
+                }
+            }
+            chip.tick_timers();
+        }
+    }
+});
@@ -0,0 +1,285 @@
+// A static scan of the instructions a program uses, to tell which platform
+// and quirks it needs before running it.
+//
+// Instructions are found by following the program from its entry point
+// through jumps, calls and skips, so data mixed in with the code is not
+// mistaken for instructions. Jumps through 0xBNNN cannot be followed.
+
+use crate::rom::LoadOptions;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+}
+
+// what an instruction is, as its pattern (e.g. `8XY4`), and the first
+// platform that has it, or `None` if no platform does
+fn classify(instruction: u16) -> Option<(&'static str, Platform)> {
+    let n = instruction & 0xF;
+    let nn = instruction & 0xFF;
+    Some(match instruction >> 12 {
+        0x0 => match instruction {
+            0x00E0 => ("00E0", Platform::Chip8),
+            0x00EE => ("00EE", Platform::Chip8),
+            0x00FB => ("00FB", Platform::SuperChip),
+            0x00FC => ("00FC", Platform::SuperChip),
+            0x00FD => ("00FD", Platform::SuperChip),
+            0x00FE => ("00FE", Platform::SuperChip),
+            0x00FF => ("00FF", Platform::SuperChip),
+            _ if instruction & 0xFFF0 == 0x00C0 => ("00CN", Platform::SuperChip),
+            _ if instruction & 0xFFF0 == 0x00D0 => ("00DN", Platform::XoChip),
+            _ => ("0NNN", Platform::Chip8),
+        },
+        0x1 => ("1NNN", Platform::Chip8),
+        0x2 => ("2NNN", Platform::Chip8),
+        0x3 => ("3XNN", Platform::Chip8),
+        0x4 => ("4XNN", Platform::Chip8),
+        0x5 => match n {
+            0x0 => ("5XY0", Platform::Chip8),
+            0x2 => ("5XY2", Platform::XoChip),
+            0x3 => ("5XY3", Platform::XoChip),
+            _ => return None,
+        },
+        0x6 => ("6XNN", Platform::Chip8),
+        0x7 => ("7XNN", Platform::Chip8),
+        0x8 => match n {
+            0x0 => ("8XY0", Platform::Chip8),
+            0x1 => ("8XY1", Platform::Chip8),
+            0x2 => ("8XY2", Platform::Chip8),
+            0x3 => ("8XY3", Platform::Chip8),
+            0x4 => ("8XY4", Platform::Chip8),
+            0x5 => ("8XY5", Platform::Chip8),
+            0x6 => ("8XY6", Platform::Chip8),
+            0x7 => ("8XY7", Platform::Chip8),
+            0xE => ("8XYE", Platform::Chip8),
+            _ => return None,
+        },
+        0x9 if n == 0 => ("9XY0", Platform::Chip8),
+        0x9 => return None,
+        0xA => ("ANNN", Platform::Chip8),
+        0xB => ("BNNN", Platform::Chip8),
+        0xC => ("CXNN", Platform::Chip8),
+        0xD if n == 0 => ("DXY0", Platform::SuperChip),
+        0xD => ("DXYN", Platform::Chip8),
+        0xE => match nn {
+            0x9E => ("EX9E", Platform::Chip8),
+            0xA1 => ("EXA1", Platform::Chip8),
+            _ => return None,
+        },
+        _ => match (instruction, nn) {
+            (0xF000, _) => ("F000", Platform::XoChip),
+            (0xF002, _) => ("F002", Platform::XoChip),
+            (_, 0x01) => ("FN01", Platform::XoChip),
+            (_, 0x07) => ("FX07", Platform::Chip8),
+            (_, 0x0A) => ("FX0A", Platform::Chip8),
+            (_, 0x15) => ("FX15", Platform::Chip8),
+            (_, 0x18) => ("FX18", Platform::Chip8),
+            (_, 0x1E) => ("FX1E", Platform::Chip8),
+            (_, 0x29) => ("FX29", Platform::Chip8),
+            (_, 0x30) => ("FX30", Platform::SuperChip),
+            (_, 0x33) => ("FX33", Platform::Chip8),
+            (_, 0x3A) => ("FX3A", Platform::XoChip),
+            (_, 0x55) => ("FX55", Platform::Chip8),
+            (_, 0x65) => ("FX65", Platform::Chip8),
+            (_, 0x75) => ("FX75", Platform::SuperChip),
+            (_, 0x85) => ("FX85", Platform::SuperChip),
+            _ => return None,
+        },
+    })
+}
+
+// whether an instruction reads or writes memory at I
+fn uses_i(instruction: u16) -> bool {
+    let nn = instruction & 0xFF;
+    instruction >> 12 == 0xD || (instruction >> 12 == 0xF && [0x1E, 0x33, 0x55, 0x65].contains(&nn))
+}
+
+// whether an instruction sets I or leaves the straight line of code, after
+// which what I points at is no longer known
+fn ends_use_of_i(instruction: u16) -> bool {
+    let nn = instruction & 0xFF;
+    matches!(instruction >> 12, 0x1 | 0x2 | 0xA | 0xB)
+        || instruction == 0x00EE
+        || instruction == 0xF000
+        || (instruction >> 12 == 0xF && (nn == 0x29 || nn == 0x30))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Analysis {
+    pub instructions: BTreeMap<&'static str, usize>, // how often each instruction is used
+    pub reachable: usize,                           // instructions found from the entry point
+    pub invalid: Vec<u16>,                          // addresses of instructions no platform has
+    pub super_chip: Vec<u16>,                       // addresses of SUPER-CHIP instructions
+    pub xo_chip: Vec<u16>,                          // addresses of XO-CHIP instructions
+    pub machine_code: Vec<u16>,                     // addresses of 0NNN calls
+    pub shifts: Vec<u16>,                           // 8XY6 and 8XYE with X and Y different
+    pub load_store: Vec<u16>,                       // FX55 and FX65 followed by a use of I
+    pub jumps: Vec<u16>,                            // BNNN, which depends on the jump quirk
+    pub self_modifying: Vec<u16>,                   // writes through I pointing at code
+}
+
+impl Analysis {
+    // the earliest platform with every instruction the program uses
+    pub fn platform(&self) -> Platform {
+        if !self.xo_chip.is_empty() {
+            Platform::XoChip
+        } else if !self.super_chip.is_empty() {
+            Platform::SuperChip
+        } else {
+            Platform::Chip8
+        }
+    }
+}
+
+pub fn analyse(program: &[u8], options: &LoadOptions) -> Analysis {
+    let start = options.address as usize;
+    let fetch = |addr: usize| -> Option<u16> {
+        let offset = addr.checked_sub(start)?;
+        Some((*program.get(offset)? as u16) << 8 | *program.get(offset + 1)? as u16)
+    };
+
+    // follow the program from its entry point
+    let mut code = BTreeSet::new();
+    let mut pending = vec![options.entry as usize];
+    while let Some(pc) = pending.pop() {
+        let Some(instruction) = fetch(pc) else { continue };
+        if !code.insert(pc) {
+            continue;
+        }
+        let nnn = (instruction & 0xFFF) as usize;
+        // XO-CHIP's F000 NNNN is four bytes long
+        let skip = if fetch(pc + 2) == Some(0xF000) { 6 } else { 4 };
+        match classify(instruction) {
+            Some(("00EE", _)) | Some(("00FD", _)) | Some(("BNNN", _)) | None => {}
+            Some(("1NNN", _)) => pending.push(nnn),
+            Some(("2NNN", _)) => pending.extend([pc + 2, nnn]),
+            Some(("3XNN", _)) | Some(("4XNN", _)) | Some(("5XY0", _)) | Some(("9XY0", _)) | Some(("EX9E", _))
+            | Some(("EXA1", _)) => pending.extend([pc + 2, pc + skip]),
+            Some(("F000", _)) => pending.push(pc + 4),
+            Some(_) => pending.push(pc + 2),
+        }
+    }
+
+    let mut analysis = Analysis {
+        reachable: code.len(),
+        ..Analysis::default()
+    };
+    for &pc in &code {
+        let instruction = fetch(pc).unwrap();
+        let addr = pc as u16;
+        let Some((pattern, platform)) = classify(instruction) else {
+            analysis.invalid.push(addr);
+            continue;
+        };
+        *analysis.instructions.entry(pattern).or_default() += 1;
+        match platform {
+            Platform::SuperChip => analysis.super_chip.push(addr),
+            Platform::XoChip => analysis.xo_chip.push(addr),
+            Platform::Chip8 => {}
+        }
+
+        let x = instruction >> 8 & 0xF;
+        let y = instruction >> 4 & 0xF;
+        match pattern {
+            "0NNN" => analysis.machine_code.push(addr),
+            "8XY6" | "8XYE" if x != y => analysis.shifts.push(addr),
+            "BNNN" => analysis.jumps.push(addr),
+            "FX55" | "FX65" => {
+                // I is used again before it is set, in a straight line
+                let next = (pc + 2..)
+                    .step_by(2)
+                    .map_while(|pc| code.contains(&pc).then(|| fetch(pc).unwrap()))
+                    .take_while(|&next| !ends_use_of_i(next))
+                    .find(|&next| uses_i(next));
+                if next.is_some() {
+                    analysis.load_store.push(addr);
+                }
+            }
+            "ANNN" => {
+                // a write through I before it is set again, pointing at code
+                let target = (instruction & 0xFFF) as usize;
+                let writes = (pc + 2..)
+                    .step_by(2)
+                    .map_while(|pc| code.contains(&pc).then(|| fetch(pc).unwrap()))
+                    .take_while(|&next| !ends_use_of_i(next))
+                    .any(|next| next >> 12 == 0xF && [0x33, 0x55].contains(&(next & 0xFF)));
+                if writes && (target..target + 16).any(|addr| code.contains(&addr)) {
+                    analysis.self_modifying.push(addr);
+                }
+            }
+            _ => {}
+        }
+    }
+    analysis
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(instructions: &[u16]) -> Vec<u8> {
+        instructions.iter().flat_map(|instruction| instruction.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn follows_the_program() {
+        // the data after the jump is never run
+        let analysis = analyse(&program(&[0x6001, 0x3000, 0x00E0, 0x1206, 0xFFFF]), &LoadOptions::default());
+        assert_eq!(analysis.reachable, 4);
+        assert!(analysis.invalid.is_empty());
+        assert_eq!(analysis.instructions["6XNN"], 1);
+        assert_eq!(analysis.platform(), Platform::Chip8);
+
+        let analysis = analyse(&program(&[0x2204, 0x1202, 0x00FF, 0x00EE]), &LoadOptions::default());
+        assert_eq!(analysis.super_chip, [0x204]);
+        assert_eq!(analysis.platform(), Platform::SuperChip);
+
+        // a skip over XO-CHIP's four byte F000 NNNN skips all of it
+        let analysis = analyse(&program(&[0x3000, 0xF000, 0x1234, 0x1206]), &LoadOptions::default());
+        assert_eq!(analysis.xo_chip, [0x202]);
+        assert_eq!(analysis.reachable, 3);
+        assert_eq!(analysis.platform(), Platform::XoChip);
+    }
+
+    #[test]
+    fn quirks_and_oddities() {
+        let analysis = analyse(
+            &program(&[
+                0x8126, 0x8116, 0x0300, 0xA200, 0xF255, 0xF065, 0xD125, 0xF029, 0xF165, 0xF229, 0xD125, 0xB200,
+                0xF001,
+            ]),
+            &LoadOptions::default(),
+        );
+        assert_eq!(analysis.shifts, [0x200]);
+        assert_eq!(analysis.machine_code, [0x204]);
+        assert_eq!(analysis.self_modifying, [0x206]);
+        assert_eq!(analysis.load_store, [0x208, 0x20A]);
+        assert_eq!(analysis.jumps, [0x216]);
+        // after BNNN nothing more can be followed
+        assert_eq!(analysis.reachable, 12);
+    }
+
+    #[test]
+    fn instructions_that_set_i() {
+        for instruction in [0xA123, 0xF000, 0xF129, 0xF130, 0x2300, 0x00EE] {
+            assert!(ends_use_of_i(instruction), "{:04X}", instruction);
+        }
+        for instruction in [0xF11E, 0xF155, 0xD125, 0x6000] {
+            assert!(!ends_use_of_i(instruction), "{:04X}", instruction);
+        }
+    }
+}
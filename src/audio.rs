@@ -0,0 +1,282 @@
+// Audio backends for the sound timer.
+//
+// The core only reports whether the sound timer is active, backends decide
+// how (or whether) that becomes audible. Each backend is driven once per
+// 60Hz frame.
+
+use crate::chip8::SoundEvent;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const FRAME_RATE: u32 = 60;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+pub trait AudioBackend {
+    // called when the sound timer starts or stops sounding
+    fn event(&mut self, _event: SoundEvent) {}
+
+    // called once per frame with whether the tone is sounding during that frame
+    fn frame(&mut self, _active: bool) {}
+}
+
+// square wave generator
+//
+// frequency is in Hz, volume is in the range 0.0..=1.0
+#[derive(Clone, Debug)]
+pub struct SquareWave {
+    frequency: f32,
+    volume: f32,
+    sample_rate: u32,
+    phase: f32, // position within the current period, 0.0..1.0
+}
+
+impl SquareWave {
+    pub fn new(frequency: f32, volume: f32, sample_rate: u32) -> SquareWave {
+        SquareWave {
+            frequency: frequency.max(0.0),
+            volume: volume.clamp(0.0, 1.0),
+            sample_rate: sample_rate.max(1),
+            phase: 0.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // number of samples that make up a single 60Hz frame
+    pub fn samples_per_frame(&self) -> usize {
+        (self.sample_rate / FRAME_RATE) as usize
+    }
+
+    // fill `buffer` with the tone when `active`, otherwise with silence
+    //
+    // the phase is kept while silent so consecutive tones join up without clicks
+    pub fn fill(&mut self, buffer: &mut [f32], active: bool) {
+        let step = self.frequency / self.sample_rate as f32;
+        for sample in buffer.iter_mut() {
+            *sample = if !active {
+                0.0
+            } else if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+impl Default for SquareWave {
+    fn default() -> SquareWave {
+        SquareWave::new(DEFAULT_FREQUENCY, DEFAULT_VOLUME, DEFAULT_SAMPLE_RATE)
+    }
+}
+
+// discards all sound
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {}
+
+// rings the terminal bell once each time a tone starts
+pub struct TerminalBell<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TerminalBell<W> {
+    pub fn new(out: W) -> TerminalBell<W> {
+        TerminalBell { out }
+    }
+}
+
+impl<W: Write> AudioBackend for TerminalBell<W> {
+    fn event(&mut self, event: SoundEvent) {
+        if event == SoundEvent::Started {
+            // no newline, so the display is left untouched
+            let _ = self.out.write_all(b"\x07");
+            let _ = self.out.flush();
+        }
+    }
+}
+
+// flashes the terminal (reverse video) for as long as a tone is playing,
+// for environments without sound
+pub struct VisualBell<W: Write> {
+    out: W,
+}
+
+impl<W: Write> VisualBell<W> {
+    pub fn new(out: W) -> VisualBell<W> {
+        VisualBell { out }
+    }
+}
+
+impl<W: Write> AudioBackend for VisualBell<W> {
+    fn event(&mut self, event: SoundEvent) {
+        let sequence: &[u8] = match event {
+            SoundEvent::Started => b"\x1b[?5h",
+            SoundEvent::Stopped => b"\x1b[?5l",
+        };
+        let _ = self.out.write_all(sequence);
+        let _ = self.out.flush();
+    }
+}
+
+impl<W: Write> Drop for VisualBell<W> {
+    fn drop(&mut self) {
+        let _ = self.out.write_all(b"\x1b[?5l");
+        let _ = self.out.flush();
+    }
+}
+
+// synthesises the tone into a buffer and writes it out as a 16 bit mono
+// WAV file, useful for checking sound output in tests
+pub struct WavAudio {
+    path: PathBuf,
+    wave: SquareWave,
+    frame_buffer: Vec<f32>,
+    samples: Vec<i16>,
+    finished: bool,
+}
+
+impl WavAudio {
+    pub fn new<P: AsRef<Path>>(path: P, wave: SquareWave) -> WavAudio {
+        WavAudio {
+            path: path.as_ref().to_path_buf(),
+            frame_buffer: vec![0.0; wave.samples_per_frame()],
+            wave,
+            samples: Vec::new(),
+            finished: false,
+        }
+    }
+
+    // all samples synthesised so far
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    // write the WAV file, this also happens when the backend is dropped
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        let mut f = fs::File::create(&self.path)?;
+        write_wav(&mut f, self.wave.sample_rate(), &self.samples)
+    }
+}
+
+impl AudioBackend for WavAudio {
+    fn frame(&mut self, active: bool) {
+        self.wave.fill(&mut self.frame_buffer, active);
+        self.samples.extend(
+            self.frame_buffer
+                .iter()
+                .map(|&sample| (sample * i16::MAX as f32) as i16),
+        );
+    }
+}
+
+impl Drop for WavAudio {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish();
+        }
+    }
+}
+
+// write 16 bit mono PCM samples as a RIFF WAV file
+pub fn write_wav<W: Write>(out: &mut W, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    // format chunk
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?; // chunk size
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // channels
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // byte rate
+    out.write_all(&2u16.to_le_bytes())?; // block align
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+    // data chunk
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+    use std::env;
+    use std::process;
+
+    fn le_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn square_wave() {
+        // a period of 8 samples
+        let mut wave = SquareWave::new(1000.0, 0.5, 8000);
+        let mut buffer = [0.0; 12];
+        wave.fill(&mut buffer, true);
+        assert_eq!(buffer, [0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5, 0.5, 0.5, 0.5, 0.5]);
+
+        // the wave keeps running while silent, so tones stay in phase
+        wave.fill(&mut buffer[..2], false);
+        assert_eq!(buffer[..2], [0.0, 0.0]);
+        wave.fill(&mut buffer[..4], true);
+        assert_eq!(buffer[..4], [-0.5, -0.5, 0.5, 0.5]);
+
+        let mut loud = SquareWave::new(1000.0, 3.0, 8000);
+        loud.fill(&mut buffer, true);
+        assert_eq!(buffer[0], 1.0);
+        assert_eq!(SquareWave::default().samples_per_frame(), 735);
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 8000, &[1, -2, i16::MAX]).unwrap();
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(le_u32(&wav, 4), 36 + 6);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(le_u32(&wav, 16), 16);
+        assert_eq!(le_u32(&wav, 24), 8000);
+        assert_eq!(le_u32(&wav, 28), 16000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(le_u32(&wav, 40), 6);
+        assert_eq!(wav[44..], [1, 0, 0xFE, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn wav_backend() {
+        let path = env::temp_dir().join(format!("chip8-audio-{}.wav", process::id()));
+        let mut audio = WavAudio::new(&path, SquareWave::new(1000.0, 1.0, 8000));
+        audio.frame(true);
+        audio.frame(false);
+        // 133 samples a frame
+        assert_eq!(audio.samples().len(), 266);
+        assert_eq!(audio.samples()[..6], [i16::MAX, i16::MAX, i16::MAX, i16::MAX, -i16::MAX, -i16::MAX]);
+        assert!(audio.samples()[133..].iter().all(|&sample| sample == 0));
+
+        // written when dropped
+        drop(audio);
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(wav.len(), 44 + 532);
+        assert_eq!(le_u32(&wav, 40), 532);
+    }
+}
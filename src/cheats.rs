@@ -0,0 +1,258 @@
+// A cheat engine: finding the variables a program keeps in memory, such as
+// lives or the score, and freezing or changing them.
+//
+// A search starts with every address in program memory, then narrows them
+// down by comparing each with its value when the search last looked, e.g.
+// after losing a life keep the addresses whose values decreased. Cheats
+// found this way hold an address at a value every frame while enabled.
+
+use crate::chip8::{Chip8, DISPLAY_OFFSET};
+
+use std::fmt;
+
+// how to narrow down a search
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    fn keep(self, before: u8, now: u8) -> bool {
+        match self {
+            Filter::Equal(value) => now == value,
+            Filter::Changed => now != before,
+            Filter::Unchanged => now == before,
+            Filter::Increased => now > before,
+            Filter::Decreased => now < before,
+        }
+    }
+}
+
+// the addresses that could still be the one searched for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Search {
+    candidates: Vec<(u16, u8)>, // address and its value when last looked at
+}
+
+impl Search {
+    // every address below the display
+    pub fn new(chip: &Chip8) -> Search {
+        Search {
+            candidates: (0..DISPLAY_OFFSET).map(|addr| (addr, chip.read(addr))).collect(),
+        }
+    }
+
+    pub fn filter(&mut self, chip: &Chip8, filter: Filter) {
+        self.candidates.retain(|&(addr, before)| filter.keep(before, chip.read(addr)));
+        for (addr, value) in &mut self.candidates {
+            *value = chip.read(*addr);
+        }
+    }
+
+    pub fn candidates(&self) -> &[(u16, u8)] {
+        &self.candidates
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub address: u16,
+    pub value: u8,
+    pub enabled: bool,
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.name.is_empty() {
+            write!(f, "{} ", self.name)?;
+        }
+        write!(f, "{:03X}={} {}", self.address, self.value, if self.enabled { "on" } else { "off" })
+    }
+}
+
+pub const COMMANDS: &str = "\
+search, equal <n>, changed, unchanged, increased, decreased, poke <addr> <n>, \
+freeze <addr> [<n>] [<name>], cheats, toggle <i>, delete <i>";
+
+// the player's cheats and memory search
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheatEngine {
+    pub cheats: Vec<Cheat>,
+    search: Option<Search>,
+}
+
+impl CheatEngine {
+    pub fn new(cheats: Vec<Cheat>) -> CheatEngine {
+        CheatEngine { cheats, search: None }
+    }
+
+    // hold the enabled cheats' addresses at their values
+    pub fn apply(&self, chip: &mut Chip8) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            chip.write(cheat.address, cheat.value);
+        }
+    }
+
+    // run a command typed in the player, returning a message to show
+    //
+    // Addresses are hex, as they are shown, and values are decimal unless
+    // they start with 0x. Cheats are numbered from 1 as `cheats` lists them.
+    pub fn command(&mut self, chip: &mut Chip8, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let filter = match (command, &args[..]) {
+            ("search", []) => {
+                let search = Search::new(chip);
+                let message = format!("Searching {} addresses", search.candidates().len());
+                self.search = Some(search);
+                return Ok(message);
+            }
+            ("equal" | "=", [value]) => Filter::Equal(parse_value(value)?),
+            ("changed", []) => Filter::Changed,
+            ("unchanged", []) => Filter::Unchanged,
+            ("increased", []) => Filter::Increased,
+            ("decreased", []) => Filter::Decreased,
+            ("poke", [address, value]) => {
+                let (address, value) = (parse_address(address)?, parse_value(value)?);
+                chip.write(address, value);
+                return Ok(format!("{:03X}={}", address, value));
+            }
+            ("freeze", [address, rest @ ..]) => {
+                let address = parse_address(address)?;
+                let (value, name) = match rest.split_first() {
+                    Some((value, name)) if parse_value(value).is_ok() => (parse_value(value)?, name),
+                    _ => (chip.read(address), rest),
+                };
+                let cheat = Cheat {
+                    name: name.join(" "),
+                    address,
+                    value,
+                    enabled: true,
+                };
+                let message = format!("Cheat {}: {}", self.cheats.len() + 1, cheat);
+                self.cheats.push(cheat);
+                return Ok(message);
+            }
+            ("cheats", []) if self.cheats.is_empty() => return Ok("No cheats".to_string()),
+            ("cheats", []) => {
+                let cheats: Vec<String> =
+                    self.cheats.iter().enumerate().map(|(i, cheat)| format!("{} {}", i + 1, cheat)).collect();
+                return Ok(cheats.join(", "));
+            }
+            ("toggle", [index]) => {
+                let index = self.index(index)?;
+                let cheat = &mut self.cheats[index];
+                cheat.enabled = !cheat.enabled;
+                return Ok(format!("Cheat {}: {}", index + 1, cheat));
+            }
+            ("delete", [index]) => {
+                let index = self.index(index)?;
+                let cheat = self.cheats.remove(index);
+                return Ok(format!("Deleted {}", cheat));
+            }
+            _ => return Err(format!("Unknown command '{}', try {}", line.trim(), COMMANDS)),
+        };
+
+        let search = self.search.as_mut().ok_or("Start a search first")?;
+        search.filter(chip, filter);
+        Ok(describe(search.candidates()))
+    }
+
+    // a cheat's index from its number
+    fn index(&self, number: &str) -> Result<usize, String> {
+        match number.parse::<usize>() {
+            Ok(number) if (1..=self.cheats.len()).contains(&number) => Ok(number - 1),
+            _ => Err(format!("No cheat {}", number)),
+        }
+    }
+}
+
+// how many addresses are left, and the first few of them
+fn describe(candidates: &[(u16, u8)]) -> String {
+    const SHOWN: usize = 6;
+    let shown: Vec<String> =
+        candidates.iter().take(SHOWN).map(|(addr, value)| format!("{:03X}={}", addr, value)).collect();
+    match candidates.len() {
+        0 => "No addresses left, start a new search".to_string(),
+        1 => format!("1 address: {}", shown[0]),
+        n if n <= SHOWN => format!("{} addresses: {}", n, shown.join(" ")),
+        n => format!("{} addresses: {} ...", n, shown.join(" ")),
+    }
+}
+
+pub(crate) fn parse_address(s: &str) -> Result<u16, String> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    match u16::from_str_radix(hex, 16) {
+        Ok(address) if address < DISPLAY_OFFSET => Ok(address),
+        _ => Err(format!("Invalid address '{}'", s)),
+    }
+}
+
+fn parse_value(s: &str) -> Result<u8, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid value '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches() {
+        let mut chip = Chip8::with_seed(0);
+        let mut engine = CheatEngine::default();
+        assert!(engine.command(&mut chip, "changed").is_err(), "no search yet");
+
+        chip.write(0x300, 3);
+        chip.write(0x301, 3);
+        chip.write(0x302, 5);
+        assert_eq!(engine.command(&mut chip, "search"), Ok("Searching 3840 addresses".to_string()));
+        assert_eq!(engine.command(&mut chip, "equal 3"), Ok("2 addresses: 300=3 301=3".to_string()));
+
+        chip.write(0x300, 2);
+        assert_eq!(engine.command(&mut chip, "decreased"), Ok("1 address: 300=2".to_string()));
+        assert_eq!(engine.command(&mut chip, "unchanged"), Ok("1 address: 300=2".to_string()));
+        assert_eq!(engine.command(&mut chip, "increased"), Ok("No addresses left, start a new search".to_string()));
+
+        engine.command(&mut chip, "search").unwrap();
+        chip.write(0x302, 6);
+        assert_eq!(engine.command(&mut chip, "changed"), Ok("1 address: 302=6".to_string()));
+    }
+
+    #[test]
+    fn cheats() {
+        let mut chip = Chip8::with_seed(0);
+        let mut engine = CheatEngine::default();
+        chip.write(0x300, 1);
+        assert_eq!(engine.command(&mut chip, "poke 301 0x10"), Ok("301=16".to_string()));
+        assert_eq!(chip.read(0x301), 0x10);
+
+        let frozen = engine.command(&mut chip, "freeze 300 9 extra lives");
+        assert_eq!(frozen, Ok("Cheat 1: extra lives 300=9 on".to_string()));
+        assert_eq!(engine.command(&mut chip, "freeze 0x301"), Ok("Cheat 2: 301=16 on".to_string()));
+        chip.write(0x300, 0);
+        engine.apply(&mut chip);
+        assert_eq!(chip.read(0x300), 9);
+
+        assert_eq!(engine.command(&mut chip, "toggle 1"), Ok("Cheat 1: extra lives 300=9 off".to_string()));
+        chip.write(0x300, 0);
+        engine.apply(&mut chip);
+        assert_eq!(chip.read(0x300), 0);
+        assert_eq!(engine.command(&mut chip, "cheats"), Ok("1 extra lives 300=9 off, 2 301=16 on".to_string()));
+        assert_eq!(engine.command(&mut chip, "delete 2"), Ok("Deleted 301=16 on".to_string()));
+        assert!(engine.command(&mut chip, "toggle 2").is_err());
+
+        assert!(engine.command(&mut chip, "poke F00 1").is_err(), "the display is not program memory");
+        assert!(engine.command(&mut chip, "poke 300 256").is_err());
+        assert!(engine.command(&mut chip, "fly").is_err());
+    }
+}
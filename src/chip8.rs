@@ -1,5 +1,11 @@
 // See: https://en.wikipedia.org/wiki/CHIP-8#Virtual_machine_description
 
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use std::error;
+use std::fmt;
+
 // font sprites
 const FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -19,8 +25,174 @@ const FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
-const DISPLAY_OFFSET: u16 = 0x0F00;
+pub(crate) const DISPLAY_OFFSET: u16 = 0x0F00;
+
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+
+// errors raised while executing a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip8Error {
+    // 0x00EE with nothing on the stack
+    StackUnderflow { pc: u16 },
+    // 0x2NNN with a full stack
+    StackOverflow { pc: u16 },
+    // an instruction that is not part of the specification (or is unsupported)
+    InvalidInstruction { pc: u16, instruction: u16 },
+    // 0xFX29 with VX > 0xF
+    InvalidFontCharacter { pc: u16, character: u8 },
+    // the program counter has left memory
+    PcOutOfBounds { pc: u16 },
+    // an instruction accessed memory past 0xFFF through I
+    MemoryOutOfBounds { pc: u16, address: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::StackUnderflow { pc } => {
+                write!(f, "return with an empty call stack at {:#05x}", pc)
+            }
+            Chip8Error::StackOverflow { pc } => write!(f, "call stack overflow at {:#05x}", pc),
+            Chip8Error::InvalidInstruction { pc, instruction } => {
+                write!(f, "invalid instruction {:#06x} at {:#05x}", instruction, pc)
+            }
+            Chip8Error::InvalidFontCharacter { pc, character } => {
+                write!(f, "invalid font character {:#04x} at {:#05x}", character, pc)
+            }
+            Chip8Error::PcOutOfBounds { pc } => {
+                write!(f, "program counter {:#05x} is outside of memory", pc)
+            }
+            Chip8Error::MemoryOutOfBounds { pc, address } => {
+                write!(f, "memory access at {:#05x} is outside of memory, from {:#05x}", address, pc)
+            }
+        }
+    }
+}
+
+impl error::Error for Chip8Error {}
+
+// a copy of the display, one bit per pixel with the most significant bit leftmost
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pixels: [u8; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8],
+}
+
+impl Frame {
+    pub fn width(&self) -> usize {
+        DISPLAY_WIDTH
+    }
+    pub fn height(&self) -> usize {
+        DISPLAY_HEIGHT
+    }
+
+    // whether the pixel at (x, y) is set, coordinates wrap around the display
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let x = x % DISPLAY_WIDTH;
+        let y = y % DISPLAY_HEIGHT;
+        self.pixels[y * DISPLAY_WIDTH / 8 + x / 8] & (0b1000_0000 >> (x % 8)) != 0
+    }
+
+    // the packed rows of the display, 8 bytes per row
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+}
+
+// changes in whether the sound timer is sounding, reported by `Chip8::poll_sound_event`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoundEvent {
+    Started,
+    Stopped,
+}
+
+// behaviours that differ between CHIP-8 platforms
+// See: https://github.com/Timendus/chip8-test-suite#quirks-test
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub vf_reset: bool,     // 0x8XY1, 0x8XY2 and 0x8XY3 reset VF to 0
+    pub memory: bool,       // 0xFX55 and 0xFX65 increment I
+    pub display_wait: bool, // 0xDXYN waits for the next frame, so at most one sprite is drawn per frame
+    pub clipping: bool,     // sprites are clipped at the edges of the display rather than wrapping
+    pub shifting: bool,     // 0x8XY6 and 0x8XYE shift VX in place rather than shifting VY into VX
+    pub jumping: bool,      // 0xBNNN jumps to XNN + VX rather than NNN + V0
+}
 
+impl Quirks {
+    // the original COSMAC VIP interpreter
+    pub const CHIP8: Quirks = Quirks {
+        vf_reset: true,
+        memory: true,
+        display_wait: true,
+        clipping: true,
+        shifting: false,
+        jumping: false,
+    };
+    // SUPER-CHIP as implemented by most modern interpreters
+    pub const SUPER_CHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory: false,
+        display_wait: false,
+        clipping: true,
+        shifting: true,
+        jumping: true,
+    };
+    pub const XO_CHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory: true,
+        display_wait: false,
+        clipping: false,
+        shifting: false,
+        jumping: false,
+    };
+
+    // every preset, with the name it is selected by
+    pub const PRESETS: [(&'static str, Quirks); 3] = [
+        ("chip8", Quirks::CHIP8),
+        ("schip", Quirks::SUPER_CHIP),
+        ("xochip", Quirks::XO_CHIP),
+    ];
+
+    pub fn preset(name: &str) -> Option<Quirks> {
+        Quirks::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|&(_, quirks)| quirks)
+    }
+
+    // the name of the preset these quirks are, if any
+    pub fn name(&self) -> Option<&'static str> {
+        Quirks::PRESETS.iter().find(|(_, preset)| preset == self).map(|&(name, _)| name)
+    }
+}
+
+// SUPER-CHIP, but with 0xBNNN jumping to NNN + V0 as it always has here
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory: false,
+            display_wait: false,
+            clipping: true,
+            shifting: true,
+            jumping: false,
+        }
+    }
+}
+
+// a copy of the registers, timers, stack and keypad, for debuggers and tests
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub stack: Vec<u16>, // return addresses, the most recent call last
+    pub keys: u16,       // bitmask of the keypad keys held
+}
+
+#[derive(Clone)]
 pub struct Chip8 {
     // main memory
     //
@@ -42,16 +214,47 @@ pub struct Chip8 {
     delay_timer: u8, // decremented at 60Hz
     sound_timer: u8, // play a sound while this is non-zero
 
+    // sound state last reported by `poll_sound_event`
+    sound_reported: bool,
+
     // stack pointer
     sp: usize,        // current location on the stack
     stack: [u16; 16], // stores return addresses for subroutines
 
     // inputs
     keypad: [bool; 16], // whether each of the keys (0x0..=0xf) are pressed
+
+    // random number source for 0xCXNN, seeded so runs can be reproduced
+    rng: ChaCha8Rng,
+
+    quirks: Quirks,
+    // whether a sprite can be drawn this frame, with the display wait quirk
+    vblank: bool,
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
+}
+
+// memory is left out, it is too big to be useful here
+impl fmt::Debug for Chip8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chip8")
+            .field("state", &self.state())
+            .field("quirks", &self.quirks)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Chip8 {
     pub fn new() -> Chip8 {
+        Chip8::with_seed(rand::random())
+    }
+
+    // a machine whose random numbers are always the same for a given seed
+    pub fn with_seed(seed: u64) -> Chip8 {
         let mut result = Chip8 {
             memory: [0; 4096],
             display_updated: false,
@@ -60,9 +263,13 @@ impl Chip8 {
             v: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
+            sound_reported: false,
             sp: 0,
             stack: [0; 16],
             keypad: [false; 16],
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            quirks: Quirks::default(),
+            vblank: false,
         };
 
         // load font
@@ -75,23 +282,142 @@ impl Chip8 {
         self.display_updated
     }
 
-    pub fn cycle(&mut self) {
+    pub fn cycle(&mut self) -> Result<(), Chip8Error> {
         self.display_updated = false;
 
-        let instruction = self.fetch();
-        self.execute(instruction);
+        let instruction = self.fetch()?;
+        self.execute(instruction)
+    }
+
+    // whether the program has stopped by jumping to its own address (0x1NNN with NNN == pc),
+    // the usual way for a CHIP-8 program to end
+    pub fn halted(&self) -> bool {
+        let pc = self.pc as usize;
+        if pc + 1 >= self.memory.len() {
+            return false;
+        }
+        let instruction = ((self.memory[pc] as u16) << 8) | self.memory[pc + 1] as u16;
+        instruction == 0x1000 | self.pc
+    }
+
+    // whether the pixel at (x, y) is set, coordinates wrap around the display
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let x = x % DISPLAY_WIDTH;
+        let y = y % DISPLAY_HEIGHT;
+        let byte = self.memory[DISPLAY_OFFSET as usize + y * DISPLAY_WIDTH / 8 + x / 8];
+        byte & (0b1000_0000 >> (x % 8)) != 0
+    }
+
+    // a copy of the current display
+    pub fn frame(&self) -> Frame {
+        let mut pixels = [0; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8];
+        pixels.copy_from_slice(&self.memory[DISPLAY_OFFSET as usize..]);
+        Frame { pixels }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+    // V0 to VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+    // all of memory, including the display at 0xF00
+    pub fn memory(&self) -> &[u8; 4096] {
+        &self.memory
+    }
+    // bitmask of the keypad keys held
+    pub fn keys(&self) -> u16 {
+        (0..16).filter(|&key| self.keypad[key]).fold(0, |keys, key| keys | 1 << key)
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            i: self.i,
+            v: self.v,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            stack: self.stack[..self.sp].to_vec(),
+            keys: self.keys(),
+        }
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+    // panics if `x` is not a register, 0x0..=0xf
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+    }
+
+    // push a return address, as 0x2NNN does
+    pub fn push_stack(&mut self, addr: u16) -> Result<(), Chip8Error> {
+        if self.sp >= self.stack.len() {
+            return Err(Chip8Error::StackOverflow { pc: self.pc });
+        }
+        self.stack[self.sp] = addr;
+        self.sp += 1;
+        Ok(())
+    }
+    // pop the most recent return address, or `None` if the stack is empty
+    pub fn pop_stack(&mut self) -> Option<u16> {
+        self.sp = self.sp.checked_sub(1)?;
+        Some(self.stack[self.sp])
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // decrement the delay and sound timers, should be called at 60Hz
+    pub fn tick_timers(&mut self) {
+        self.vblank = true;
 
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
         if self.sound_timer > 0 {
             self.sound_timer -= 1;
-            println!("\x07");
         }
     }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+    // whether a tone should currently be playing
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+    // reports the tone starting or stopping since the last call
+    pub fn poll_sound_event(&mut self) -> Option<SoundEvent> {
+        let active = self.sound_active();
+        if active == self.sound_reported {
+            return None;
+        }
+        self.sound_reported = active;
+        Some(if active {
+            SoundEvent::Started
+        } else {
+            SoundEvent::Stopped
+        })
+    }
+
     pub fn write_cmd(&mut self, addr: u16, val: u16) {
         let addr = addr as usize;
-        if addr >= self.memory.len() {
+        if addr + 1 >= self.memory.len() {
             panic!("Invalid memory write request");
         }
         self.memory[addr] = ((val & 0xFF00) >> 8) as u8;
@@ -120,12 +446,31 @@ impl Chip8 {
         self.keypad[(addr % 16) as usize]
     }
 
-    fn fetch(&mut self) -> u16 {
-        let result = ((self.read(self.pc) as u16) << 8) | self.read(self.pc + 1) as u16;
+    fn fetch(&mut self) -> Result<u16, Chip8Error> {
+        let pc = self.pc as usize;
+        if pc + 1 >= self.memory.len() {
+            return Err(Chip8Error::PcOutOfBounds { pc: self.pc });
+        }
+        let result = ((self.memory[pc] as u16) << 8) | self.memory[pc + 1] as u16;
         self.pc += 2;
-        result
+        Ok(result)
     }
-    fn execute(&mut self, instruction: u16) {
+
+    // the memory from I to I + len, for the instruction at pc
+    fn indexed(&mut self, pc: u16, len: usize) -> Result<&mut [u8], Chip8Error> {
+        let start = self.i as usize;
+        if start + len > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                pc,
+                address: start.max(self.memory.len()),
+            });
+        }
+        Ok(&mut self.memory[start..start + len])
+    }
+    fn execute(&mut self, instruction: u16) -> Result<(), Chip8Error> {
+        // address of the instruction being executed
+        let pc = self.pc - 2;
+
         let first_nibble = (instruction & 0xF000) >> 12;
         let second_nibble = (instruction & 0x0F00) >> 8;
         let third_nibble = (instruction & 0x00F0) >> 4;
@@ -148,7 +493,7 @@ impl Chip8 {
                         // 0x00EE
                         // Return from subroutine
                         if self.sp == 0 {
-                            panic!("Reached end of call stack");
+                            return Err(Chip8Error::StackUnderflow { pc });
                         }
                         self.sp -= 1;
                         self.pc = self.stack[self.sp];
@@ -156,7 +501,7 @@ impl Chip8 {
                     _ => {
                         // 0xNNN
                         // Call machine code routine at address NNN
-                        unimplemented!();
+                        return Err(Chip8Error::InvalidInstruction { pc, instruction });
                     }
                 };
             }
@@ -169,7 +514,7 @@ impl Chip8 {
                 // 0x2NNN
                 // Call subroutine at NNN
                 if self.sp >= self.stack.len() {
-                    panic!("Stack overflow");
+                    return Err(Chip8Error::StackOverflow { pc });
                 }
                 self.stack[self.sp] = self.pc;
                 self.sp += 1;
@@ -203,7 +548,7 @@ impl Chip8 {
             }
             0x7 => {
                 // 0x7XNN
-                // Adds NN to register X
+                // Adds NN to register X (wrapping, VF is not affected)
                 let result = self.v[x] as u16 + second_byte as u16;
                 self.v[x] = (result & 0xFF) as u8;
             }
@@ -218,61 +563,81 @@ impl Chip8 {
                         // 0x8XY1
                         // Sets VX to VX bitwise or VY
                         self.v[x] |= self.v[y];
+                        if self.quirks.vf_reset {
+                            self.v[0xF] = 0;
+                        }
                     }
                     0x2 => {
                         // 0x8XY2
                         // Sets VX to VX bitwise and VY
                         self.v[x] &= self.v[y];
+                        if self.quirks.vf_reset {
+                            self.v[0xF] = 0;
+                        }
                     }
                     0x3 => {
                         // 0x8XY3
                         // Sets VX to VX bitwise xor VY
                         self.v[x] ^= self.v[y];
+                        if self.quirks.vf_reset {
+                            self.v[0xF] = 0;
+                        }
                     }
                     0x4 => {
                         // 0x8XY4
                         // Sets VX to VX + VY
                         // If there is a carry, VF is set to 1, otherwise VF is set to 0
+                        // (VF is set last, so the flag wins when X is F)
                         let result: u16 = self.v[x] as u16 + self.v[y] as u16;
-                        self.v[0xF] = if result > 0xFF { 1 } else { 0 };
                         self.v[x] = (result & 0xFF) as u8;
+                        self.v[0xF] = if result > 0xFF { 1 } else { 0 };
                     }
                     0x5 => {
                         // 0x8XY5
                         // Sets VX to VX - VY
                         // VF set to 0 when there is a borrow, 1 if not
                         let result: i16 = self.v[x] as i16 - self.v[y] as i16;
-                        self.v[0xF] = if result >= 0 { 1 } else { 0 };
                         self.v[x] = ((result + 256) & 0xFF) as u8;
+                        self.v[0xF] = if result >= 0 { 1 } else { 0 };
                     }
                     0x6 => {
                         // 0x8XY6
                         // Stores LSB of VX in VF, then right shifts VX by one
-                        self.v[0xF] = self.v[x] & 1;
+                        // (VY is copied to VX first without the shifting quirk)
+                        if !self.quirks.shifting {
+                            self.v[x] = self.v[y];
+                        }
+                        let lsb = self.v[x] & 1;
                         self.v[x] >>= 1;
+                        self.v[0xF] = lsb;
                     }
                     0x7 => {
                         // 0x8XY7
                         // Sets VX to VY - VX
                         // VF set to 0 when there is a borrow, 1 if not
                         let result: i16 = self.v[y] as i16 - self.v[x] as i16;
-                        self.v[0xF] = if result >= 0 { 1 } else { 0 };
                         self.v[x] = ((result + 256) & 0xFF) as u8;
+                        self.v[0xF] = if result >= 0 { 1 } else { 0 };
                     }
                     0xE => {
                         // 0x8XYE
-                        // Stores MSB of VX in VF, then right shifts VX by one
-                        self.v[0xF] = self.v[x] & 0b10000000;
+                        // Stores MSB of VX in VF, then left shifts VX by one
+                        // (VY is copied to VX first without the shifting quirk)
+                        if !self.quirks.shifting {
+                            self.v[x] = self.v[y];
+                        }
+                        let msb = self.v[x] >> 7;
                         self.v[x] <<= 1;
+                        self.v[0xF] = msb;
                     }
                     _ => {
-                        unimplemented!();
+                        return Err(Chip8Error::InvalidInstruction { pc, instruction });
                     }
                 }
             }
             0x9 => {
                 // 0x9XY0
-                // Skips the next instruction if VX != XY
+                // Skips the next instruction if VX != VY
                 if self.v[x] != self.v[y] {
                     self.pc += 2;
                 }
@@ -284,37 +649,50 @@ impl Chip8 {
             }
             0xB => {
                 // 0xBNNN
-                // Jumps to address NNN + V0
-                self.pc = self.v[0x0] as u16 + (instruction & 0x0FFF);
+                // Jumps to address NNN + V0 (XNN + VX with the jumping quirk)
+                let offset = if self.quirks.jumping { self.v[x] } else { self.v[0x0] };
+                self.pc = offset as u16 + (instruction & 0x0FFF);
             }
             0xC => {
                 // 0xCXNN
                 // Sets VX to the bitwise and of a 1 byte random number and NN
-                let random_byte: u8 = rand::random();
+                let random_byte: u8 = self.rng.gen();
                 self.v[x] = random_byte & second_byte;
             }
             0xD => {
                 // 0xDXYN
                 // Draws a sprite at coordinate (VX, VY)
                 //   - width: 8 px
-                //   - height: (N+1) px
+                //   - height: N px
                 // Read from address stored in I
                 // VF set to 1 if any screen pixels are flipped from set to unset,
                 // otherwise to 0
+                if self.quirks.display_wait {
+                    if !self.vblank {
+                        // wait for the next frame
+                        self.pc -= 2;
+                        return Ok(());
+                    }
+                    self.vblank = false;
+                }
                 self.display_updated = true;
 
                 let x = (self.v[x] % 64) as u16;
                 let y = (self.v[y] % 32) as u16;
                 let n = fourth_nibble;
 
+                let mut sprite = [0; 15];
+                sprite[..n as usize].copy_from_slice(self.indexed(pc, n as usize)?);
+
                 self.v[0xF] = 0;
-                for row in 0..=n {
+                for row in 0..n {
                     let output_y = row + y;
-                    if output_y >= 32 {
+                    if output_y >= 32 && self.quirks.clipping {
                         break;
                     }
+                    let output_y = output_y % 32;
 
-                    let mut sprite_row = self.read(self.i + row);
+                    let mut sprite_row = sprite[row as usize];
 
                     if sprite_row == 0 {
                         continue;
@@ -325,9 +703,9 @@ impl Chip8 {
 
                     let output_address = DISPLAY_OFFSET + output_y * 8 + byte_position;
 
-                    // check that the next byte is in bounds
-                    if byte_shift != 0 && (byte_position + 1) < 8 {
-                        let output_address = output_address + 1;
+                    // check that the next byte is in bounds, or wrap to the start of the row
+                    if byte_shift != 0 && ((byte_position + 1) < 8 || !self.quirks.clipping) {
+                        let output_address = DISPLAY_OFFSET + output_y * 8 + (byte_position + 1) % 8;
 
                         let overflow_byte = sprite_row << (8 - byte_shift);
                         let current_byte = self.read(output_address);
@@ -371,7 +749,7 @@ impl Chip8 {
                         }
                     }
                     _ => {
-                        unimplemented!();
+                        return Err(Chip8Error::InvalidInstruction { pc, instruction });
                     }
                 }
             }
@@ -412,13 +790,13 @@ impl Chip8 {
                     0x1E => {
                         // 0xFX1E
                         // Adds VX to I. VF is not affected
-                        self.i += self.v[x] as u16;
+                        self.i = self.i.wrapping_add(self.v[x] as u16);
                     }
                     0x29 => {
                         // 0xFX29
                         // Sets I to the location of the sprite for the character in VX. Characters 0-F (in hexadecimal) are represented by a 4x5 font.
                         if self.v[x] >= 16 {
-                            panic!("Invalid font character access");
+                            return Err(Chip8Error::InvalidFontCharacter { pc, character: self.v[x] });
                         }
                         self.i = 5 * (self.v[x] as u16);
                     }
@@ -427,35 +805,39 @@ impl Chip8 {
                         // Stores the binary-coded decimal representation of VX, with the most significant of three
                         // digits at the address in I, the middle digit at I plus 1,
                         // and the least significant digit at I plus 2.
-                        self.write(self.i, self.v[x] / 100);
-                        self.write(self.i + 1, (self.v[x] / 10) % 10);
-                        self.write(self.i + 2, self.v[x] % 10);
+                        let value = self.v[x];
+                        self.indexed(pc, 3)?.copy_from_slice(&[value / 100, (value / 10) % 10, value % 10]);
                     }
                     0x55 => {
                         // 0xFX55
-                        // Stores V0 to VX (including VX) in memory starting at address I. The offset from I is increased by 1 for each value written, but I itself is left unmodified
-                        for addr_offset in 0..=x {
-                            let addr = self.i + (addr_offset as u16);
-                            self.write(addr, self.v[addr_offset as usize]);
+                        // Stores V0 to VX (including VX) in memory starting at address I. The offset from I is increased by 1 for each value written, but I itself is left unmodified (unless the memory quirk is set)
+                        let registers = self.v;
+                        self.indexed(pc, x + 1)?.copy_from_slice(&registers[0..=x]);
+                        if self.quirks.memory {
+                            self.i = self.i.wrapping_add(x as u16 + 1);
                         }
                     }
                     0x65 => {
                         // 0xFX65
-                        // Fills V0 to VX (including VX) with values from memory starting at address I. The offset from I is increased by 1 for each value written, but I itself is left unmodified
-                        for addr_offset in 0..=x {
-                            let addr = self.i + (addr_offset as u16);
-                            self.v[addr_offset as usize] = self.read(addr);
+                        // Fills V0 to VX (including VX) with values from memory starting at address I. The offset from I is increased by 1 for each value written, but I itself is left unmodified (unless the memory quirk is set)
+                        let mut registers = [0; 16];
+                        registers[0..=x].copy_from_slice(self.indexed(pc, x + 1)?);
+                        self.v[0..=x].copy_from_slice(&registers[0..=x]);
+                        if self.quirks.memory {
+                            self.i = self.i.wrapping_add(x as u16 + 1);
                         }
                     }
                     _ => {
-                        unimplemented!();
+                        return Err(Chip8Error::InvalidInstruction { pc, instruction });
                     }
                 }
             }
             _ => {
-                unimplemented!();
+                return Err(Chip8Error::InvalidInstruction { pc, instruction });
             }
         };
+
+        Ok(())
     }
 
     pub fn display_to_string(&self) -> String {
@@ -479,3 +861,714 @@ impl Chip8 {
         string
     }
 }
+
+#[cfg(test)]
+mod differential;
+
+// programs and machines shared by the tests of other modules
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{Chip8, Frame, DISPLAY_OFFSET};
+
+    // draws the 0 glyph at 0,0 then halts
+    pub const ZERO: [u8; 6] = [0xA0, 0x00, 0xD0, 0x05, 0x12, 0x04];
+
+    // a machine with `program` loaded at 0x200
+    pub fn load(program: &[u8]) -> Chip8 {
+        let mut chip = Chip8::with_seed(0);
+        for (addr, &byte) in (0x200..).zip(program) {
+            chip.write(addr, byte);
+        }
+        chip
+    }
+
+    // a display with only the top left pixel set
+    pub fn corner() -> Frame {
+        let mut chip = Chip8::with_seed(0);
+        chip.write(DISPLAY_OFFSET, 0b1000_0000);
+        chip.frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a machine with `program` loaded at 0x200
+    fn load(program: &[u16]) -> Chip8 {
+        let bytes: Vec<u8> = program.iter().flat_map(|instruction| instruction.to_be_bytes()).collect();
+        fixtures::load(&bytes)
+    }
+
+    // run every instruction in `program` once
+    fn run(program: &[u16]) -> Chip8 {
+        let mut chip = load(program);
+        for _ in program {
+            chip.cycle().unwrap();
+        }
+        chip
+    }
+
+    // run a single instruction on a machine with the given registers
+    fn exec(v: &[(usize, u8)], instruction: u16) -> Chip8 {
+        let mut chip = load(&[instruction]);
+        for &(register, value) in v {
+            chip.v[register] = value;
+        }
+        chip.cycle().unwrap();
+        chip
+    }
+
+    fn rows(chip: &Chip8, y: usize, count: usize) -> Vec<u8> {
+        let start = DISPLAY_OFFSET as usize + y * 8;
+        chip.memory[start..start + count * 8].to_vec()
+    }
+
+    #[test]
+    fn new_machine() {
+        let chip = Chip8::new();
+        assert_eq!(chip.pc, 0x200);
+        assert_eq!(&chip.memory[0..80], &FONT[..]);
+        assert!(chip.memory[0xF00..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn clear_display() {
+        let mut chip = load(&[0x00E0]);
+        chip.memory[0xF00..].fill(0xFF);
+        chip.cycle().unwrap();
+        assert!(chip.memory[0xF00..].iter().all(|&b| b == 0));
+        assert!(chip.display_updated());
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut chip = load(&[0x2208, 0x0000, 0x0000, 0x0000, 0x00EE]);
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x208);
+        assert_eq!(chip.sp, 1);
+        assert_eq!(chip.stack[0], 0x202);
+
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.sp, 0);
+    }
+
+    #[test]
+    fn state_and_setters() {
+        let mut chip = load(&[0x2208, 0x0000, 0x0000, 0x0000, 0x00EE]);
+        chip.cycle().unwrap();
+        chip.write_keypad(0xA, true);
+        chip.set_register(0xF, 0x12);
+        chip.set_i(0x345);
+        chip.push_stack(0x300).unwrap();
+
+        let state = chip.state();
+        assert_eq!(state.pc, 0x208);
+        assert_eq!(state.i, 0x345);
+        assert_eq!(state.v[0xF], 0x12);
+        assert_eq!(state.stack, [0x202, 0x300]);
+        assert_eq!(state.keys, 1 << 0xA);
+
+        // returns to the address pushed last
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc(), 0x300);
+        assert_eq!(chip.pop_stack(), Some(0x202));
+        assert_eq!(chip.pop_stack(), None);
+
+        chip.set_pc(0x200);
+        for _ in 0..16 {
+            chip.push_stack(0x200).unwrap();
+        }
+        assert_eq!(chip.push_stack(0x200), Err(Chip8Error::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let mut chip = load(&[0xC0FF]);
+        let mut copy = chip.clone();
+        chip.cycle().unwrap();
+        copy.cycle().unwrap();
+        // the random number generator is copied too
+        assert_eq!(chip.state(), copy.state());
+
+        copy.write(0x200, 0xFF);
+        assert_eq!(chip.read(0x200), 0xC0);
+        assert!(format!("{:?}", chip).starts_with("Chip8 { state: CpuState { pc: 514,"));
+    }
+
+    #[test]
+    fn return_with_empty_stack() {
+        let mut chip = load(&[0x00EE]);
+        assert_eq!(chip.cycle(), Err(Chip8Error::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn call_stack_overflow() {
+        // calls itself forever
+        let mut chip = load(&[0x2200]);
+        for _ in 0..16 {
+            chip.cycle().unwrap();
+        }
+        assert_eq!(chip.cycle(), Err(Chip8Error::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn machine_code_routine_is_unsupported() {
+        let mut chip = load(&[0x0123]);
+        assert_eq!(
+            chip.cycle(),
+            Err(Chip8Error::InvalidInstruction { pc: 0x200, instruction: 0x0123 })
+        );
+    }
+
+    #[test]
+    fn jump() {
+        let chip = run(&[0x1ABC]);
+        assert_eq!(chip.pc, 0xABC);
+    }
+
+    #[test]
+    fn jump_to_self_halts() {
+        let mut chip = load(&[0x6001, 0x1202]);
+        assert!(!chip.halted());
+        chip.cycle().unwrap();
+        assert!(chip.halted());
+    }
+
+    #[test]
+    fn skip_if_equal_immediate() {
+        assert_eq!(exec(&[(3, 0x42)], 0x3342).pc, 0x204);
+        assert_eq!(exec(&[(3, 0x41)], 0x3342).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_not_equal_immediate() {
+        assert_eq!(exec(&[(3, 0x42)], 0x4342).pc, 0x202);
+        assert_eq!(exec(&[(3, 0x41)], 0x4342).pc, 0x204);
+    }
+
+    #[test]
+    fn skip_if_registers_equal() {
+        assert_eq!(exec(&[(1, 7), (2, 7)], 0x5120).pc, 0x204);
+        assert_eq!(exec(&[(1, 7), (2, 8)], 0x5120).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_registers_not_equal() {
+        assert_eq!(exec(&[(1, 7), (2, 7)], 0x9120).pc, 0x202);
+        assert_eq!(exec(&[(1, 7), (2, 8)], 0x9120).pc, 0x204);
+    }
+
+    #[test]
+    fn load_immediate() {
+        assert_eq!(exec(&[], 0x6A5C).v[0xA], 0x5C);
+    }
+
+    #[test]
+    fn add_immediate() {
+        assert_eq!(exec(&[(4, 0x10)], 0x7405).v[4], 0x15);
+    }
+
+    #[test]
+    fn add_immediate_wraps_without_carry() {
+        let chip = exec(&[(4, 0xFF), (0xF, 0x55)], 0x7402);
+        assert_eq!(chip.v[4], 0x01);
+        assert_eq!(chip.v[0xF], 0x55);
+    }
+
+    #[test]
+    fn register_copy() {
+        assert_eq!(exec(&[(1, 0), (2, 0x33)], 0x8120).v[1], 0x33);
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        assert_eq!(exec(&[(1, 0b1100), (2, 0b1010)], 0x8121).v[1], 0b1110);
+        assert_eq!(exec(&[(1, 0b1100), (2, 0b1010)], 0x8122).v[1], 0b1000);
+        assert_eq!(exec(&[(1, 0b1100), (2, 0b1010)], 0x8123).v[1], 0b0110);
+    }
+
+    #[test]
+    fn add_registers() {
+        let chip = exec(&[(1, 0x10), (2, 0x20), (0xF, 9)], 0x8124);
+        assert_eq!(chip.v[1], 0x30);
+        assert_eq!(chip.v[0xF], 0);
+
+        let chip = exec(&[(1, 0xFF), (2, 0x01)], 0x8124);
+        assert_eq!(chip.v[1], 0x00);
+        assert_eq!(chip.v[0xF], 1);
+
+        let chip = exec(&[(1, 0xFF), (2, 0xFF)], 0x8124);
+        assert_eq!(chip.v[1], 0xFE);
+        assert_eq!(chip.v[0xF], 1);
+    }
+
+    #[test]
+    fn subtract_registers() {
+        let chip = exec(&[(1, 0x30), (2, 0x10)], 0x8125);
+        assert_eq!(chip.v[1], 0x20);
+        assert_eq!(chip.v[0xF], 1);
+
+        // equal values do not borrow
+        let chip = exec(&[(1, 0x30), (2, 0x30)], 0x8125);
+        assert_eq!(chip.v[1], 0x00);
+        assert_eq!(chip.v[0xF], 1);
+
+        let chip = exec(&[(1, 0x10), (2, 0x30)], 0x8125);
+        assert_eq!(chip.v[1], 0xE0);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn reverse_subtract_registers() {
+        let chip = exec(&[(1, 0x10), (2, 0x30)], 0x8127);
+        assert_eq!(chip.v[1], 0x20);
+        assert_eq!(chip.v[0xF], 1);
+
+        let chip = exec(&[(1, 0x30), (2, 0x10)], 0x8127);
+        assert_eq!(chip.v[1], 0xE0);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn shift_right() {
+        let chip = exec(&[(1, 0b0000_0101)], 0x8126);
+        assert_eq!(chip.v[1], 0b0000_0010);
+        assert_eq!(chip.v[0xF], 1);
+
+        let chip = exec(&[(1, 0b0000_0100), (0xF, 9)], 0x8126);
+        assert_eq!(chip.v[1], 0b0000_0010);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn shift_left() {
+        let chip = exec(&[(1, 0b1000_0001)], 0x812E);
+        assert_eq!(chip.v[1], 0b0000_0010);
+        assert_eq!(chip.v[0xF], 1);
+
+        let chip = exec(&[(1, 0b0100_0001), (0xF, 9)], 0x812E);
+        assert_eq!(chip.v[1], 0b1000_0010);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn flag_register_as_operand() {
+        // the flag is written after the result, so it wins
+        assert_eq!(exec(&[(0xF, 0xFF), (1, 0x01)], 0x8F14).v[0xF], 1);
+        assert_eq!(exec(&[(0xF, 0x10), (1, 0x20)], 0x8F15).v[0xF], 0);
+        assert_eq!(exec(&[(0xF, 0x10), (1, 0x20)], 0x8F17).v[0xF], 1);
+        assert_eq!(exec(&[(0xF, 0b10)], 0x8F06).v[0xF], 0);
+        assert_eq!(exec(&[(0xF, 0b1000_0000)], 0x8F0E).v[0xF], 1);
+    }
+
+    #[test]
+    fn invalid_arithmetic_instruction() {
+        let mut chip = load(&[0x8128]);
+        assert_eq!(
+            chip.cycle(),
+            Err(Chip8Error::InvalidInstruction { pc: 0x200, instruction: 0x8128 })
+        );
+    }
+
+    #[test]
+    fn set_index() {
+        assert_eq!(exec(&[], 0xA123).i, 0x123);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        assert_eq!(exec(&[(0, 0x10)], 0xB300).pc, 0x310);
+    }
+
+    #[test]
+    fn random_is_masked() {
+        for _ in 0..32 {
+            assert_eq!(exec(&[], 0xC10F).v[1] & 0xF0, 0);
+        }
+        assert_eq!(exec(&[(1, 0xAA)], 0xC100).v[1], 0);
+    }
+
+    #[test]
+    fn random_is_reproducible() {
+        let a = run(&[0xC0FF, 0xC1FF, 0xC2FF]);
+        let b = run(&[0xC0FF, 0xC1FF, 0xC2FF]);
+        assert_eq!(a.v, b.v);
+    }
+
+    #[test]
+    fn draw_sprite() {
+        // draw the "0" font sprite at (0, 0)
+        let chip = run(&[0xA000, 0xD005]);
+        assert!(chip.display_updated());
+        assert_eq!(chip.v[0xF], 0);
+        for (row, &expected) in FONT[0..5].iter().enumerate() {
+            assert_eq!(rows(&chip, row, 1)[0], expected);
+        }
+    }
+
+    #[test]
+    fn draw_sprite_height_is_n() {
+        let chip = run(&[0xA000, 0xD003]);
+        assert_eq!(rows(&chip, 0, 3).iter().filter(|&&b| b != 0).count(), 3);
+        assert!(rows(&chip, 3, 1).iter().all(|&b| b == 0));
+
+        // a zero height sprite draws nothing
+        let chip = run(&[0xA000, 0xD000]);
+        assert!(rows(&chip, 0, 32).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn draw_sprite_unaligned() {
+        // "0" at x = 4, spans two bytes
+        let chip = run(&[0xA000, 0x6004, 0xD015]);
+        assert_eq!(rows(&chip, 0, 1)[0..2], [0x0F, 0x00]);
+        assert_eq!(rows(&chip, 1, 1)[0..2], [0x09, 0x00]);
+
+        let chip = run(&[0xA000, 0x6006, 0xD015]);
+        assert_eq!(rows(&chip, 0, 1)[0..2], [0x03, 0xC0]);
+    }
+
+    #[test]
+    fn draw_sprite_collision() {
+        let chip = run(&[0xA000, 0xD005, 0xD005]);
+        assert_eq!(chip.v[0xF], 1);
+        assert!(rows(&chip, 0, 5).iter().all(|&b| b == 0));
+
+        // sharing a byte without turning a pixel off is not a collision
+        let chip = run(&[0xA000, 0xD011, 0x6004, 0xA005, 0xD011]);
+        assert_eq!(rows(&chip, 0, 1)[0], 0xF2);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn draw_sprite_wraps_start_and_clips() {
+        // the start position wraps
+        let chip = run(&[0xA000, 0x6040, 0x6120, 0xD015]);
+        assert_eq!(rows(&chip, 0, 1)[0], 0xF0);
+
+        // but the sprite is clipped at the right and bottom edges
+        let chip = run(&[0xA000, 0x603C, 0x611E, 0xD015]);
+        assert_eq!(rows(&chip, 30, 1)[7], 0x0F);
+        assert_eq!(rows(&chip, 31, 1)[7], 0x09);
+        assert_eq!(rows(&chip, 0, 1)[0], 0x00);
+        assert!(rows(&chip, 0, 30).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn skip_if_key() {
+        let mut chip = load(&[0xE19E]);
+        chip.v[1] = 0xA;
+        chip.write_keypad(0xA, true);
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x204);
+
+        assert_eq!(exec(&[(1, 0xA)], 0xE19E).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_not_key() {
+        let mut chip = load(&[0xE1A1]);
+        chip.v[1] = 0xA;
+        chip.write_keypad(0xA, true);
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x202);
+
+        assert_eq!(exec(&[(1, 0xA)], 0xE1A1).pc, 0x204);
+    }
+
+    #[test]
+    fn invalid_key_instruction() {
+        let mut chip = load(&[0xE100]);
+        assert!(chip.cycle().is_err());
+    }
+
+    #[test]
+    fn delay_timer() {
+        let mut chip = load(&[0xF115, 0xF207]);
+        chip.v[1] = 2;
+        chip.cycle().unwrap();
+        chip.tick_timers();
+        chip.cycle().unwrap();
+        assert_eq!(chip.v[2], 1);
+
+        chip.tick_timers();
+        chip.tick_timers();
+        assert_eq!(chip.delay_timer, 0);
+    }
+
+    #[test]
+    fn sound_timer_and_events() {
+        let mut chip = load(&[0xF118]);
+        chip.v[1] = 2;
+        assert!(chip.poll_sound_event().is_none());
+
+        chip.cycle().unwrap();
+        assert!(chip.sound_active());
+        assert_eq!(chip.poll_sound_event(), Some(SoundEvent::Started));
+        assert_eq!(chip.poll_sound_event(), None);
+
+        chip.tick_timers();
+        assert!(chip.sound_active());
+        chip.tick_timers();
+        assert!(!chip.sound_active());
+        assert_eq!(chip.poll_sound_event(), Some(SoundEvent::Stopped));
+    }
+
+    #[test]
+    fn wait_for_key() {
+        let mut chip = load(&[0xF30A]);
+        chip.cycle().unwrap();
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x200);
+
+        chip.write_keypad(0x7, true);
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.v[3], 0x7);
+    }
+
+    #[test]
+    fn add_to_index() {
+        let chip = exec(&[(1, 0x10), (0xF, 0x55)], 0xF11E);
+        assert_eq!(chip.i, 0x10);
+        assert_eq!(chip.v[0xF], 0x55);
+
+        let mut chip = load(&[0xF11E]);
+        chip.i = 0xFFF;
+        chip.v[1] = 0x01;
+        chip.cycle().unwrap();
+        assert_eq!(chip.i, 0x1000);
+        assert_eq!(chip.v[0xF], 0);
+    }
+
+    #[test]
+    fn font_character() {
+        assert_eq!(exec(&[(1, 0x0)], 0xF129).i, 0);
+        assert_eq!(exec(&[(1, 0xA)], 0xF129).i, 50);
+        assert_eq!(exec(&[(1, 0xF)], 0xF129).i, 75);
+
+        let mut chip = load(&[0xF129]);
+        chip.v[1] = 0x10;
+        assert_eq!(
+            chip.cycle(),
+            Err(Chip8Error::InvalidFontCharacter { pc: 0x200, character: 0x10 })
+        );
+    }
+
+    #[test]
+    fn binary_coded_decimal() {
+        for &(value, digits) in &[(0u8, [0, 0, 0]), (7, [0, 0, 7]), (42, [0, 4, 2]), (255, [2, 5, 5])] {
+            let chip = run(&[0xA300, 0x6000 | value as u16, 0xF033]);
+            assert_eq!(chip.memory[0x300..0x303], digits);
+            assert_eq!(chip.i, 0x300);
+        }
+    }
+
+    #[test]
+    fn store_registers() {
+        let mut chip = load(&[0xA300, 0xF255]);
+        chip.v[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        chip.cycle().unwrap();
+        chip.cycle().unwrap();
+        assert_eq!(chip.memory[0x300..0x304], [1, 2, 3, 0]);
+        assert_eq!(chip.i, 0x300);
+    }
+
+    #[test]
+    fn load_registers() {
+        let mut chip = load(&[0xA300, 0xF265]);
+        chip.memory[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        chip.cycle().unwrap();
+        chip.cycle().unwrap();
+        assert_eq!(chip.v[0..4], [1, 2, 3, 0]);
+        assert_eq!(chip.i, 0x300);
+    }
+
+    #[test]
+    fn invalid_misc_instruction() {
+        let mut chip = load(&[0xF1FF]);
+        assert!(chip.cycle().is_err());
+    }
+
+    #[test]
+    fn quirk_jumping() {
+        let mut chip = load(&[0xB300]);
+        chip.set_quirks(Quirks::SUPER_CHIP);
+        chip.v[0] = 0x10;
+        chip.v[3] = 0x20;
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x320);
+    }
+
+    #[test]
+    fn quirk_vf_reset() {
+        for &instruction in &[0x8121, 0x8122, 0x8123] {
+            let mut chip = load(&[instruction]);
+            chip.set_quirks(Quirks::CHIP8);
+            chip.v[0xF] = 0x55;
+            chip.cycle().unwrap();
+            assert_eq!(chip.v[0xF], 0);
+
+            let chip = exec(&[(0xF, 0x55)], instruction);
+            assert_eq!(chip.v[0xF], 0x55);
+        }
+    }
+
+    #[test]
+    fn quirk_memory() {
+        let mut chip = load(&[0xA300, 0xF255, 0xF165]);
+        chip.set_quirks(Quirks::CHIP8);
+        chip.cycle().unwrap();
+        chip.cycle().unwrap();
+        assert_eq!(chip.i, 0x303);
+        chip.cycle().unwrap();
+        assert_eq!(chip.i, 0x305);
+    }
+
+    #[test]
+    fn quirk_shifting() {
+        let mut chip = load(&[0x8126, 0x834E]);
+        chip.set_quirks(Quirks::CHIP8);
+        chip.v[1] = 0xFF;
+        chip.v[2] = 0b0000_0110;
+        chip.v[4] = 0b1000_0001;
+        chip.cycle().unwrap();
+        assert_eq!(chip.v[1], 0b0000_0011);
+        assert_eq!(chip.v[0xF], 0);
+        chip.cycle().unwrap();
+        assert_eq!(chip.v[3], 0b0000_0010);
+        assert_eq!(chip.v[0xF], 1);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut chip = load(&[0xA000, 0xD015, 0xD015]);
+        chip.set_quirks(Quirks::CHIP8);
+        chip.cycle().unwrap();
+
+        // nothing is drawn until the next frame starts
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x202);
+        assert!(!chip.display_updated());
+
+        chip.tick_timers();
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x204);
+        assert!(chip.pixel(0, 0));
+
+        // only one sprite per frame
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x204);
+        chip.tick_timers();
+        chip.cycle().unwrap();
+        assert_eq!(chip.pc, 0x206);
+        assert!(!chip.pixel(0, 0));
+    }
+
+    #[test]
+    fn quirk_clipping() {
+        // "0" at (62, 30) wraps around to the other edges without clipping
+        let mut chip = load(&[0xA000, 0x603E, 0x611E, 0xD015]);
+        chip.set_quirks(Quirks::XO_CHIP);
+        for _ in 0..4 {
+            chip.cycle().unwrap();
+        }
+        for &(row, left, right) in &[(30, 0xC0, 0x03), (31, 0x40, 0x02), (0, 0x40, 0x02), (2, 0xC0, 0x03)] {
+            assert_eq!(rows(&chip, row, 1)[0], left);
+            assert_eq!(rows(&chip, row, 1)[7], right);
+        }
+    }
+
+    #[test]
+    fn quirk_presets_by_name() {
+        assert_eq!(Quirks::preset("chip8"), Some(Quirks::CHIP8));
+        assert_eq!(Quirks::preset("SCHIP"), Some(Quirks::SUPER_CHIP));
+        assert_eq!(Quirks::preset("xochip"), Some(Quirks::XO_CHIP));
+        assert_eq!(Quirks::preset("unknown"), None);
+    }
+
+    #[test]
+    fn pc_out_of_bounds() {
+        let mut chip = load(&[0x6010, 0xBFFF]);
+        chip.set_quirks(Quirks::CHIP8);
+        chip.cycle().unwrap();
+        chip.cycle().unwrap();
+        assert_eq!(chip.cycle(), Err(Chip8Error::PcOutOfBounds { pc: 0x100F }));
+
+        // the last byte of memory can not hold a whole instruction
+        let mut chip = load(&[0x1FFF]);
+        chip.cycle().unwrap();
+        assert_eq!(chip.cycle(), Err(Chip8Error::PcOutOfBounds { pc: 0xFFF }));
+    }
+
+    #[test]
+    fn indexed_memory_out_of_bounds() {
+        for &instruction in &[0xD005, 0xF033, 0xF555, 0xF565] {
+            let mut chip = load(&[0xAFFE, instruction]);
+            chip.cycle().unwrap();
+            assert_eq!(
+                chip.cycle(),
+                Err(Chip8Error::MemoryOutOfBounds { pc: 0x202, address: 0x1000 })
+            );
+        }
+
+        // nothing is written when only part of the range is in memory
+        let mut chip = load(&[0xAFFE, 0xF555]);
+        chip.v[0..6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+        chip.cycle().unwrap();
+        assert!(chip.cycle().is_err());
+        assert_eq!(chip.memory[0xFFE..], [0, 0]);
+    }
+
+    #[test]
+    fn index_register_wraps() {
+        let mut chip = load(&[0xF11E, 0xF11E]);
+        chip.i = 0xFFF0;
+        chip.v[1] = 0x20;
+        chip.cycle().unwrap();
+        assert_eq!(chip.i, 0x0010);
+    }
+
+    #[test]
+    fn random_programs_never_panic() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0xF022);
+        for _ in 0..200 {
+            let mut chip = Chip8::with_seed(0);
+            let (_, quirks) = Quirks::PRESETS[rng.gen_range(0..3)];
+            chip.set_quirks(quirks);
+            rng.fill(&mut chip.memory[0x200..]);
+
+            for cycle in 0..2000 {
+                chip.write_keypad(rng.gen(), rng.gen());
+                if chip.cycle().is_err() {
+                    break;
+                }
+                if cycle % 10 == 0 {
+                    chip.tick_timers();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keypad_addresses_wrap() {
+        let mut chip = Chip8::new();
+        chip.write_keypad(0x12, true);
+        assert!(chip.read_keypad(0x2));
+    }
+
+    #[test]
+    fn frame_matches_pixels() {
+        let chip = run(&[0xA000, 0x6003, 0x6102, 0xD015]);
+        let frame = chip.frame();
+        for y in 0..DISPLAY_HEIGHT {
+            for x in 0..DISPLAY_WIDTH {
+                assert_eq!(frame.pixel(x, y), chip.pixel(x, y));
+            }
+        }
+        assert!(frame.pixel(3, 2));
+        assert!(!frame.pixel(2, 2));
+    }
+}
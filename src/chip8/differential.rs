@@ -0,0 +1,424 @@
+// Differential tests against a reference model.
+//
+// `Reference` is a deliberately simple implementation of the same
+// semantics: one match arm per instruction, memory accessed byte by byte and
+// sprites drawn pixel by pixel. Random machine states and instructions are
+// run through both it and `Chip8::cycle`, and the resulting machines must
+// be identical.
+
+use super::*;
+
+use proptest::prelude::*;
+use rand::Rng;
+
+struct Reference {
+    memory: Vec<u8>,
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: [bool; 16],
+    quirks: Quirks,
+    vblank: bool,
+    display_updated: bool,
+    rng: ChaCha8Rng,
+}
+
+impl Reference {
+    fn pixel(&self, x: usize, y: usize) -> bool {
+        self.memory[0xF00 + y * 8 + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+    fn flip(&mut self, x: usize, y: usize) {
+        self.memory[0xF00 + y * 8 + x / 8] ^= 0x80 >> (x % 8);
+    }
+
+    fn check_indexed(&self, pc: u16, len: usize) -> Result<(), Chip8Error> {
+        if self.i as usize + len > 4096 {
+            let address = (self.i as usize).max(4096);
+            return Err(Chip8Error::MemoryOutOfBounds { pc, address });
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), Chip8Error> {
+        self.display_updated = false;
+
+        let pc = self.pc;
+        if pc as usize + 1 >= 4096 {
+            return Err(Chip8Error::PcOutOfBounds { pc });
+        }
+        let op = (self.memory[pc as usize] as u16) << 8 | self.memory[pc as usize + 1] as u16;
+        self.pc += 2;
+
+        let x = (op >> 8 & 0xF) as usize;
+        let y = (op >> 4 & 0xF) as usize;
+        let n = op & 0xF;
+        let nn = (op & 0xFF) as u8;
+        let nnn = op & 0xFFF;
+        let invalid = Chip8Error::InvalidInstruction { pc, instruction: op };
+
+        match (op >> 12, n) {
+            _ if op == 0x00E0 => {
+                for byte in &mut self.memory[0xF00..] {
+                    *byte = 0;
+                }
+                self.display_updated = true;
+            }
+            _ if op == 0x00EE => {
+                self.pc = self.stack.pop().ok_or(Chip8Error::StackUnderflow { pc })?;
+            }
+            (0x0, _) => return Err(invalid),
+            (0x1, _) => self.pc = nnn,
+            (0x2, _) => {
+                if self.stack.len() == 16 {
+                    return Err(Chip8Error::StackOverflow { pc });
+                }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            (0x3, _) => {
+                if self.v[x] == nn {
+                    self.pc += 2;
+                }
+            }
+            (0x4, _) => {
+                if self.v[x] != nn {
+                    self.pc += 2;
+                }
+            }
+            (0x5, _) => {
+                if self.v[x] == self.v[y] {
+                    self.pc += 2;
+                }
+            }
+            (0x6, _) => self.v[x] = nn,
+            (0x7, _) => self.v[x] = self.v[x].wrapping_add(nn),
+            (0x8, 0x0) => self.v[x] = self.v[y],
+            (0x8, 0x1) | (0x8, 0x2) | (0x8, 0x3) => {
+                self.v[x] = match n {
+                    0x1 => self.v[x] | self.v[y],
+                    0x2 => self.v[x] & self.v[y],
+                    _ => self.v[x] ^ self.v[y],
+                };
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            (0x8, 0x4) => {
+                let (result, carry) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = result;
+                self.v[0xF] = carry as u8;
+            }
+            (0x8, 0x5) => {
+                let (result, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                self.v[x] = result;
+                self.v[0xF] = !borrow as u8;
+            }
+            (0x8, 0x7) => {
+                let (result, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                self.v[x] = result;
+                self.v[0xF] = !borrow as u8;
+            }
+            (0x8, 0x6) | (0x8, 0xE) => {
+                let value = if self.quirks.shifting { self.v[x] } else { self.v[y] };
+                let (result, flag) = if n == 0x6 {
+                    (value / 2, value % 2)
+                } else {
+                    (value.wrapping_mul(2), value / 128)
+                };
+                self.v[x] = result;
+                self.v[0xF] = flag;
+            }
+            (0x9, _) => {
+                if self.v[x] != self.v[y] {
+                    self.pc += 2;
+                }
+            }
+            (0xA, _) => self.i = nnn,
+            (0xB, _) => {
+                let register = if self.quirks.jumping { x } else { 0 };
+                self.pc = nnn + self.v[register] as u16;
+            }
+            (0xC, _) => self.v[x] = self.rng.gen::<u8>() & nn,
+            (0xD, _) => {
+                if self.quirks.display_wait {
+                    if !self.vblank {
+                        self.pc = pc;
+                        return Ok(());
+                    }
+                    self.vblank = false;
+                }
+                self.check_indexed(pc, n as usize)?;
+                self.display_updated = true;
+
+                let left = self.v[x] as usize % 64;
+                let top = self.v[y] as usize % 32;
+                // the sprite is read before drawing, it may overlap the display
+                let start = self.i as usize;
+                let sprite = self.memory[start..start + n as usize].to_vec();
+                self.v[0xF] = 0;
+                for (row, sprite) in sprite.into_iter().enumerate() {
+                    for column in 0..8 {
+                        if sprite & (0x80 >> column) == 0 {
+                            continue;
+                        }
+                        let (x, y) = (left + column, top + row);
+                        if self.quirks.clipping && (x >= 64 || y >= 32) {
+                            continue;
+                        }
+                        if self.pixel(x % 64, y % 32) {
+                            self.v[0xF] = 1;
+                        }
+                        self.flip(x % 64, y % 32);
+                    }
+                }
+            }
+            (0xE, _) if nn == 0x9E => {
+                if self.keypad[self.v[x] as usize % 16] {
+                    self.pc += 2;
+                }
+            }
+            (0xE, _) if nn == 0xA1 => {
+                if !self.keypad[self.v[x] as usize % 16] {
+                    self.pc += 2;
+                }
+            }
+            (0xF, _) if nn == 0x07 => self.v[x] = self.delay_timer,
+            (0xF, _) if nn == 0x0A => match self.keypad.iter().position(|&pressed| pressed) {
+                Some(key) => self.v[x] = key as u8,
+                None => self.pc = pc,
+            },
+            (0xF, _) if nn == 0x15 => self.delay_timer = self.v[x],
+            (0xF, _) if nn == 0x18 => self.sound_timer = self.v[x],
+            (0xF, _) if nn == 0x1E => self.i = self.i.wrapping_add(self.v[x] as u16),
+            (0xF, _) if nn == 0x29 => {
+                if self.v[x] > 0xF {
+                    return Err(Chip8Error::InvalidFontCharacter { pc, character: self.v[x] });
+                }
+                self.i = self.v[x] as u16 * 5;
+            }
+            (0xF, _) if nn == 0x33 => {
+                self.check_indexed(pc, 3)?;
+                let i = self.i as usize;
+                self.memory[i] = self.v[x] / 100;
+                self.memory[i + 1] = self.v[x] / 10 % 10;
+                self.memory[i + 2] = self.v[x] % 10;
+            }
+            (0xF, _) if nn == 0x55 || nn == 0x65 => {
+                self.check_indexed(pc, x + 1)?;
+                for register in 0..=x {
+                    let address = self.i as usize + register;
+                    if nn == 0x55 {
+                        self.memory[address] = self.v[register];
+                    } else {
+                        self.v[register] = self.memory[address];
+                    }
+                }
+                if self.quirks.memory {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            _ => return Err(invalid),
+        }
+        Ok(())
+    }
+}
+
+// a random machine state, applied to both implementations
+#[derive(Clone, Debug)]
+struct State {
+    memory: Vec<u8>,
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    timers: (u8, u8),
+    keypad: [bool; 16],
+    quirks: Quirks,
+    vblank: bool,
+    seed: u64,
+}
+
+impl State {
+    fn chip8(&self) -> Chip8 {
+        let mut chip = Chip8::with_seed(self.seed);
+        chip.memory.copy_from_slice(&self.memory);
+        chip.v = self.v;
+        chip.i = self.i;
+        chip.pc = self.pc;
+        chip.stack[..self.stack.len()].copy_from_slice(&self.stack);
+        chip.sp = self.stack.len();
+        chip.delay_timer = self.timers.0;
+        chip.sound_timer = self.timers.1;
+        chip.keypad = self.keypad;
+        chip.quirks = self.quirks;
+        chip.vblank = self.vblank;
+        chip
+    }
+
+    fn reference(&self) -> Reference {
+        Reference {
+            memory: self.memory.clone(),
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            stack: self.stack.clone(),
+            delay_timer: self.timers.0,
+            sound_timer: self.timers.1,
+            keypad: self.keypad,
+            quirks: self.quirks,
+            vblank: self.vblank,
+            display_updated: false,
+            rng: ChaCha8Rng::seed_from_u64(self.seed),
+        }
+    }
+}
+
+// (mask, value) pairs for each documented instruction, the bits outside the
+// mask are random
+const INSTRUCTIONS: &[(u16, u16)] = &[
+    (0xFFFF, 0x00E0),
+    (0xFFFF, 0x00EE),
+    (0xF000, 0x1000),
+    (0xF000, 0x2000),
+    (0xF000, 0x3000),
+    (0xF000, 0x4000),
+    (0xF00F, 0x5000),
+    (0xF000, 0x6000),
+    (0xF000, 0x7000),
+    (0xF00F, 0x8000),
+    (0xF00F, 0x8001),
+    (0xF00F, 0x8002),
+    (0xF00F, 0x8003),
+    (0xF00F, 0x8004),
+    (0xF00F, 0x8005),
+    (0xF00F, 0x8006),
+    (0xF00F, 0x8007),
+    (0xF00F, 0x800E),
+    (0xF00F, 0x9000),
+    (0xF000, 0xA000),
+    (0xF000, 0xB000),
+    (0xF000, 0xC000),
+    (0xF000, 0xD000),
+    (0xF0FF, 0xE09E),
+    (0xF0FF, 0xE0A1),
+    (0xF0FF, 0xF007),
+    (0xF0FF, 0xF00A),
+    (0xF0FF, 0xF015),
+    (0xF0FF, 0xF018),
+    (0xF0FF, 0xF01E),
+    (0xF0FF, 0xF029),
+    (0xF0FF, 0xF033),
+    (0xF0FF, 0xF055),
+    (0xF0FF, 0xF065),
+];
+
+fn instruction() -> impl Strategy<Value = u16> {
+    prop_oneof![
+        1 => any::<u16>(),
+        4 => (0..INSTRUCTIONS.len(), any::<u16>()).prop_map(|(index, bits)| {
+            let (mask, value) = INSTRUCTIONS[index];
+            bits & !mask | value
+        }),
+    ]
+}
+
+fn state() -> impl Strategy<Value = State> {
+    let machine = (
+        prop::collection::vec(any::<u8>(), 4096),
+        prop::array::uniform16(any::<u8>()),
+        // mostly valid addresses, but sometimes anything
+        prop_oneof![4 => 0u16..0x1000, 1 => any::<u16>()],
+        0u16..0x1000,
+        prop::collection::vec(0u16..0x1000, 0..=16),
+    );
+    let io = (
+        any::<(u8, u8)>(),
+        prop::array::uniform16(prop::bool::weighted(0.1)),
+        0..Quirks::PRESETS.len(),
+        any::<bool>(),
+        any::<u64>(),
+    );
+    (machine, io).prop_map(|((memory, v, i, pc, stack), (timers, keypad, preset, vblank, seed))| State {
+        memory,
+        v,
+        i,
+        pc,
+        stack,
+        timers,
+        keypad,
+        quirks: Quirks::PRESETS[preset].1,
+        vblank,
+        seed,
+    })
+}
+
+fn assert_same(chip: &Chip8, reference: &Reference) -> Result<(), TestCaseError> {
+    prop_assert_eq!(chip.v, reference.v, "registers");
+    prop_assert_eq!(chip.i, reference.i, "I");
+    prop_assert_eq!(chip.pc, reference.pc, "pc");
+    prop_assert_eq!(&chip.stack[..chip.sp], &reference.stack[..], "stack");
+    prop_assert_eq!(chip.delay_timer, reference.delay_timer, "delay timer");
+    prop_assert_eq!(chip.sound_timer, reference.sound_timer, "sound timer");
+    prop_assert_eq!(chip.display_updated, reference.display_updated, "display updated");
+    prop_assert_eq!(&chip.memory[..0xF00], &reference.memory[..0xF00], "memory");
+    prop_assert_eq!(&chip.memory[0xF00..], &reference.memory[0xF00..], "display");
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    // a single instruction from a random state
+    #[test]
+    fn instruction_matches_reference(mut state in state(), instruction in instruction()) {
+        let pc = state.pc as usize;
+        if pc + 1 < 4096 {
+            state.memory[pc] = (instruction >> 8) as u8;
+            state.memory[pc + 1] = instruction as u8;
+        }
+
+        let mut chip = state.chip8();
+        let mut reference = state.reference();
+        let result = chip.cycle();
+        prop_assert_eq!(result, reference.step());
+        if result.is_ok() {
+            assert_same(&chip, &reference)?;
+        }
+    }
+
+    // a run of random instructions, with timers ticking between some of them
+    #[test]
+    fn program_matches_reference(
+        state in state(),
+        program in prop::collection::vec(instruction(), 1..32),
+        ticks in prop::collection::vec(any::<bool>(), 32),
+    ) {
+        let mut state = state;
+        state.pc = 0x200;
+        for (addr, instruction) in (0x200..).step_by(2).zip(&program) {
+            state.memory[addr] = (instruction >> 8) as u8;
+            state.memory[addr + 1] = *instruction as u8;
+        }
+
+        let mut chip = state.chip8();
+        let mut reference = state.reference();
+        for &tick in ticks.iter().take(program.len()) {
+            let result = chip.cycle();
+            prop_assert_eq!(result, reference.step());
+            if result.is_err() {
+                break;
+            }
+            assert_same(&chip, &reference)?;
+
+            if tick {
+                chip.tick_timers();
+                reference.delay_timer = reference.delay_timer.saturating_sub(1);
+                reference.sound_timer = reference.sound_timer.saturating_sub(1);
+                reference.vblank = true;
+            }
+        }
+    }
+}
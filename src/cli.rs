@@ -0,0 +1,466 @@
+// Command line parsing for the chip8 binary.
+
+use chip8::audio::{DEFAULT_FREQUENCY, DEFAULT_VOLUME};
+use chip8::graphics::Protocol;
+use chip8::headless::{HeadlessConfig, KeyScript};
+use chip8::screenshot::{ImageFormat, ImageOptions, Palette, MAX_SCALE};
+use chip8::terminal::RenderMode;
+use chip8::theme::{ColourDepth, Theme};
+use chip8::Quirks;
+
+pub const USAGE: &str = "\
+usage:
+    chip8 [play] <rom> [options]     run a program in the terminal, reading it
+                                     from stdin when <rom> is -
+    chip8 headless <rom> [options]   run a program without a terminal
+    chip8 config [<rom>] [options]   print the settings the player would use for a
+                                     rom, or the defaults from the configuration file
+    chip8 info <rom> [options]       show a rom's size, hash and database entry,
+                                     and the instructions and quirks it uses
+    chip8 conformance <dir> [--bless] [--cycles <n>]
+                                     run the test roms in a directory under every
+                                     quirk preset, checking the marks they draw or
+                                     comparing their displays with snapshots
+
+    roms can be binaries (.ch8, .sc8, .xo8), hex dumps (.hex, .txt) or Octo
+    cartridges (.gif), guessed from the contents for other names
+
+options:
+    --cycles <n>        instructions executed per 60Hz frame (default 10, or the
+                        speed the rom or the database gives)
+    --quirks <preset>   chip8, schip or xochip (default schip without the jumping
+                        quirk, or the quirks the rom or the database gives)
+    --seed <n>          seed for the random number generator, for reproducible runs
+    --load-address <addr>
+                        where the program is loaded, e.g. 0x600 for ETI-660
+                        programs (default 0x200)
+    --entry <addr>      where the program starts (default the load address)
+    --database <dir>    look roms up in a copy of the CHIP-8 community database
+                        rather than the bundled one
+    --no-database       do not look roms up, for their quirks, speed and colours
+    --scale <n>         screenshot pixel size, up to 32 (default 8)
+    --palette <bg,fg>   screenshot colours as RRGGBB,RRGGBB (default 000000,ffffff)
+
+play options:
+    --config <path>     read settings from this file rather than
+                        $XDG_CONFIG_HOME/chip8/config.toml
+    --no-config         do not read the configuration file
+    --save-config       remember this session's settings for the rom in the
+                        configuration file, with the renderer and speed it ends on
+    --renderer <mode>   squares, half-blocks, braille or ascii (default squares),
+                        Tab switches between them while playing
+    --theme <theme>     draw in colour with green, amber, high-contrast,
+                        colour-blind or colours as RRGGBB,RRGGBB (background and
+                        foreground, plus the second plane and both planes)
+    --colours <depth>   16, 256 or 24bit colours (default from $COLORTERM and
+                        $TERM, no colour when $NO_COLOR is set)
+    --graphics <mode>   draw the display as an image with auto, sixel or kitty,
+                        or off (default off), falling back to text when the
+                        terminal does not support it
+    --status            show the rom, speed and frame rate below the display
+    --hud               show the registers, timers and keys held below the display
+    --keypad            draw a keypad beside the display that can be clicked
+    --audio <backend>   bell, visual, none or wav:<path> (default bell)
+    --pitch <hz>        tone frequency for synthesised audio (default 440)
+    --volume <level>    tone volume from 0.0 to 1.0 (default 0.25)
+    --screenshot-format <format>
+                        png or pbm, for screenshots taken with Ctrl+S (default png)
+    --record-movie <path>
+                        record the keys pressed to a movie file, which replays
+                        the session exactly with `headless --movie`
+
+    Ctrl+R starts and stops recording a GIF, scaled and coloured with
+    --scale and --palette. P pauses, + and - change the speed, I shows and
+    hides the status bar, H the HUD and K the keypad. : opens a command line
+    for cheats: search, equal <n>, changed, unchanged, increased, decreased,
+    poke <addr> <n>, freeze <addr> [<n>] [<name>], cheats, toggle <i>,
+    delete <i> and save
+
+headless options:
+    --frames <n>        number of frames to run (default 600)
+    --until-halt        stop early once the program jumps to itself
+    --keys <script>     scripted input, e.g. '30:5,32:,90:4a'
+    --keys-file <path>  read scripted input from a file
+    --output <path>     write the final display to a file instead of stdout
+    --screenshot <path> also save the final display as a .png or .pbm image
+    --record <path>     record every frame to an animated .gif
+    --movie <path>      replay a movie recorded by the player, with its seed,
+                        settings and keys, checking it ends on the same display
+                        and machine state
+
+exit status:
+    0   success
+    1   the program raised an error
+    2   invalid usage or unreadable files
+    3   --until-halt was given but the program did not halt, a movie
+        did not end as it was recorded, or a conformance test failed
+";
+
+pub const DEFAULT_CYCLES: u32 = 10;
+
+// headless options a movie gives itself
+const MOVIE_OPTIONS: [&str; 9] = [
+    "--frames",
+    "--until-halt",
+    "--keys",
+    "--keys-file",
+    "--cycles",
+    "--quirks",
+    "--seed",
+    "--load-address",
+    "--entry",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Audio {
+    Bell,
+    Visual,
+    None,
+    Wav(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Graphics {
+    Off,
+    // use the protocol, or whichever the terminal supports
+    Detect(Option<Protocol>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigFile {
+    Default,
+    Path(String),
+    Off,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Database {
+    Bundled,
+    Dir(String),
+    Off,
+}
+
+// the rom to run and how to set up the machine for it
+//
+// Settings are None when they are not given, so those that came with the
+// rom or are in the database can be used instead.
+#[derive(Clone, Debug)]
+pub struct RomOptions {
+    pub path: String,
+    pub load_address: Option<u16>,
+    pub entry: Option<u16>,
+    pub seed: Option<u64>,
+    pub quirks: Option<Quirks>,
+    pub cycles_per_frame: Option<u32>,
+    pub database: Database,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlayOptions {
+    pub rom: RomOptions,
+    pub config: ConfigFile,
+    pub save_config: bool,
+    pub render_mode: Option<RenderMode>,
+    pub theme: Option<Theme>,
+    pub colour_depth: Option<ColourDepth>,
+    pub graphics: Graphics,
+    pub status: bool,
+    pub hud: bool,
+    pub keypad: bool,
+    pub audio: Audio,
+    pub pitch: f32,
+    pub volume: f32,
+    pub image: ImageOptions,
+    pub screenshot_format: ImageFormat,
+    pub record_movie: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeadlessOptions {
+    pub rom: RomOptions,
+    pub config: HeadlessConfig,
+    pub output: Option<String>,
+    pub image: ImageOptions,
+    pub screenshot: Option<String>,
+    pub record: Option<String>,
+    pub movie: Option<String>,
+}
+
+// the configuration file, and the player's settings for a rom when one is given
+#[derive(Clone, Debug)]
+pub struct ConfigOptions {
+    pub config: ConfigFile,
+    pub play: Option<PlayOptions>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InfoOptions {
+    pub rom: RomOptions,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConformanceOptions {
+    pub dir: String,
+    pub cycles_per_frame: u32,
+    pub bless: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Play(PlayOptions),
+    Headless(HeadlessOptions),
+    Info(InfoOptions),
+    Config(ConfigOptions),
+    Conformance(ConformanceOptions),
+    Help,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Play,
+    Headless,
+    Info,
+    Config,
+    Conformance,
+}
+
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.peekable();
+
+    let mode = match args.peek().map(String::as_str) {
+        None | Some("-h") | Some("--help") | Some("help") => return Ok(Command::Help),
+        Some("play") => Some(Mode::Play),
+        Some("headless") => Some(Mode::Headless),
+        Some("info") => Some(Mode::Info),
+        Some("config") => Some(Mode::Config),
+        Some("conformance") => Some(Mode::Conformance),
+        _ => None,
+    };
+    if mode.is_some() {
+        args.next();
+    }
+    // play is the default subcommand
+    let mode = mode.unwrap_or(Mode::Play);
+    // config takes the play options, to show the settings they lead to
+    let play = mode == Mode::Play || mode == Mode::Config;
+    let headless = mode == Mode::Headless;
+    let info = mode == Mode::Info;
+    let conformance = mode == Mode::Conformance;
+
+    let mut rom = None;
+    let mut load_address = None;
+    let mut entry = None;
+    let mut seed = None;
+    let mut quirks = None;
+    let mut bless = false;
+    let mut cycles_per_frame = None;
+    let mut database = Database::Bundled;
+    let mut config_file = ConfigFile::Default;
+    let mut save_config = false;
+    let mut render_mode = None;
+    let mut theme = None;
+    let mut colour_depth = None;
+    let mut graphics = Graphics::Off;
+    let mut status = false;
+    let mut hud = false;
+    let mut keypad = false;
+    let mut audio = Audio::Bell;
+    let mut pitch = DEFAULT_FREQUENCY;
+    let mut volume = DEFAULT_VOLUME;
+    let mut config = HeadlessConfig::default();
+    let mut output = None;
+    let mut image = ImageOptions::default();
+    let mut screenshot_format = ImageFormat::Png;
+    let mut screenshot = None;
+    let mut record = None;
+    let mut record_movie = None;
+    let mut movie = None;
+    let mut movie_option = None; // the first option a movie would override
+
+    while let Some(arg) = args.next() {
+        if headless && MOVIE_OPTIONS.contains(&arg.as_str()) {
+            movie_option.get_or_insert_with(|| arg.clone());
+        }
+        match arg.as_str() {
+            "--cycles" => cycles_per_frame = Some(parse_value(&arg, args.next())?),
+            "--seed" if play || headless => seed = Some(parse_value(&arg, args.next())?),
+            "--load-address" if !conformance => load_address = Some(parse_address(&arg, args.next())?),
+            "--entry" if !conformance => entry = Some(parse_address(&arg, args.next())?),
+            "--database" if !conformance => database = Database::Dir(value(&arg, args.next())?),
+            "--no-database" if !conformance => database = Database::Off,
+            "--quirks" if !conformance => {
+                let name = value(&arg, args.next())?;
+                quirks = Some(Quirks::preset(&name).ok_or_else(|| format!("unknown quirk preset '{}'", name))?);
+            }
+            "--scale" if play || headless => {
+                image.scale = parse_value(&arg, args.next())?;
+                if !(1..=MAX_SCALE).contains(&image.scale) {
+                    return Err(format!("'--scale' should be from 1 to {}", MAX_SCALE));
+                }
+            }
+            "--palette" if play || headless => image.palette = Palette::parse(&value(&arg, args.next())?)?,
+            "--bless" if conformance => bless = true,
+            "--config" if play => config_file = ConfigFile::Path(value(&arg, args.next())?),
+            "--no-config" if play => config_file = ConfigFile::Off,
+            "--save-config" if mode == Mode::Play => save_config = true,
+            "--renderer" if play => {
+                let name = value(&arg, args.next())?;
+                render_mode =
+                    Some(RenderMode::from_name(&name).ok_or_else(|| format!("unknown renderer '{}'", name))?);
+            }
+            "--theme" if play => theme = Some(Theme::parse(&value(&arg, args.next())?)?),
+            "--colours" | "--colors" if play => {
+                let depth = value(&arg, args.next())?;
+                colour_depth =
+                    Some(ColourDepth::parse(&depth).ok_or_else(|| format!("unknown colour depth '{}'", depth))?);
+            }
+            "--graphics" if play => {
+                graphics = match value(&arg, args.next())?.as_str() {
+                    "off" => Graphics::Off,
+                    "auto" => Graphics::Detect(None),
+                    "sixel" => Graphics::Detect(Some(Protocol::Sixel)),
+                    "kitty" => Graphics::Detect(Some(Protocol::Kitty)),
+                    other => return Err(format!("unknown graphics mode '{}'", other)),
+                }
+            }
+            "--status" if play => status = true,
+            "--hud" if play => hud = true,
+            "--keypad" if play => keypad = true,
+            "--audio" if play => {
+                audio = match value(&arg, args.next())?.as_str() {
+                    "bell" => Audio::Bell,
+                    "visual" => Audio::Visual,
+                    "none" => Audio::None,
+                    other => match other.strip_prefix("wav:") {
+                        Some(path) if !path.is_empty() => Audio::Wav(path.to_string()),
+                        _ => return Err(format!("unknown audio backend '{}'", other)),
+                    },
+                }
+            }
+            "--pitch" if play => pitch = parse_value(&arg, args.next())?,
+            "--volume" if play => volume = parse_value(&arg, args.next())?,
+            "--screenshot-format" if play => {
+                screenshot_format = match value(&arg, args.next())?.as_str() {
+                    "png" => ImageFormat::Png,
+                    "pbm" => ImageFormat::Pbm,
+                    other => return Err(format!("unknown image format '{}'", other)),
+                }
+            }
+            "--record-movie" if mode == Mode::Play => record_movie = Some(value(&arg, args.next())?),
+            "--frames" if headless => config.frames = parse_value(&arg, args.next())?,
+            "--until-halt" if headless => config.until_halt = true,
+            "--keys" if headless => config.keys = KeyScript::parse(&value(&arg, args.next())?)?,
+            "--keys-file" if headless => {
+                let path = value(&arg, args.next())?;
+                let script = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("unable to read '{}': {}", path, e))?;
+                config.keys = KeyScript::parse(&script)?;
+            }
+            "--output" if headless => output = Some(value(&arg, args.next())?),
+            "--screenshot" if headless => {
+                let path = value(&arg, args.next())?;
+                if ImageFormat::from_path(&path).is_none() {
+                    return Err(format!("'{}' should be a .png or .pbm file", path));
+                }
+                screenshot = Some(path);
+            }
+            "--record" if headless => record = Some(value(&arg, args.next())?),
+            "--movie" if headless => movie = Some(value(&arg, args.next())?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    if let (Some(_), Some(option)) = (&movie, movie_option) {
+        return Err(format!("'{}' can't be used with '--movie', which has its own settings and keys", option));
+    }
+
+    if conformance {
+        let dir = rom.ok_or_else(|| "no test rom directory given".to_string())?;
+        return Ok(Command::Conformance(ConformanceOptions {
+            dir,
+            cycles_per_frame: cycles_per_frame.unwrap_or(DEFAULT_CYCLES),
+            bless,
+        }));
+    }
+
+    if mode == Mode::Config && rom.is_none() {
+        return Ok(Command::Config(ConfigOptions {
+            config: config_file,
+            play: None,
+        }));
+    }
+
+    let rom = RomOptions {
+        path: rom.ok_or_else(|| "no rom given".to_string())?,
+        load_address,
+        entry,
+        seed,
+        quirks,
+        cycles_per_frame,
+        database,
+    };
+
+    Ok(if info {
+        Command::Info(InfoOptions { rom })
+    } else if headless {
+        Command::Headless(HeadlessOptions {
+            rom,
+            config,
+            output,
+            image,
+            screenshot,
+            record,
+            movie,
+        })
+    } else {
+        let options = PlayOptions {
+            rom,
+            config: config_file.clone(),
+            save_config,
+            render_mode,
+            theme,
+            colour_depth,
+            graphics,
+            status,
+            hud,
+            keypad,
+            audio,
+            pitch,
+            volume,
+            image,
+            screenshot_format,
+            record_movie,
+        };
+        if mode == Mode::Config {
+            Command::Config(ConfigOptions {
+                config: config_file,
+                play: Some(options),
+            })
+        } else {
+            Command::Play(options)
+        }
+    })
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("missing value for '{}'", option))
+}
+
+// an address written in hex with a 0x prefix, or in decimal
+fn parse_address(option: &str, value: Option<String>) -> Result<u16, String> {
+    let value = self::value(option, value)?;
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address '{}' for '{}'", value, option))
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = self::value(option, value)?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for '{}'", value, option))
+}
@@ -0,0 +1,535 @@
+// The player's configuration file.
+//
+// A TOML file in the XDG config directory holds default settings, and
+// settings for particular roms in sections named after their SHA-1 hash:
+//
+//   cycles = 15
+//   renderer = "braille"
+//   speed = 2.0
+//
+//   [roms.b232ef880bd6060fb45fa6effed7edf0ae95670e]
+//   name = "pong.ch8"
+//   quirks = "chip8"
+//   keys = "x123qweasdzc4rfv"
+//   cheats = [{ name = "lives", address = "2F0", value = 9, enabled = true }]
+//
+// Quirks are a preset name or a table of the quirks, keys are the keyboard
+// keys for the keypad keys 0 to F and speed is a multiple of 60 frames per
+// second, as set with + and -. Cheats are made in the player.
+
+use crate::cheats::{self, Cheat};
+use crate::chip8::Quirks;
+use crate::frontend::SPEEDS;
+use crate::terminal::{KeyMap, RenderMode};
+use crate::theme::Theme;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, Item};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    pub cycles_per_frame: Option<u32>,
+    pub quirks: Option<Quirks>,
+    pub theme: Option<Theme>,
+    pub renderer: Option<RenderMode>,
+    pub speed: Option<usize>, // an index into SPEEDS
+    pub keys: Option<KeyMap>,
+}
+
+impl Settings {
+    // these settings, with those missing taken from `other`
+    pub fn or(&self, other: &Settings) -> Settings {
+        Settings {
+            cycles_per_frame: self.cycles_per_frame.or(other.cycles_per_frame),
+            quirks: self.quirks.or(other.quirks),
+            theme: self.theme.or(other.theme),
+            renderer: self.renderer.or(other.renderer),
+            speed: self.speed.or(other.speed),
+            keys: self.keys.or(other.keys),
+        }
+    }
+
+    fn from_table(table: &Table, allowed: &[&str]) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        for (key, value) in table {
+            let string = || value.as_str().ok_or_else(|| format!("'{}' should be a string", key));
+            match key.as_str() {
+                "cycles" => {
+                    let cycles = value.as_integer().ok_or("'cycles' should be a number")?;
+                    settings.cycles_per_frame =
+                        Some(u32::try_from(cycles).map_err(|_| format!("invalid number of cycles {}", cycles))?);
+                }
+                "quirks" => settings.quirks = Some(quirks_from_value(value)?),
+                "theme" => settings.theme = Some(Theme::parse(string()?)?),
+                "renderer" => {
+                    let name = string()?;
+                    settings.renderer =
+                        Some(RenderMode::from_name(name).ok_or_else(|| format!("unknown renderer '{}'", name))?);
+                }
+                "speed" => {
+                    let speed = value.as_float().or_else(|| value.as_integer().map(|speed| speed as f64));
+                    let speed = speed.ok_or("'speed' should be a number")?;
+                    settings.speed = Some(
+                        SPEEDS
+                            .iter()
+                            .position(|&option| f64::from(option) == speed)
+                            .ok_or_else(|| format!("invalid speed {}, it can be 0.25, 0.5, 1, 2, 4, 8 or 16", speed))?,
+                    );
+                }
+                "keys" => settings.keys = Some(KeyMap::parse(string()?)?),
+                _ if allowed.contains(&key.as_str()) => {}
+                _ => return Err(format!("unknown setting '{}'", key)),
+            }
+        }
+        Ok(settings)
+    }
+
+    fn to_table(self) -> Table {
+        let mut table = Table::new();
+        if let Some(cycles) = self.cycles_per_frame {
+            table.insert("cycles".to_string(), Value::Integer(cycles.into()));
+        }
+        if let Some(quirks) = self.quirks {
+            table.insert("quirks".to_string(), quirks_to_value(quirks));
+        }
+        if let Some(theme) = self.theme {
+            table.insert("theme".to_string(), Value::String(theme.to_string()));
+        }
+        if let Some(renderer) = self.renderer {
+            table.insert("renderer".to_string(), Value::String(renderer.name().to_string()));
+        }
+        if let Some(speed) = self.speed {
+            table.insert("speed".to_string(), Value::Float(SPEEDS[speed].into()));
+        }
+        if let Some(keys) = self.keys {
+            table.insert("keys".to_string(), Value::String(keys.to_string()));
+        }
+        table
+    }
+
+    // the settings as they would be written in the configuration file
+    pub fn to_toml(self) -> String {
+        self.to_table().to_string()
+    }
+}
+
+// the names quirks are given in the configuration file
+const QUIRK_NAMES: [&str; 6] = ["vf_reset", "memory", "display_wait", "clipping", "shifting", "jumping"];
+
+fn quirk_fields(quirks: &mut Quirks) -> [&mut bool; 6] {
+    [
+        &mut quirks.vf_reset,
+        &mut quirks.memory,
+        &mut quirks.display_wait,
+        &mut quirks.clipping,
+        &mut quirks.shifting,
+        &mut quirks.jumping,
+    ]
+}
+
+// a preset name, or a table of quirks with those missing taken from the
+// default quirks
+pub(crate) fn quirks_from_value(value: &Value) -> Result<Quirks, String> {
+    match value {
+        Value::String(name) => Quirks::preset(name).ok_or_else(|| format!("unknown quirk preset '{}'", name)),
+        Value::Table(table) => {
+            let mut quirks = Quirks::default();
+            let fields = quirk_fields(&mut quirks);
+            for (name, value) in table {
+                let index = QUIRK_NAMES
+                    .iter()
+                    .position(|quirk| quirk == name)
+                    .ok_or_else(|| format!("unknown quirk '{}'", name))?;
+                *fields[index] = value.as_bool().ok_or_else(|| format!("quirk '{}' should be true or false", name))?;
+            }
+            Ok(quirks)
+        }
+        _ => Err("'quirks' should be a preset name or a table of quirks".to_string()),
+    }
+}
+
+pub(crate) fn quirks_to_value(mut quirks: Quirks) -> Value {
+    if let Some(name) = quirks.name() {
+        return Value::String(name.to_string());
+    }
+    let fields = quirk_fields(&mut quirks);
+    let table = QUIRK_NAMES.iter().zip(fields).map(|(name, &mut value)| (name.to_string(), Value::Boolean(value)));
+    Value::Table(table.collect())
+}
+
+// a cheat as a table with the address in hex, as the player shows it
+fn cheat_from_value(value: &Value) -> Result<Cheat, String> {
+    let table = value.as_table().ok_or("a cheat should be a table")?;
+    let address = table.get("address").and_then(Value::as_str).ok_or("a cheat needs an address")?;
+    let value = table.get("value").and_then(Value::as_integer).ok_or("a cheat needs a value")?;
+    Ok(Cheat {
+        name: table.get("name").and_then(Value::as_str).unwrap_or_default().to_string(),
+        address: cheats::parse_address(address)?,
+        value: u8::try_from(value).map_err(|_| format!("invalid cheat value {}", value))?,
+        enabled: table.get("enabled").and_then(Value::as_bool).unwrap_or(true),
+    })
+}
+
+fn cheat_to_value(cheat: &Cheat) -> Value {
+    let mut table = Table::new();
+    if !cheat.name.is_empty() {
+        table.insert("name".to_string(), Value::String(cheat.name.clone()));
+    }
+    table.insert("address".to_string(), Value::String(format!("{:03X}", cheat.address)));
+    table.insert("value".to_string(), Value::Integer(cheat.value.into()));
+    table.insert("enabled".to_string(), Value::Boolean(cheat.enabled));
+    Value::Table(table)
+}
+
+// settings for one rom
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RomSection {
+    pub name: Option<String>, // the rom's file name, as a reminder of which rom the hash is
+    pub settings: Settings,
+    pub cheats: Vec<Cheat>,
+}
+
+impl RomSection {
+    fn to_table(&self) -> Table {
+        let mut table = self.settings.to_table();
+        if let Some(name) = &self.name {
+            table.insert("name".to_string(), Value::String(name.clone()));
+        }
+        if !self.cheats.is_empty() {
+            let cheats = self.cheats.iter().map(cheat_to_value).collect();
+            table.insert("cheats".to_string(), Value::Array(cheats));
+        }
+        table
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub defaults: Settings,
+    pub roms: BTreeMap<String, RomSection>, // by SHA-1 hash
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl Config {
+    // `$XDG_CONFIG_HOME/chip8/config.toml`, or under `~/.config` when it is
+    // not set
+    //
+    // See: https://specifications.freedesktop.org/basedir-spec/latest/
+    pub fn path() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(dir.join("chip8").join("config.toml"))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let table: Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.to_string().trim_end().to_string()))?;
+        let mut config = Config {
+            defaults: Settings::from_table(&table, &["roms"]).map_err(ConfigError::Invalid)?,
+            ..Config::default()
+        };
+
+        let roms = match table.get("roms") {
+            Some(Value::Table(roms)) => roms.clone(),
+            Some(_) => return Err(ConfigError::Invalid("'roms' should be a table".to_string())),
+            None => Table::new(),
+        };
+        for (hash, section) in roms {
+            let invalid = |reason: String| ConfigError::Invalid(format!("[roms.{}]: {}", hash, reason));
+            let section = section.as_table().ok_or_else(|| invalid("should be a table".to_string()))?;
+            let name = match section.get("name") {
+                Some(name) => Some(name.as_str().ok_or_else(|| invalid("'name' should be a string".to_string()))?),
+                None => None,
+            };
+            let settings = Settings::from_table(section, &["name", "cheats"]).map_err(invalid)?;
+            let cheats = match section.get("cheats") {
+                Some(Value::Array(cheats)) => cheats.iter().map(cheat_from_value).collect::<Result<_, _>>(),
+                Some(_) => Err("'cheats' should be a list of cheats".to_string()),
+                None => Ok(Vec::new()),
+            };
+            config.roms.insert(
+                hash.to_ascii_lowercase(),
+                RomSection {
+                    name: name.map(str::to_string),
+                    settings,
+                    cheats: cheats.map_err(invalid)?,
+                },
+            );
+        }
+        Ok(config)
+    }
+
+    // read a configuration file, which is empty when it does not exist
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_toml())?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = self.defaults.to_table();
+        let roms = self.roms.iter().map(|(hash, section)| (hash.clone(), Value::Table(section.to_table())));
+        let roms: Table = roms.collect();
+        if !roms.is_empty() {
+            table.insert("roms".to_string(), Value::Table(roms));
+        }
+        table.to_string()
+    }
+
+    // the settings for a rom, by its hash, without the defaults
+    pub fn rom(&self, hash: &str) -> Settings {
+        self.roms.get(hash).map(|section| section.settings).unwrap_or_default()
+    }
+}
+
+// change a rom's section of a configuration file, creating either when they
+// do not exist
+//
+// Only the section's settings are rewritten, so the rest of the file keeps
+// its comments and layout.
+pub fn update_section<F>(path: &Path, hash: &str, update: F) -> Result<(), ConfigError>
+where
+    F: FnOnce(&mut RomSection),
+{
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut section = Config::parse(&text)?.roms.remove(hash).unwrap_or_default();
+    update(&mut section);
+
+    let mut document: DocumentMut =
+        text.parse().map_err(|e: toml_edit::TomlError| ConfigError::Invalid(e.to_string()))?;
+    let updated: DocumentMut = section.to_table().to_string().parse().expect("a section is valid TOML");
+    let roms = document["roms"].or_insert({
+        let mut roms = toml_edit::Table::new();
+        roms.set_implicit(true);
+        Item::Table(roms)
+    });
+    let roms = roms.as_table_like_mut().expect("'roms' is a table");
+    // the hash may have been written in capitals
+    let key = roms.iter().map(|(key, _)| key).find(|key| key.eq_ignore_ascii_case(hash)).unwrap_or(hash).to_string();
+    let existing = roms.entry(&key).or_insert(toml_edit::table());
+    let existing = existing.as_table_like_mut().expect("rom sections are tables");
+
+    let removed: Vec<String> =
+        existing.iter().map(|(key, _)| key.to_string()).filter(|key| !updated.contains_key(key)).collect();
+    for key in removed {
+        existing.remove(&key);
+    }
+    for (key, item) in updated.iter() {
+        // unchanged values keep their formatting and comments
+        let unchanged = match (existing.get(key).and_then(Item::as_value), item.as_value()) {
+            (Some(old), Some(new)) => old.to_string().trim() == new.to_string().trim(),
+            _ => false,
+        };
+        if !unchanged {
+            existing.insert(key, item.clone());
+        }
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &str = "b232ef880bd6060fb45fa6effed7edf0ae95670e";
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(&format!(
+            r#"
+            cycles = 15
+            renderer = "braille"
+            theme = "amber"
+            speed = 2
+
+            [roms.{}]
+            name = "pong.ch8"
+            quirks = "chip8"
+            keys = "x123qweasdzc4rfv"
+            cheats = [{{ name = "lives", address = "2f0", value = 9 }}]
+
+            [roms.0000000000000000000000000000000000000000]
+            quirks = {{ shifting = false, vf_reset = true }}
+            "#,
+            PONG
+        ))
+        .unwrap();
+        assert_eq!(config.defaults.cycles_per_frame, Some(15));
+        assert_eq!(config.defaults.renderer, Some(RenderMode::Braille));
+        assert_eq!(config.defaults.speed.map(|speed| SPEEDS[speed]), Some(2.0));
+        assert_eq!(config.roms[PONG].name.as_deref(), Some("pong.ch8"));
+        let lives = Cheat {
+            name: "lives".to_string(),
+            address: 0x2F0,
+            value: 9,
+            enabled: true,
+        };
+        assert_eq!(config.roms[PONG].cheats, [lives]);
+
+        let pong = config.rom(PONG).or(&config.defaults);
+        assert_eq!(pong.quirks, Some(Quirks::CHIP8));
+        assert_eq!(pong.cycles_per_frame, Some(15));
+        assert_eq!(pong.keys.unwrap().key('x'), Some(0));
+
+        let quirks = config.rom("0000000000000000000000000000000000000000").quirks.unwrap();
+        assert!(!quirks.shifting && quirks.vf_reset && !quirks.jumping && !quirks.memory);
+        assert_eq!(config.rom("unknown"), Settings::default());
+    }
+
+    #[test]
+    fn invalid() {
+        for text in [
+            "cycles = -1",
+            "cycles = \"fast\"",
+            "speed = 3",
+            "speed = \"fast\"",
+            "quirks = \"cosmac\"",
+            "quirks = { wrapping = true }",
+            "keys = \"1234\"",
+            "roms = 1",
+            "[roms.abc]\nname = 1",
+            "[roms.abc]\ncheats = [{ address = \"xyz\", value = 1 }]",
+            "[roms.abc]\ncheats = [{ address = \"300\", value = 256 }]",
+            "cycles = ",
+        ] {
+            assert!(matches!(Config::parse(text), Err(ConfigError::Invalid(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = Config {
+            defaults: Settings {
+                cycles_per_frame: Some(20),
+                renderer: Some(RenderMode::HalfBlocks),
+                speed: Some(0),
+                ..Settings::default()
+            },
+            ..Config::default()
+        };
+        config.roms.insert(
+            PONG.to_string(),
+            RomSection {
+                name: Some("pong.ch8".to_string()),
+                settings: Settings {
+                    quirks: Some(Quirks { clipping: false, ..Quirks::CHIP8 }),
+                    theme: Some(Theme::parse("000000,ff8000").unwrap()),
+                    keys: Some(KeyMap::default()),
+                    ..Settings::default()
+                },
+                cheats: vec![Cheat {
+                    name: String::new(),
+                    address: 0x300,
+                    value: 0,
+                    enabled: false,
+                }],
+            },
+        );
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let path = env::temp_dir().join(format!("chip8-config-{}", std::process::id())).join("config.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        let config = Config {
+            defaults: Settings {
+                cycles_per_frame: Some(12),
+                ..Settings::default()
+            },
+            ..Config::default()
+        };
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn update_sections() {
+        let path = env::temp_dir().join(format!("chip8-config-update-{}", std::process::id())).join("config.toml");
+        update_section(&path, PONG, |section| section.settings.speed = Some(3)).unwrap();
+        assert_eq!(Config::load(&path).unwrap().roms[PONG].settings.speed, Some(3));
+
+        let text = format!(
+            "# played too fast\ncycles = 8 # slower\n\n[roms.{}]\nspeed = 8.0\n# made in the player\nkeys = \"{}\"\n",
+            PONG.to_ascii_uppercase(),
+            KeyMap::default()
+        );
+        fs::write(&path, &text).unwrap();
+        update_section(&path, PONG, |section| {
+            section.name = Some("pong.ch8".to_string());
+            section.settings.speed = None;
+        })
+        .unwrap();
+        let updated = fs::read_to_string(&path).unwrap();
+        assert!(updated.starts_with("# played too fast\ncycles = 8 # slower\n"), "{}", updated);
+        assert!(updated.contains("# made in the player\n"), "{}", updated);
+        assert!(!updated.contains("speed"), "{}", updated);
+        let config = Config::parse(&updated).unwrap();
+        assert_eq!(config.roms[PONG].name.as_deref(), Some("pong.ch8"));
+        assert_eq!(config.roms[PONG].settings.keys, Some(KeyMap::default()));
+        assert_eq!(config.defaults.cycles_per_frame, Some(8));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+// Runs the community CHIP-8 test roms under every quirk preset.
+//
+// The roms are not distributed with this crate, they are read from a
+// directory. The Timendus roms that draw a check or a cross beside each test
+// pass when they end showing checks and no crosses. Other roms are compared
+// with a snapshot of their display for each preset, stored as
+// `expected/<rom>.<preset>.txt` in the format of `headless::display_to_text`,
+// and running with `bless` writes the current displays as the snapshots.
+
+use crate::chip8::{Chip8, Frame, Quirks};
+use crate::headless::{self, HeadlessConfig, KeyScript};
+use crate::rom::{self, LoadOptions};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// how to run a known test rom
+struct TestRom {
+    name: &'static str,
+    frames: u32,
+    keys: &'static str,
+    // the Timendus roms read the platform (or test) to run from 0x1FF
+    // instead of showing a menu
+    select: Option<Select>,
+    // whether it draws a check or a cross for each test, rather than only
+    // having a display to compare
+    marked: bool,
+}
+
+enum Select {
+    Platform, // 1 for CHIP-8, 2 for SUPER-CHIP, 3 for XO-CHIP
+    Test(u8),
+}
+
+// See: https://github.com/Timendus/chip8-test-suite
+const KNOWN_ROMS: &[TestRom] = &[
+    TestRom { name: "1-chip8-logo.ch8", frames: 60, keys: "", select: None, marked: false },
+    TestRom { name: "2-ibm-logo.ch8", frames: 60, keys: "", select: None, marked: false },
+    TestRom { name: "3-corax+.ch8", frames: 120, keys: "", select: None, marked: true },
+    TestRom { name: "4-flags.ch8", frames: 300, keys: "", select: None, marked: true },
+    TestRom { name: "5-quirks.ch8", frames: 1200, keys: "", select: Some(Select::Platform), marked: true },
+    // the FX0A test, with a key pressed and released
+    TestRom { name: "6-keypad.ch8", frames: 240, keys: "60:5,70:", select: Some(Select::Test(3)), marked: true },
+    TestRom { name: "test_opcode.ch8", frames: 120, keys: "", select: None, marked: false },
+    TestRom { name: "BC_test.ch8", frames: 120, keys: "", select: None, marked: false },
+];
+
+// frames to run roms that are not in `KNOWN_ROMS`
+const DEFAULT_FRAMES: u32 = 300;
+
+// the marks the Timendus roms draw for passing and failing tests, as rows of
+// pixels
+const CHECK: [&str; 5] = ["......#", ".....#.", "#...#..", ".#.#...", "..#...."];
+const CROSS: [&str; 5] = ["#...#", ".#.#.", "..#..", ".#.#.", "#...#"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail,
+    // there is no expected display for this rom and preset
+    Missing,
+    // the expected display was written
+    Blessed,
+    // the rom raised an error
+    Error(String),
+}
+
+impl Status {
+    fn label(&self) -> &str {
+        match self {
+            Status::Pass => "pass",
+            Status::Fail => "FAIL",
+            Status::Missing => "missing",
+            Status::Blessed => "blessed",
+            Status::Error(_) => "ERROR",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaseResult {
+    pub rom: String,
+    pub preset: &'static str,
+    pub status: Status,
+    // compared with a snapshot rather than the rom's own marks
+    pub snapshot: bool,
+}
+
+// results for every rom and preset
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub results: Vec<CaseResult>,
+}
+
+impl Report {
+    // whether nothing failed or raised an error
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| !matches!(result.status, Status::Fail | Status::Error(_)))
+    }
+
+    // a table with a row for each rom and a column for each preset,
+    // followed by any error messages
+    pub fn table(&self) -> String {
+        let mut roms: Vec<&str> = Vec::new();
+        for result in &self.results {
+            if !roms.contains(&result.rom.as_str()) {
+                roms.push(&result.rom);
+            }
+        }
+        let width = roms.iter().map(|rom| rom.len()).max().unwrap_or(0).max(3);
+
+        let mut table = format!("{:width$}", "rom", width = width);
+        for (preset, _) in &Quirks::PRESETS {
+            table.push_str(&format!("  {:8}", preset));
+        }
+        table.truncate(table.trim_end().len());
+        table.push('\n');
+
+        for rom in &roms {
+            table.push_str(&format!("{:width$}", rom, width = width));
+            for (preset, _) in &Quirks::PRESETS {
+                let label = self
+                    .results
+                    .iter()
+                    .find(|result| result.rom == *rom && result.preset == *preset)
+                    .map_or(String::new(), |result| {
+                        let mark = if result.snapshot { "*" } else { "" };
+                        format!("{}{}", result.status.label(), mark)
+                    });
+                table.push_str(&format!("  {:8}", label));
+            }
+            table.truncate(table.trim_end().len());
+            table.push('\n');
+        }
+
+        if self.results.iter().any(|result| result.snapshot) {
+            table.push_str("\n* compared with a snapshot written by --bless\n");
+        }
+        for result in &self.results {
+            if let Status::Error(message) = &result.status {
+                table.push_str(&format!("\n{} ({}): {}", result.rom, result.preset, message));
+            }
+        }
+        table
+    }
+}
+
+// run every `.ch8` file in `dir` under each quirk preset
+pub fn run_suite(dir: &Path, cycles_per_frame: u32, bless: bool) -> io::Result<Report> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ch8")))
+        .collect();
+    roms.sort();
+
+    let expected_dir = dir.join("expected");
+    if bless {
+        fs::create_dir_all(&expected_dir)?;
+    }
+
+    let mut report = Report::default();
+    for path in &roms {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let program = fs::read(path)?;
+
+        let known = KNOWN_ROMS.iter().find(|rom| rom.name.eq_ignore_ascii_case(&name));
+        let marked = known.is_some_and(|rom| rom.marked);
+
+        for (number, &(preset, quirks)) in Quirks::PRESETS.iter().enumerate() {
+            let chip = run_rom(known, &program, quirks, number as u8 + 1, cycles_per_frame);
+            let expected_path = expected_dir.join(format!("{}.{}.txt", name, preset));
+
+            let status = match chip {
+                Err(message) => Status::Error(message),
+                Ok(chip) if marked => marks(&chip.frame()),
+                Ok(chip) => snapshot(&headless::display_to_text(&chip), &expected_path, bless)?,
+            };
+            report.results.push(CaseResult {
+                rom: name.clone(),
+                preset,
+                status,
+                snapshot: !marked,
+            });
+        }
+    }
+    Ok(report)
+}
+
+// whether the display shows checks and no crosses
+fn marks(frame: &Frame) -> Status {
+    if count(frame, &CHECK) > 0 && count(frame, &CROSS) == 0 {
+        Status::Pass
+    } else {
+        Status::Fail
+    }
+}
+
+// compare a display with its snapshot, or write the snapshot when blessing
+fn snapshot(display: &str, path: &Path, bless: bool) -> io::Result<Status> {
+    if bless {
+        fs::write(path, display)?;
+        return Ok(Status::Blessed);
+    }
+    match fs::read_to_string(path) {
+        Ok(expected) if expected == display => Ok(Status::Pass),
+        Ok(_) => Ok(Status::Fail),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Status::Missing),
+        Err(e) => Err(e),
+    }
+}
+
+// run a single rom, returning the machine as it ends
+fn run_rom(
+    known: Option<&TestRom>,
+    program: &[u8],
+    quirks: Quirks,
+    platform: u8,
+    cycles_per_frame: u32,
+) -> Result<Chip8, String> {
+    let mut chip = Chip8::with_seed(0);
+    chip.set_quirks(quirks);
+    rom::load(&mut chip, program, &LoadOptions::default()).map_err(|e| e.to_string())?;
+
+    let mut config = HeadlessConfig {
+        frames: DEFAULT_FRAMES,
+        cycles_per_frame,
+        until_halt: true,
+        keys: KeyScript::default(),
+    };
+    if let Some(rom) = known {
+        config.frames = rom.frames;
+        config.keys = KeyScript::parse(rom.keys)?;
+        match rom.select {
+            Some(Select::Platform) => chip.write(0x1FF, platform),
+            Some(Select::Test(test)) => chip.write(0x1FF, test),
+            None => {}
+        }
+    }
+
+    headless::run(&mut chip, &config).map_err(|e| e.to_string())?;
+    Ok(chip)
+}
+
+// how many times `glyph` is on the display
+fn count(frame: &Frame, glyph: &[&str]) -> usize {
+    let width = glyph[0].len();
+    let height = glyph.len();
+    let drawn_at = |x: usize, y: usize| {
+        glyph.iter().enumerate().all(|(row, pixels)| {
+            pixels.bytes().enumerate().all(|(column, pixel)| frame.pixel(x + column, y + row) == (pixel == b'#'))
+        })
+    };
+    (0..=frame.height() - height)
+        .flat_map(|y| (0..=frame.width() - width).map(move |x| (x, y)))
+        .filter(|&(x, y)| drawn_at(x, y))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::ZERO;
+
+    use std::env;
+    use std::process;
+
+    fn statuses(report: &Report) -> Vec<&Status> {
+        report.results.iter().map(|result| &result.status).collect()
+    }
+
+    #[test]
+    fn bless_then_compare() {
+        let dir = env::temp_dir().join(format!("chip8-conformance-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("zero.ch8"), ZERO).unwrap();
+        fs::write(dir.join("notes.txt"), "not a rom").unwrap();
+
+        let report = run_suite(&dir, 10, false).unwrap();
+        assert_eq!(statuses(&report), [&Status::Missing; 3]);
+        assert!(report.passed());
+
+        let report = run_suite(&dir, 10, true).unwrap();
+        assert_eq!(statuses(&report), [&Status::Blessed; 3]);
+        let expected = dir.join("expected").join("zero.ch8.chip8.txt");
+        assert!(fs::read_to_string(&expected).unwrap().starts_with("⬜⬜⬜⬜⬛"));
+
+        let report = run_suite(&dir, 10, false).unwrap();
+        assert_eq!(statuses(&report), [&Status::Pass; 3]);
+        assert!(report.passed());
+
+        fs::write(&expected, "").unwrap();
+        let report = run_suite(&dir, 10, false).unwrap();
+        assert_eq!(statuses(&report), [&Status::Fail, &Status::Pass, &Status::Pass]);
+        assert!(!report.passed());
+        assert_eq!(
+            report.table(),
+            "rom       chip8     schip     xochip\nzero.ch8  FAIL*     pass*     pass*\n\n\
+             * compared with a snapshot written by --bless\n"
+        );
+
+        // returns with nothing on the stack
+        fs::write(dir.join("broken.ch8"), [0x00, 0xEE]).unwrap();
+        let report = run_suite(&dir, 10, false).unwrap();
+        assert_eq!(report.results[0].rom, "broken.ch8");
+        assert!(matches!(report.results[0].status, Status::Error(_)));
+        assert!(report.table().contains("\nbroken.ch8 (chip8): "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // draws a mark at 8,8 then halts
+    fn marked(mark: [u8; 5]) -> Vec<u8> {
+        let mut program = vec![0x60, 0x08, 0xA2, 0x08, 0xD0, 0x05, 0x12, 0x06];
+        program.extend_from_slice(&mark);
+        program
+    }
+
+    #[test]
+    fn timendus_roms_are_checked_by_their_marks() {
+        let dir = env::temp_dir().join(format!("chip8-conformance-marks-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("3-corax+.ch8"), marked([0x02, 0x04, 0x88, 0x50, 0x20])).unwrap();
+        fs::write(dir.join("4-flags.ch8"), marked([0x88, 0x50, 0x20, 0x50, 0x88])).unwrap();
+        fs::write(dir.join("5-quirks.ch8"), ZERO).unwrap();
+
+        // blessing writes nothing for them
+        let report = run_suite(&dir, 10, true).unwrap();
+        assert_eq!(statuses(&report)[..3], [&Status::Pass; 3]);
+        assert_eq!(statuses(&report)[3..6], [&Status::Fail; 3]);
+        assert_eq!(statuses(&report)[6..], [&Status::Fail; 3], "no marks at all");
+        assert!(report.results.iter().all(|result| !result.snapshot));
+        assert!(!report.table().contains('*'));
+        assert_eq!(fs::read_dir(dir.join("expected")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+// Looking roms up in a database of known programs, so they can be run with
+// the settings they need.
+//
+// The database is in the format of the CHIP-8 community database: programs
+// in programs.json, the programs' roms by SHA-1 hash in sha1-hashes.json and
+// the quirks of each platform in platforms.json. Only the example roms are
+// bundled, a copy of the full database can be used instead.
+//
+// See: https://github.com/chip-8/chip-8-database
+
+use crate::chip8::Quirks;
+use crate::rom::Rom;
+use crate::theme::Theme;
+
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+
+pub struct Database {
+    programs: Value,
+    hashes: Value,
+    platforms: Value,
+}
+
+// what the database knows about a rom
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GameInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Option<String>, // the name of the platform the rom was written for
+    pub quirks: Option<Quirks>,
+    pub cycles_per_frame: Option<u32>,
+    pub theme: Option<Theme>,
+    pub load_address: Option<u16>,
+    pub keys: Vec<(String, u8)>, // what each keypad key does
+}
+
+impl Database {
+    pub fn bundled() -> Database {
+        Database::parse(PROGRAMS, HASHES, PLATFORMS).expect("the bundled database is valid")
+    }
+
+    // a copy of the community database's `database` directory
+    pub fn open(dir: &Path) -> io::Result<Database> {
+        let read = |name: &str| fs::read_to_string(dir.join(name));
+        Database::parse(&read("programs.json")?, &read("sha1-hashes.json")?, &read("platforms.json")?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn parse(programs: &str, hashes: &str, platforms: &str) -> serde_json::Result<Database> {
+        Ok(Database {
+            programs: serde_json::from_str(programs)?,
+            hashes: serde_json::from_str(hashes)?,
+            platforms: serde_json::from_str(platforms)?,
+        })
+    }
+
+    pub fn lookup(&self, program: &[u8]) -> Option<GameInfo> {
+        let hash = sha1_hex(program);
+        let index = self.hashes[&hash].as_u64()?;
+        let program = &self.programs[index as usize];
+        let rom = &program["roms"][&hash];
+
+        let strings = |value: &Value| -> Vec<String> {
+            value
+                .as_array()
+                .map_or_else(Vec::new, |values| values.iter().filter_map(|v| Some(v.as_str()?.to_string())).collect())
+        };
+
+        // the first platform listed is the one the rom was written for
+        let platform_id = rom["platforms"][0].as_str();
+        let platform = platform_id.and_then(|id| {
+            self.platforms
+                .as_array()?
+                .iter()
+                .find(|platform| platform["id"].as_str() == Some(id))
+        });
+        let quirks = platform.map(|platform| {
+            let overrides = &rom["quirkyPlatforms"][platform_id.unwrap()];
+            quirks(&platform["quirks"], overrides)
+        });
+
+        // the background and plane colours, without the extra XO-CHIP planes
+        let theme = Some(strings(&rom["colors"]["pixels"]))
+            .filter(|colours| colours.len() >= 2)
+            .and_then(|colours| {
+                let count = if colours.len() >= 4 { 4 } else { 2 };
+                Theme::parse(&colours[..count].join(",")).ok()
+            });
+        let mut keys: Vec<(String, u8)> = rom["keys"]
+            .as_object()
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|(name, key)| Some((name.clone(), key.as_u64().filter(|&key| key < 16)? as u8)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        keys.sort_by_key(|&(_, key)| key);
+
+        Some(GameInfo {
+            title: program["title"].as_str().unwrap_or("Unknown").to_string(),
+            authors: strings(&program["authors"]),
+            release: program["release"].as_str().map(str::to_string),
+            platform: platform.and_then(|platform| platform["name"].as_str()).map(str::to_string),
+            quirks,
+            cycles_per_frame: rom["tickrate"]
+                .as_u64()
+                .or_else(|| platform?["defaultTickrate"].as_u64())
+                .map(|rate| rate as u32),
+            theme,
+            load_address: rom["startAddress"].as_u64().and_then(|address| u16::try_from(address).ok()),
+            keys,
+        })
+    }
+}
+
+// a platform's quirks, with any the rom needs set differently
+//
+// CHIP-48 and SUPER-CHIP 1.0 increment I by X rather than X + 1 when saving
+// and loading registers. There is no quirk for that, so they leave I
+// unchanged instead, like SUPER-CHIP 1.1; programs for them rarely read I
+// back afterwards.
+fn quirks(platform: &Value, overrides: &Value) -> Quirks {
+    let quirk = |name: &str| overrides[name].as_bool().or_else(|| platform[name].as_bool()).unwrap_or(false);
+    Quirks {
+        vf_reset: quirk("logic"),
+        memory: !quirk("memoryLeaveIUnchanged") && !quirk("memoryIncrementByX"),
+        display_wait: quirk("vblank"),
+        clipping: !quirk("wrap"),
+        shifting: quirk("shift"),
+        jumping: quirk("jump"),
+    }
+}
+
+impl GameInfo {
+    // fill in the settings the rom did not come with
+    pub fn apply(&self, rom: &mut Rom) {
+        rom.quirks = rom.quirks.or(self.quirks);
+        rom.cycles_per_frame = rom.cycles_per_frame.or(self.cycles_per_frame);
+        rom.theme = rom.theme.or(self.theme);
+        rom.load_address = rom.load_address.or(self.load_address);
+    }
+
+    // the title, authors and keys on one line
+    pub fn summary(&self) -> String {
+        let mut summary = self.title.clone();
+        if !self.authors.is_empty() {
+            summary += &format!(" by {}", self.authors.join(", "));
+        }
+        if !self.keys.is_empty() {
+            let keys: Vec<String> = self.keys.iter().map(|(name, key)| format!("{:X} {}", key, name)).collect();
+            summary += &format!(" - {}", keys.join(", "));
+        }
+        summary
+    }
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &[u8] = include_bytes!("../examples/pong.ch8");
+
+    #[test]
+    fn hashes() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(PONG), "b232ef880bd6060fb45fa6effed7edf0ae95670e");
+    }
+
+    #[test]
+    fn bundled_examples() {
+        let database = Database::bundled();
+        let pong = database.lookup(PONG).unwrap();
+        assert_eq!(pong.title, "Pong");
+        assert_eq!(pong.platform.as_deref(), Some("Cosmac VIP CHIP-8"));
+        assert_eq!(pong.quirks, Some(Quirks::CHIP8));
+        // the rom has no tickrate, so the platform's is used
+        assert_eq!(pong.cycles_per_frame, Some(15));
+        assert_eq!(pong.summary(), "Pong by Paul Vervalin - 1 up, 4 down, C player2Up, D player2Down");
+        assert_eq!(database.lookup(&[0x12, 0x00]), None);
+    }
+
+    #[test]
+    fn platform_quirks() {
+        let platforms = Database::bundled().platforms;
+        let platform = |id: &str| {
+            let platforms = platforms.as_array().unwrap();
+            let platform = platforms.iter().find(|platform| platform["id"] == id).unwrap();
+            quirks(&platform["quirks"], &Value::Null)
+        };
+        assert_eq!(platform("originalChip8"), Quirks::CHIP8);
+        // I is left unchanged where these would increment it by X
+        assert_eq!(platform("chip48"), Quirks::SUPER_CHIP);
+        assert_eq!(platform("superchip1"), Quirks::SUPER_CHIP);
+        assert_eq!(platform("superchip"), Quirks::SUPER_CHIP);
+        assert_eq!(platform("xochip"), Quirks::XO_CHIP);
+    }
+
+    #[test]
+    fn rom_settings() {
+        let programs = r##"[{
+            "title": "Test", "authors": ["A", "B"],
+            "roms": {"b232ef880bd6060fb45fa6effed7edf0ae95670e": {
+                "platforms": ["originalChip8"], "tickrate": 20, "startAddress": 1536,
+                "colors": {"pixels": ["#000000", "#ff8000", "#0080ff", "#ffffff"]},
+                "quirkyPlatforms": {"originalChip8": {"shift": true}}
+            }}
+        }]"##;
+        let hashes = r#"{"b232ef880bd6060fb45fa6effed7edf0ae95670e": 0}"#;
+        let database = Database::parse(programs, hashes, PLATFORMS).unwrap();
+        let game = database.lookup(PONG).unwrap();
+        assert_eq!(game.quirks, Some(Quirks { shifting: true, ..Quirks::CHIP8 }));
+        assert_eq!(game.load_address, Some(0x600));
+        assert_eq!(game.summary(), "Test by A, B");
+
+        // settings that came with the rom win
+        let mut rom = Rom {
+            cycles_per_frame: Some(7),
+            ..Rom::default()
+        };
+        game.apply(&mut rom);
+        assert_eq!(rom.cycles_per_frame, Some(7));
+        assert_eq!(rom.theme.unwrap().colours[1], [0xFF, 0x80, 0x00]);
+        assert_eq!(rom.theme.unwrap().colours[3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(rom.quirks, game.quirks);
+    }
+}
@@ -0,0 +1,680 @@
+// Frontends for running a program interactively.
+//
+// A frontend is made of a `Renderer` that presents the display, an `Input`
+// that is polled for the keypad and player actions, and an `AudioBackend`
+// for the sound timer. `run` drives a machine through any combination of
+// them, one 60Hz frame at a time.
+
+use crate::audio::AudioBackend;
+use crate::chip8::{Chip8, Chip8Error};
+use crate::terminal::RenderMode;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Renderer {
+    // called at the end of a frame in which the display or message changed
+    fn render(&mut self, chip: &Chip8) -> io::Result<()>;
+
+    // show a line of text alongside the display from the next render,
+    // replacing the previous one
+    fn message(&mut self, _message: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    // switch to the next way of drawing the display, if there is more than
+    // one, returning a message describing it
+    fn next_mode(&mut self) -> Option<String> {
+        None
+    }
+
+    // the way of drawing the display picked last, for renderers that have
+    // more than one
+    fn render_mode(&self) -> Option<RenderMode> {
+        None
+    }
+
+    // the space available changed, the display is redrawn afterwards
+    fn resize(&mut self, _size: TerminalSize) {}
+
+    // lines shown below the message from the next render, such as the
+    // status bar and register HUD, replacing the previous ones
+    fn panel(&mut self, _lines: &[String]) {}
+
+    // show or hide the on-screen keypad, from the next render
+    fn show_keypad(&mut self, _show: bool) {}
+
+    // the on-screen keypad key drawn at a cell, with one-based coordinates
+    fn key_at(&self, _column: u16, _row: u16) -> Option<u8> {
+        None
+    }
+}
+
+// the size of a terminal in cells, and in pixels when it reports them (or 0)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub columns: u16,
+    pub rows: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+// something the player asked for, other than pressing keypad keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Screenshot,
+    ToggleRecording,
+    NextRenderMode,
+    Resize(TerminalSize),
+    Pause,
+    SpeedUp,
+    SpeedDown,
+    ToggleStatus,
+    ToggleHud,
+    ToggleKeypad,
+    // the mouse button was pressed at a cell, with one-based coordinates
+    MouseDown { column: u16, row: u16 },
+    MouseUp,
+    // a command being typed after `:`, or None once it is entered or cancelled
+    Typing(Option<String>),
+    // a command that was entered, for `on_frame`
+    Command(String),
+}
+
+// input read at the start of a frame
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Poll {
+    pub keys: u16, // bitmask of the keypad keys held during the frame
+    pub actions: Vec<Action>,
+}
+
+pub trait Input {
+    fn poll(&mut self) -> io::Result<Poll>;
+}
+
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    pub title: String, // shown in the status bar, usually the rom name
+    pub cycles_per_frame: u32,
+    // an index into SPEEDS, which `run` leaves where the player set it
+    pub speed: usize,
+    // sleep so frames run at 60Hz, disable to run as fast as possible
+    pub throttle: bool,
+    // render at most 60 times a second, however fast frames are running
+    pub cap_redraw: bool,
+    // show the status bar and the register HUD from the start
+    pub status: bool,
+    pub hud: bool,
+    // draw a keypad beside the display that can be clicked
+    pub keypad: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        RunConfig {
+            title: String::new(),
+            cycles_per_frame: 10,
+            speed: NORMAL_SPEED,
+            throttle: true,
+            cap_redraw: true,
+            status: false,
+            hud: false,
+            keypad: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Execution { frame: u32, error: Chip8Error }, // counting frames from the start
+    Io(io::Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Execution { error, .. } => write!(f, "{}", error),
+            RunError::Io(e) => write!(f, "terminal error: {}", e),
+        }
+    }
+}
+
+impl error::Error for RunError {}
+
+impl From<io::Error> for RunError {
+    fn from(e: io::Error) -> RunError {
+        RunError::Io(e)
+    }
+}
+
+pub const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// speeds the player can pick from, as multiples of 60 frames per second
+pub const SPEEDS: [f32; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0];
+pub const NORMAL_SPEED: usize = 2;
+
+// how often the frame and instruction rates are worked out
+const METER_INTERVAL: Duration = Duration::from_millis(500);
+
+// measures frames and instructions per second
+struct Meter {
+    start: Instant,
+    frames: u32,
+    cycles: u64,
+    fps: f64,
+    ips: f64,
+}
+
+impl Meter {
+    fn new() -> Meter {
+        Meter {
+            start: Instant::now(),
+            frames: 0,
+            cycles: 0,
+            fps: 0.0,
+            ips: 0.0,
+        }
+    }
+
+    fn frame(&mut self, cycles: u32) {
+        self.frames += 1;
+        self.cycles += cycles as u64;
+    }
+
+    fn update(&mut self) {
+        let elapsed = self.start.elapsed();
+        if elapsed >= METER_INTERVAL {
+            let seconds = elapsed.as_secs_f64();
+            self.fps = self.frames as f64 / seconds;
+            self.ips = self.cycles as f64 / seconds;
+            *self = Meter {
+                fps: self.fps,
+                ips: self.ips,
+                ..Meter::new()
+            };
+        }
+    }
+}
+
+fn status_line(title: &str, meter: &Meter, speed: f32, paused: bool, sound: bool) -> String {
+    let mut line = format!("{}  {:.0} fps  {:.0} ips  {}x", title, meter.fps, meter.ips, speed);
+    if paused {
+        line.push_str("  paused");
+    }
+    if sound {
+        line.push_str("  sound");
+    }
+    line.trim_start().to_string()
+}
+
+fn hud_lines(chip: &Chip8) -> Vec<String> {
+    let registers = |range: std::ops::Range<usize>| {
+        range
+            .map(|x| format!("V{:X} {:02x}", x, chip.registers()[x]))
+            .collect::<Vec<String>>()
+            .join("  ")
+    };
+    let keys: Vec<String> = (0..16)
+        .filter(|key| chip.keys() & (1 << key) != 0)
+        .map(|key| format!("{:X}", key))
+        .collect();
+
+    vec![
+        format!(
+            "PC {:03x}  I {:03x}  DT {:02x}  ST {:02x}",
+            chip.pc(),
+            chip.i(),
+            chip.delay_timer(),
+            chip.sound_timer()
+        ),
+        registers(0..8),
+        registers(8..16),
+        format!("keys {}", if keys.is_empty() { "-".to_string() } else { keys.join(" ") }),
+    ]
+}
+
+// when the next redraw is due, after one that was due at `due` happened at
+// `now`
+//
+// Redraws are spaced FRAME_TIME apart on average, so frames that take
+// slightly less than that still redraw every time, but a late one only
+// brings the next forward by a frame.
+fn after_redraw(due: Instant, now: Instant) -> Instant {
+    (due + FRAME_TIME).max(now.checked_sub(FRAME_TIME).unwrap_or(now))
+}
+
+// run until the player quits
+//
+// Actions that control the player are handled here, `on_frame` is called
+// once per 60Hz tick with the other actions (screenshots, recording and
+// commands) that were polled at its start, and returns a message to show.
+// It is also given the frames of the program run during the tick, counted
+// from the start, which is none while paused and several when running
+// faster than normal.
+pub fn run<R, I, A, F>(
+    chip: &mut Chip8,
+    config: &mut RunConfig,
+    renderer: &mut R,
+    input: &mut I,
+    audio: &mut A,
+    on_frame: F,
+) -> Result<(), RunError>
+where
+    R: Renderer + ?Sized,
+    I: Input + ?Sized,
+    A: AudioBackend + ?Sized,
+    F: FnMut(&mut Chip8, &[Action], Range<u32>) -> Option<String>,
+{
+    run_with(chip, config, renderer, input, audio, |_| {}, on_frame)
+}
+
+// run, also calling `before_frame` with the machine before every frame of
+// the program, however many run in a tick
+pub fn run_with<R, I, A, B, F>(
+    chip: &mut Chip8,
+    config: &mut RunConfig,
+    renderer: &mut R,
+    input: &mut I,
+    audio: &mut A,
+    mut before_frame: B,
+    mut on_frame: F,
+) -> Result<(), RunError>
+where
+    R: Renderer + ?Sized,
+    I: Input + ?Sized,
+    A: AudioBackend + ?Sized,
+    B: FnMut(&mut Chip8),
+    F: FnMut(&mut Chip8, &[Action], Range<u32>) -> Option<String>,
+{
+    let mut show_keypad = config.keypad;
+    renderer.show_keypad(show_keypad);
+    renderer.render(chip)?;
+    let mut redraw = false;
+    let mut next_redraw = Instant::now();
+
+    let mut paused = false;
+    let speed = &mut config.speed;
+    *speed = (*speed).min(SPEEDS.len() - 1);
+    let mut frames_due = 0.0;
+    let mut frame = 0; // frames of the program run so far
+    let mut show_status = config.status;
+    let mut show_hud = config.hud;
+    let mut meter = Meter::new();
+    let mut panel = Vec::new();
+    let mut clicked = None; // the on-screen key held down with the mouse
+    let mut shown_keys = chip.keys();
+
+    loop {
+        let frame_start = Instant::now();
+
+        let mut poll = input.poll()?;
+        for action in &poll.actions {
+            match *action {
+                Action::Quit => return Ok(()),
+                Action::NextRenderMode => {
+                    if let Some(message) = renderer.next_mode() {
+                        renderer.message(&message)?;
+                        redraw = true;
+                    }
+                }
+                Action::Resize(size) => {
+                    renderer.resize(size);
+                    redraw = true;
+                }
+                Action::Pause => paused = !paused,
+                Action::SpeedUp => *speed = (*speed + 1).min(SPEEDS.len() - 1),
+                Action::SpeedDown => *speed = speed.saturating_sub(1),
+                Action::ToggleStatus => show_status = !show_status,
+                Action::ToggleHud => show_hud = !show_hud,
+                Action::ToggleKeypad => {
+                    show_keypad = !show_keypad;
+                    renderer.show_keypad(show_keypad);
+                    redraw = true;
+                }
+                // a click shorter than a frame still presses the key
+                Action::MouseDown { column, row } => {
+                    clicked = renderer.key_at(column, row);
+                    poll.keys |= clicked.map_or(0, |key| 1 << key);
+                }
+                Action::MouseUp => clicked = None,
+                Action::Typing(ref command) => {
+                    renderer.message(&command.as_ref().map_or_else(String::new, |command| format!(":{}", command)))?;
+                    redraw = true;
+                }
+                Action::Screenshot | Action::ToggleRecording | Action::Command(_) => {}
+            }
+        }
+        poll.keys |= clicked.map_or(0, |key| 1 << key);
+        poll.actions.retain(|action| {
+            matches!(action, Action::Screenshot | Action::ToggleRecording | Action::Command(_))
+        });
+        for key in 0..16 {
+            chip.write_keypad(key, poll.keys & (1 << key) != 0);
+        }
+
+        // run as many frames as the speed allows, carrying over fractions
+        let first_frame = frame;
+        if !paused {
+            frames_due += SPEEDS[*speed];
+        }
+        while frames_due >= 1.0 {
+            before_frame(chip);
+            for _ in 0..config.cycles_per_frame {
+                chip.cycle().map_err(|error| RunError::Execution { frame, error })?;
+                redraw |= chip.display_updated();
+            }
+            chip.tick_timers();
+            meter.frame(config.cycles_per_frame);
+            frames_due -= 1.0;
+            frame += 1;
+
+            while let Some(event) = chip.poll_sound_event() {
+                audio.event(event);
+            }
+            audio.frame(chip.sound_active());
+        }
+        meter.update();
+
+        if let Some(message) = on_frame(chip, &poll.actions, first_frame..frame) {
+            renderer.message(&message)?;
+            redraw = true;
+        }
+
+        let mut lines = Vec::new();
+        if show_status {
+            lines.push(status_line(&config.title, &meter, SPEEDS[*speed], paused, chip.sound_active()));
+        }
+        if show_hud {
+            lines.extend(hud_lines(chip));
+        }
+        if show_keypad && chip.keys() != shown_keys {
+            shown_keys = chip.keys();
+            redraw = true;
+        }
+        if lines != panel {
+            renderer.panel(&lines);
+            panel = lines;
+            redraw = true;
+        }
+
+        let now = Instant::now();
+        if redraw && (!config.cap_redraw || now >= next_redraw) {
+            renderer.render(chip)?;
+            redraw = false;
+            next_redraw = after_redraw(next_redraw, now);
+        }
+
+        if config.throttle {
+            if let Some(remaining) = FRAME_TIME.checked_sub(frame_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::load;
+    use crate::chip8::SoundEvent;
+
+    // replays a list of polls, then quits
+    struct Script(Vec<Poll>);
+
+    impl Input for Script {
+        fn poll(&mut self) -> io::Result<Poll> {
+            if self.0.is_empty() {
+                return Ok(Poll { keys: 0, actions: vec![Action::Quit] });
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    #[derive(Default)]
+    struct Frames {
+        rendered: Vec<String>,
+        messages: Vec<String>,
+        panels: Vec<Vec<String>>,
+    }
+
+    impl Renderer for Frames {
+        fn render(&mut self, chip: &Chip8) -> io::Result<()> {
+            self.rendered.push(crate::headless::display_to_text(chip));
+            Ok(())
+        }
+        fn message(&mut self, message: &str) -> io::Result<()> {
+            self.messages.push(message.to_string());
+            Ok(())
+        }
+        fn panel(&mut self, lines: &[String]) {
+            self.panels.push(lines.to_vec());
+        }
+        // a keypad with key N in column N
+        fn key_at(&self, column: u16, _row: u16) -> Option<u8> {
+            (column < 16).then_some(column as u8)
+        }
+    }
+
+    #[derive(Default)]
+    struct Sound(Vec<SoundEvent>);
+
+    impl AudioBackend for Sound {
+        fn event(&mut self, event: SoundEvent) {
+            self.0.push(event);
+        }
+    }
+
+    const CONFIG: RunConfig = RunConfig {
+        title: String::new(),
+        cycles_per_frame: 10,
+        speed: NORMAL_SPEED,
+        throttle: false,
+        cap_redraw: false,
+        status: false,
+        hud: false,
+        keypad: false,
+    };
+
+    #[test]
+    fn renders_changed_frames() {
+        // clear the screen, draw the font character in V0, then halt
+        let mut chip = load(&[0x00, 0xE0, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06]);
+        let mut renderer = Frames::default();
+        let mut input = Script(vec![Poll::default(); 3]);
+
+        run(&mut chip, &mut CONFIG.clone(), &mut renderer, &mut input, &mut Sound::default(), |_, _, _| None).unwrap();
+        // the initial display, then the frame that drew
+        assert_eq!(renderer.rendered.len(), 2);
+        assert!(chip.pixel(0, 0));
+    }
+
+    #[test]
+    fn redraws_are_capped() {
+        // redraws wanted for a second, spaced by each of `steps` in turn,
+        // returning how many (of how many wanted) happen
+        let start = Instant::now();
+        let redraws = |steps: &[Duration]| {
+            let (mut due, mut now) = (start, start);
+            let (mut redraws, mut wanted) = (0, 0);
+            for step in steps.iter().cycle() {
+                if now >= start + Duration::from_secs(1) {
+                    break;
+                }
+                wanted += 1;
+                if now >= due {
+                    redraws += 1;
+                    due = after_redraw(due, now);
+                }
+                now += *step;
+            }
+            (redraws, wanted)
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(redraws(&[ms(1)]), (60, 1000));
+        assert_eq!(redraws(&[FRAME_TIME - Duration::from_micros(100)]), (60, 61));
+        assert_eq!(redraws(&[ms(50)]), (20, 20));
+        // frames a little shorter than FRAME_TIME after longer ones still
+        // redraw every time
+        assert_eq!(redraws(&[FRAME_TIME + ms(2), FRAME_TIME - ms(2)]), (61, 61));
+
+        // a late redraw doesn't lead to a burst to catch up
+        let late = start + ms(500);
+        assert_eq!(after_redraw(start, late), late - FRAME_TIME);
+        assert_eq!(after_redraw(after_redraw(start, late), late), late);
+    }
+
+    #[test]
+    fn keys_and_actions_are_passed_on() {
+        // wait for a key into V1, play a sound for that many frames and halt
+        let mut chip = load(&[0xF1, 0x0A, 0xF1, 0x18, 0x12, 0x04]);
+        let mut renderer = Frames::default();
+        let mut input = Script(vec![
+            Poll::default(),
+            Poll { keys: 1 << 3, actions: vec![Action::Screenshot] },
+            Poll::default(),
+            Poll::default(),
+            Poll::default(),
+        ]);
+        let mut sound = Sound::default();
+
+        let mut actions = Vec::new();
+        run(&mut chip, &mut CONFIG.clone(), &mut renderer, &mut input, &mut sound, |_, polled, _| {
+            actions.extend_from_slice(polled);
+            polled.first().map(|action| format!("{:?}", action))
+        })
+        .unwrap();
+
+        assert_eq!(chip.sound_timer(), 0);
+        assert_eq!(sound.0, [SoundEvent::Started, SoundEvent::Stopped]);
+        assert_eq!(actions, [Action::Screenshot]);
+        assert_eq!(renderer.messages, ["Screenshot"]);
+    }
+
+    #[test]
+    fn execution_errors_stop_the_loop() {
+        let mut chip = load(&[0x00, 0xEE]);
+        let result = run(
+            &mut chip,
+            &mut CONFIG.clone(),
+            &mut Frames::default(),
+            &mut Script(Vec::new()),
+            &mut Sound::default(),
+            |_, _, _| None,
+        );
+        assert!(result.is_ok(), "quit before the first frame runs");
+
+        let mut input = Script(vec![Poll::default()]);
+        let result = run(
+            &mut chip,
+            &mut CONFIG.clone(),
+            &mut Frames::default(),
+            &mut input,
+            &mut Sound::default(),
+            |_, _, _| None,
+        );
+        let underflow = Chip8Error::StackUnderflow { pc: 0x200 };
+        assert!(matches!(result, Err(RunError::Execution { frame: 0, error }) if error == underflow));
+    }
+
+    #[test]
+    fn pause_and_speed() {
+        // add one to V0 forever, five times a frame
+        let mut chip = load(&[0x70, 0x01, 0x12, 0x00]);
+        let poll = |actions: &[Action]| Poll { keys: 0, actions: actions.to_vec() };
+        let mut input = Script(vec![
+            poll(&[]),
+            poll(&[Action::Pause]),
+            poll(&[Action::Pause]),
+            poll(&[Action::SpeedUp]),
+            poll(&[Action::SpeedDown, Action::SpeedDown]),
+            poll(&[]),
+        ]);
+
+        let mut actions = Vec::new();
+        let mut frames = Vec::new();
+        let mut config = CONFIG.clone();
+        run(&mut chip, &mut config, &mut Frames::default(), &mut input, &mut Sound::default(), |_, polled, run| {
+            actions.extend_from_slice(polled);
+            frames.push(run);
+            None
+        })
+        .unwrap();
+        // one frame, a paused frame, one frame, two frames, then one frame
+        // over two half speed ticks
+        assert_eq!(chip.registers()[0], 25);
+        assert_eq!(frames, [0..1, 1..1, 1..2, 2..4, 4..4, 4..5]);
+        assert!(actions.is_empty(), "player actions are not passed on");
+        assert_eq!(config.speed, NORMAL_SPEED - 1, "the speed is left where it was set");
+    }
+
+    #[test]
+    fn hook_before_every_frame() {
+        // add V1 to V0 then clear V1, once a frame, while V1 is held at 7
+        let mut chip = load(&[0x80, 0x14, 0x61, 0x00, 0x12, 0x00]);
+        let mut input = Script(vec![Poll::default(); 2]);
+        let mut config = RunConfig {
+            cycles_per_frame: 3,
+            speed: SPEEDS.len() - 1,
+            ..CONFIG
+        };
+
+        let hold = |chip: &mut Chip8| chip.set_register(1, 7);
+        let mut sound = Sound::default();
+        run_with(&mut chip, &mut config, &mut Frames::default(), &mut input, &mut sound, hold, |_, _, _| None).unwrap();
+        // two ticks at 16 times speed
+        assert_eq!(chip.registers()[0], 32 * 7);
+    }
+
+    #[test]
+    fn clicked_keys_are_held() {
+        let mut chip = load(&[0x12, 0x00]);
+        let poll = |actions: &[Action]| Poll { keys: 0, actions: actions.to_vec() };
+        let mut input = Script(vec![
+            poll(&[Action::MouseDown { column: 7, row: 1 }]),
+            poll(&[]),
+            poll(&[Action::MouseUp]),
+            poll(&[Action::MouseDown { column: 3, row: 1 }, Action::MouseUp]),
+            poll(&[Action::MouseDown { column: 20, row: 1 }]),
+        ]);
+
+        let mut keys = Vec::new();
+        run(&mut chip, &mut CONFIG.clone(), &mut Frames::default(), &mut input, &mut Sound::default(), |chip, _, _| {
+            keys.push(chip.keys());
+            None
+        })
+        .unwrap();
+        assert_eq!(keys, [1 << 7, 1 << 7, 0, 1 << 3, 0]);
+    }
+
+    #[test]
+    fn status_and_hud() {
+        // load 0x12 into V1 and 0x345 into I, then halt
+        let mut chip = load(&[0x61, 0x12, 0xA3, 0x45, 0x12, 0x04]);
+        let mut renderer = Frames::default();
+        let mut input = Script(vec![
+            Poll { keys: 1 << 0xA, actions: Vec::new() },
+            Poll { keys: 0, actions: vec![Action::ToggleStatus, Action::Pause] },
+            Poll { keys: 0, actions: vec![Action::ToggleHud] },
+        ]);
+        let mut config = RunConfig { title: "test.ch8".to_string(), hud: true, ..CONFIG };
+
+        run(&mut chip, &mut config, &mut renderer, &mut input, &mut Sound::default(), |_, _, _| None).unwrap();
+        assert_eq!(
+            renderer.panels[0],
+            [
+                "PC 204  I 345  DT 00  ST 00",
+                "V0 00  V1 12  V2 00  V3 00  V4 00  V5 00  V6 00  V7 00",
+                "V8 00  V9 00  VA 00  VB 00  VC 00  VD 00  VE 00  VF 00",
+                "keys A",
+            ]
+        );
+        assert_eq!(renderer.panels[1][0], "test.ch8  0 fps  0 ips  1x  paused");
+        assert_eq!(renderer.panels[1][4], "keys -");
+        assert_eq!(renderer.panels[2], ["test.ch8  0 fps  0 ips  1x  paused"]);
+    }
+}
@@ -0,0 +1,551 @@
+// Drawing the display as an image, for terminals that support sixel or the
+// kitty graphics protocol.
+//
+// Each pixel is scaled up by a whole number so the display fills as much of
+// the terminal as it can while keeping square, sharp edged pixels.
+//
+// See: https://vt100.net/docs/vt3xx-gp/chapter14.html
+// See: https://sw.kovidgoyal.net/kitty/graphics-protocol/
+
+use crate::chip8::{Chip8, Frame, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::frontend::{Renderer, TerminalSize};
+use crate::screenshot::{self, ImageOptions, Palette};
+use crate::terminal::{Keypad, ENTER_MOUSE, EXIT_MOUSE};
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Sixel,
+    Kitty,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Sixel => "sixel",
+            Protocol::Kitty => "kitty",
+        }
+    }
+}
+
+// how long to wait for the terminal to answer queries
+const QUERY_TIMEOUT: Duration = Duration::from_millis(250);
+
+// a kitty graphics query for a 1x1 image, which kitty answers with OK
+const KITTY_QUERY: &str = "\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\";
+// primary device attributes, which every terminal answers
+const DEVICE_ATTRIBUTES: &str = "\x1b[c";
+
+// the protocols a terminal supports, from its answers to the queries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Support {
+    pub sixel: bool,
+    pub kitty: bool,
+}
+
+impl Support {
+    pub fn parse(response: &[u8]) -> Support {
+        let response = String::from_utf8_lossy(response);
+        let kitty = response.contains("\x1b_Gi=31;OK");
+
+        // device attributes are `ESC [ ? 62 ; 4 ; ... c`, where 4 is sixel
+        let sixel = response
+            .find("\x1b[?")
+            .and_then(|start| {
+                let attributes = &response[start + 3..];
+                attributes.find('c').map(|end| &attributes[..end])
+            })
+            .is_some_and(|attributes| attributes.split(';').skip(1).any(|attribute| attribute == "4"));
+
+        Support { sixel, kitty }
+    }
+
+    // the protocol to use, preferring `wanted` or otherwise kitty
+    pub fn choose(self, wanted: Option<Protocol>) -> Option<Protocol> {
+        match wanted {
+            Some(Protocol::Sixel) if self.sixel => Some(Protocol::Sixel),
+            Some(Protocol::Kitty) if self.kitty => Some(Protocol::Kitty),
+            Some(_) => None,
+            None if self.kitty => Some(Protocol::Kitty),
+            None if self.sixel => Some(Protocol::Sixel),
+            None => None,
+        }
+    }
+}
+
+// ask the terminal which protocols it supports, also returning anything
+// typed while waiting for the answers
+//
+// `input` should not block, and the terminal should be in raw mode so the
+// answers are not echoed.
+pub fn detect<W: Write, R: Read>(out: &mut W, input: &mut R) -> io::Result<(Support, Vec<u8>)> {
+    write!(out, "{}{}", KITTY_QUERY, DEVICE_ATTRIBUTES)?;
+    out.flush()?;
+
+    let start = Instant::now();
+    let mut response = Vec::new();
+    let mut buffer = [0; 256];
+    while start.elapsed() < QUERY_TIMEOUT {
+        let read = input.read(&mut buffer)?;
+        response.extend_from_slice(&buffer[..read]);
+        // the device attributes are answered last
+        if let Some(start) = response.windows(3).position(|window| window == b"\x1b[?") {
+            if response[start..].contains(&b'c') {
+                break;
+            }
+        }
+        if read == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    Ok((Support::parse(&response), typed(&response)))
+}
+
+// the input read while detecting, without the terminal's answers
+fn typed(response: &[u8]) -> Vec<u8> {
+    let mut typed = Vec::new();
+    let mut rest = response;
+    while let Some(&byte) = rest.first() {
+        let answer = if rest.starts_with(b"\x1b_G") {
+            rest.windows(2).position(|window| window == b"\x1b\\").map(|end| end + 2)
+        } else if rest.starts_with(b"\x1b[?") {
+            rest.iter().position(|&byte| byte == b'c').map(|end| end + 1)
+        } else {
+            None
+        };
+        match answer {
+            Some(length) => rest = &rest[length..],
+            None => {
+                typed.push(byte);
+                rest = &rest[1..];
+            }
+        }
+    }
+    typed
+}
+
+// the display as a sixel image
+pub fn encode_sixel(frame: &Frame, scale: usize, palette: &Palette) -> Vec<u8> {
+    let scale = scale.max(1);
+    let width = frame.width() * scale;
+    let height = frame.height() * scale;
+
+    let mut out = Vec::new();
+    // pixel aspect ratio 1:1, with the image size given up front
+    out.extend_from_slice(format!("\x1bP0;1;0q\"1;1;{};{}", width, height).as_bytes());
+    for (register, colour) in [palette.background, palette.foreground].iter().enumerate() {
+        let [r, g, b] = colour.map(|channel| channel as u32 * 100 / 255);
+        out.extend_from_slice(format!("#{};2;{};{};{}", register, r, g, b).as_bytes());
+    }
+
+    // each band of six rows is drawn once per colour, with runs of the same
+    // column pattern compressed
+    for band in (0..height).step_by(6) {
+        for register in 0..2 {
+            out.extend_from_slice(format!("#{}", register).as_bytes());
+            let columns = (0..width).map(|x| {
+                (0..6)
+                    .filter(|row| band + row < height)
+                    .filter(|row| frame.pixel(x / scale, (band + row) / scale) == (register == 1))
+                    .fold(0u8, |bits, row| bits | 1 << row)
+            });
+
+            let mut run: Option<(u8, usize)> = None;
+            for bits in columns.chain(std::iter::once(u8::MAX)) {
+                match run {
+                    Some((previous, length)) if previous == bits => run = Some((bits, length + 1)),
+                    _ => {
+                        if let Some((previous, length)) = run {
+                            let sixel = b'?' + previous;
+                            if length > 3 {
+                                out.extend_from_slice(format!("!{}", length).as_bytes());
+                                out.push(sixel);
+                            } else {
+                                out.extend(std::iter::repeat_n(sixel, length));
+                            }
+                        }
+                        run = Some((bits, 1));
+                    }
+                }
+            }
+            // back to the start of the band for the next colour
+            out.push(b'$');
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+    out
+}
+
+// the display as a kitty graphics image, sent as a PNG
+//
+// The image always has the same id and placement, so each frame replaces the
+// last in place.
+pub fn encode_kitty(frame: &Frame, scale: usize, palette: &Palette) -> io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    let options = ImageOptions {
+        scale: scale.max(1),
+        palette: *palette,
+    };
+    screenshot::write_png(&mut png, frame, &options)?;
+    let data = base64(&png);
+
+    // the data is sent in chunks of at most 4096 bytes
+    let chunks: Vec<&[u8]> = data.chunks(4096).collect();
+    let mut out = Vec::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = (index + 1 < chunks.len()) as u8;
+        if index == 0 {
+            out.extend_from_slice(format!("\x1b_Ga=T,f=100,i=1,p=1,q=2,C=1,m={};", more).as_bytes());
+        } else {
+            out.extend_from_slice(format!("\x1b_Gm={};", more).as_bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+    Ok(out)
+}
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+    out
+}
+
+// the size of each cell in pixels, as (width, height)
+fn cell_size(size: &TerminalSize) -> Option<(usize, usize)> {
+    if size.columns == 0 || size.rows == 0 || size.width == 0 || size.height == 0 {
+        return None;
+    }
+    Some((
+        (size.width / size.columns).max(1) as usize,
+        (size.height / size.rows).max(1) as usize,
+    ))
+}
+
+// the largest scale that fits the display in the terminal, leaving `columns`
+// to the right and `rows` below it for text, or `None` if even a scale of 1
+// does not fit
+fn fit(size: &TerminalSize, columns: usize, rows: usize) -> Option<usize> {
+    let (cell_width, cell_height) = cell_size(size)?;
+    let available_width = (size.width as usize).saturating_sub(cell_width * columns);
+    let available_height = (size.height as usize).saturating_sub(cell_height * rows);
+    let scale = (available_width / DISPLAY_WIDTH).min(available_height / DISPLAY_HEIGHT);
+    (scale > 0).then_some(scale)
+}
+
+// Draws the display as an image, centred in the terminal.
+//
+// The image is only sent again when the display changes.
+pub struct GraphicsRenderer<W: Write> {
+    out: W,
+    protocol: Protocol,
+    palette: Palette,
+    size: Option<TerminalSize>,
+    scale: usize,
+    too_small: bool,
+    origin: (u16, u16), // top left cell of the image
+    image_rows: u16,    // rows of cells covered by the image
+    drawn: Option<Frame>,
+    message: String,
+    message_changed: bool,
+    panel: Vec<String>, // lines below the message
+    panel_changed: bool,
+    keypad: Option<Keypad>, // drawn to the right of the image when set
+}
+
+impl<W: Write> GraphicsRenderer<W> {
+    // the image is drawn at a scale of 1 in the top left until the terminal
+    // size is known
+    pub fn new(out: W, protocol: Protocol, palette: Palette) -> GraphicsRenderer<W> {
+        GraphicsRenderer {
+            out,
+            protocol,
+            palette,
+            size: None,
+            scale: 1,
+            too_small: false,
+            origin: (1, 1),
+            image_rows: 0,
+            drawn: None,
+            message: String::new(),
+            message_changed: false,
+            panel: Vec::new(),
+            panel_changed: false,
+            keypad: None,
+        }
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    fn layout(&mut self) {
+        self.scale = 1;
+        self.too_small = false;
+        self.origin = (1, 1);
+        self.image_rows = 0;
+
+        let Some(size) = self.size else { return };
+        let Some((cell_width, cell_height)) = cell_size(&size) else { return };
+        // a row for the message and one either side of it, then the panel
+        let text_rows = 2 + self.panel.len() as u16;
+        let text_columns = if self.keypad.is_some() { Keypad::COLUMNS } else { 0 };
+        let Some(scale) = fit(&size, text_columns as usize, text_rows as usize) else {
+            self.too_small = true;
+            return;
+        };
+        let columns = (DISPLAY_WIDTH * scale).div_ceil(cell_width) as u16;
+        let rows = (DISPLAY_HEIGHT * scale).div_ceil(cell_height) as u16;
+        self.scale = scale;
+        self.image_rows = rows;
+        self.origin = (
+            size.columns.saturating_sub(columns + text_columns) / 2 + 1,
+            size.rows.saturating_sub(rows + text_rows) / 2 + 1,
+        );
+        if let Some(keypad) = &mut self.keypad {
+            keypad.place((self.origin.0 + columns, self.origin.1));
+        }
+    }
+
+    // the terminal row below the image that the message is shown on
+    fn message_row(&self) -> u16 {
+        self.origin.1 + self.image_rows + 1
+    }
+}
+
+impl<W: Write> Renderer for GraphicsRenderer<W> {
+    fn render(&mut self, chip: &Chip8) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let frame = chip.frame();
+        if self.drawn.is_none() {
+            write!(buffer, "{}{}", termion::cursor::Hide, termion::clear::All)?;
+            self.message_changed = true;
+            self.panel_changed = true;
+            if let Some(keypad) = &mut self.keypad {
+                buffer.extend_from_slice(ENTER_MOUSE.as_bytes());
+                keypad.invalidate();
+            }
+        }
+        if self.too_small {
+            if self.drawn.is_none() {
+                write!(buffer, "{}Terminal too small", termion::cursor::Goto(1, 1))?;
+                self.drawn = Some(frame);
+            }
+            self.message_changed = false;
+            self.panel_changed = false;
+        } else if self.drawn.as_ref() != Some(&frame) {
+            write!(buffer, "{}", termion::cursor::Goto(self.origin.0, self.origin.1))?;
+            match self.protocol {
+                Protocol::Sixel => buffer.extend_from_slice(&encode_sixel(&frame, self.scale, &self.palette)),
+                Protocol::Kitty => buffer.extend_from_slice(&encode_kitty(&frame, self.scale, &self.palette)?),
+            }
+            self.drawn = Some(frame);
+        }
+        if let (Some(keypad), false) = (&mut self.keypad, self.too_small) {
+            keypad.draw(&mut buffer, chip.keys())?;
+        }
+        if self.message_changed {
+            write!(
+                buffer,
+                "{}{}{}",
+                termion::cursor::Goto(self.origin.0, self.message_row()),
+                termion::clear::CurrentLine,
+                self.message
+            )?;
+            self.message_changed = false;
+        }
+        if self.panel_changed {
+            let width = self.size.map_or(usize::MAX, |size| (size.columns + 1 - self.origin.0) as usize);
+            for (row, line) in (self.message_row() + 1..).zip(&self.panel) {
+                let line: String = line.chars().take(width).collect();
+                write!(
+                    buffer,
+                    "{}{}{}",
+                    termion::cursor::Goto(self.origin.0, row),
+                    termion::clear::CurrentLine,
+                    line
+                )?;
+            }
+            self.panel_changed = false;
+        }
+
+        if !buffer.is_empty() {
+            self.out.write_all(&buffer)?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.message = message.to_string();
+        self.message_changed = true;
+        Ok(())
+    }
+
+    fn resize(&mut self, size: TerminalSize) {
+        self.size = Some(size);
+        self.layout();
+        self.drawn = None;
+    }
+
+    fn panel(&mut self, lines: &[String]) {
+        if lines.len() != self.panel.len() {
+            self.panel = lines.to_vec();
+            self.layout();
+            self.drawn = None;
+        } else {
+            self.panel = lines.to_vec();
+            self.panel_changed = true;
+        }
+    }
+
+    fn show_keypad(&mut self, show: bool) {
+        if show != self.keypad.is_some() {
+            if !show {
+                let _ = self.out.write_all(EXIT_MOUSE.as_bytes());
+            }
+            self.keypad = show.then(Keypad::new);
+            self.layout();
+            self.drawn = None;
+        }
+    }
+
+    fn key_at(&self, column: u16, row: u16) -> Option<u8> {
+        match &self.keypad {
+            Some(keypad) if !self.too_small => keypad.key_at(column, row),
+            _ => None,
+        }
+    }
+}
+
+impl<W: Write> Drop for GraphicsRenderer<W> {
+    fn drop(&mut self) {
+        if self.protocol == Protocol::Kitty {
+            // remove the image, it would otherwise stay on screen
+            let _ = write!(self.out, "\x1b_Ga=d,d=i,i=1,q=2\x1b\\");
+        }
+        let row = self.message_row() + self.panel.len() as u16 + 1;
+        let _ = write!(self.out, "{}{}", termion::cursor::Goto(1, row), termion::cursor::Show);
+        if self.keypad.is_some() {
+            let _ = self.out.write_all(EXIT_MOUSE.as_bytes());
+        }
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_support() {
+        let kitty = b"\x1b_Gi=31;OK\x1b\\\x1b[?62;22c";
+        assert_eq!(Support::parse(kitty), Support { sixel: false, kitty: true });
+        let xterm = b"\x1b[?63;1;2;4;6;9;15;22c";
+        assert_eq!(Support::parse(xterm), Support { sixel: true, kitty: false });
+        // 4 is only sixel after the terminal class
+        assert_eq!(Support::parse(b"\x1b[?4;6c"), Support::default());
+        assert_eq!(Support::parse(b""), Support::default());
+    }
+
+    #[test]
+    fn choose_protocol() {
+        let both = Support { sixel: true, kitty: true };
+        assert_eq!(both.choose(None), Some(Protocol::Kitty));
+        assert_eq!(both.choose(Some(Protocol::Sixel)), Some(Protocol::Sixel));
+        let sixel = Support { sixel: true, kitty: false };
+        assert_eq!(sixel.choose(None), Some(Protocol::Sixel));
+        assert_eq!(sixel.choose(Some(Protocol::Kitty)), None);
+        assert_eq!(Support::default().choose(None), None);
+    }
+
+    #[test]
+    fn detect_reads_the_answers() {
+        let mut out = Vec::new();
+        let mut input: &[u8] = b"\x1b[?62;4c";
+        let (support, typed) = detect(&mut out, &mut input).unwrap();
+        assert!(support.sixel);
+        assert!(typed.is_empty());
+        assert_eq!(out, format!("{}{}", KITTY_QUERY, DEVICE_ATTRIBUTES).as_bytes());
+
+        // keys pressed before the answers come are kept
+        let mut input: &[u8] = b"q\x1b_Gi=31;OK\x1b\\1\x1b[A\x1b[?62;4c";
+        let (support, typed) = detect(&mut Vec::new(), &mut input).unwrap();
+        assert_eq!(support, Support { sixel: true, kitty: true });
+        assert_eq!(typed, b"q1\x1b[A");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), b"");
+        assert_eq!(base64(b"f"), b"Zg==");
+        assert_eq!(base64(b"fo"), b"Zm8=");
+        assert_eq!(base64(b"foo"), b"Zm9v");
+        assert_eq!(base64(b"foob"), b"Zm9vYg==");
+    }
+
+    #[test]
+    fn sixel_image() {
+        let mut chip = Chip8::with_seed(0);
+        chip.write(0xF00, 0b1000_0000);
+        let sixel = String::from_utf8(encode_sixel(&chip.frame(), 1, &Palette::default())).unwrap();
+
+        assert!(sixel.starts_with("\x1bP0;1;0q\"1;1;64;32#0;2;0;0;0#1;2;100;100;100"));
+        assert!(sixel.ends_with("-\x1b\\"));
+        // 32 rows make 6 bands, the last of them two rows high
+        assert_eq!(sixel.matches('-').count(), 6);
+        // the first band, the lit pixel is the top of the first column
+        assert!(sixel.contains("#0}!63~$#1@!63?$-"));
+        assert!(sixel.contains("#0!64B$#1!64?$-\x1b\\"));
+    }
+
+    #[test]
+    fn kitty_image_chunks() {
+        let chip = Chip8::with_seed(0);
+        let kitty = encode_kitty(&chip.frame(), 40, &Palette::default()).unwrap();
+        let kitty = String::from_utf8(kitty).unwrap();
+        assert!(kitty.starts_with("\x1b_Ga=T,f=100,i=1,p=1,q=2,C=1,m=1;iVBOR"));
+        assert!(kitty.ends_with("\x1b\\"));
+        assert!(kitty.contains("\x1b_Gm=0;"));
+    }
+
+    #[test]
+    fn scale_to_fit() {
+        // 80x24 cells of 10x20 pixels
+        let size = TerminalSize { columns: 80, rows: 24, width: 800, height: 480 };
+        assert_eq!(fit(&size, 0, 2), Some(12));
+        assert_eq!(fit(&TerminalSize { width: 1600, ..size }, 0, 2), Some(13));
+        assert_eq!(fit(&TerminalSize { width: 0, height: 0, ..size }, 0, 2), None);
+        assert_eq!(fit(&TerminalSize { width: 60, ..size }, 0, 2), None);
+        assert_eq!(fit(&TerminalSize { rows: 1, height: 20, ..size }, 0, 2), None);
+    }
+
+    #[test]
+    fn image_is_centred() {
+        let chip = Chip8::with_seed(0);
+        let mut renderer = GraphicsRenderer::new(Vec::new(), Protocol::Sixel, Palette::default());
+        renderer.resize(TerminalSize { columns: 100, rows: 30, width: 1000, height: 600 });
+        renderer.render(&chip).unwrap();
+
+        // a scale of 15 is 960x480 pixels, 96 columns and 24 rows
+        assert_eq!(renderer.scale(), 15);
+        let drawn = String::from_utf8_lossy(&renderer.out).to_string();
+        assert!(drawn.contains(&format!("{}\x1bP", termion::cursor::Goto(3, 3))));
+        assert_eq!(renderer.message_row(), 28);
+    }
+}
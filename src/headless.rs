@@ -0,0 +1,233 @@
+// Runs a program without a terminal, for automated testing.
+//
+// The machine is run for a fixed number of 60Hz frames (or until it halts),
+// with keypad input taken from a script rather than the keyboard.
+
+use crate::chip8::{Chip8, Chip8Error, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+use std::error;
+use std::fmt;
+
+// keypad input as a list of (frame, keys held from that frame onwards)
+//
+// Written as comma separated `FRAME:KEYS` entries, where KEYS is the hex
+// digits of the keys that are held (empty or `-` to release every key),
+// e.g. `30:5,32:-,90:4a` presses 5 on frame 30, releases it on frame 32 and
+// holds 4 and A from frame 90 onwards.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    entries: Vec<(u32, u16)>, // sorted by frame, keys as a bitmask
+}
+
+impl KeyScript {
+    pub fn parse(script: &str) -> Result<KeyScript, String> {
+        let mut entries = Vec::new();
+        for entry in script.split(|c: char| c == ',' || c.is_whitespace()) {
+            if entry.is_empty() {
+                continue;
+            }
+            let (frame, keys) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("missing ':' in key script entry '{}'", entry))?;
+            let frame: u32 = frame
+                .parse()
+                .map_err(|_| format!("invalid frame number '{}'", frame))?;
+
+            let keys = if keys == "-" { "" } else { keys };
+            let mut mask = 0;
+            for key in keys.chars() {
+                let key = key
+                    .to_digit(16)
+                    .ok_or_else(|| format!("invalid key '{}' in key script", key))?;
+                mask |= 1 << key;
+            }
+            entries.push((frame, mask));
+        }
+        entries.sort_by_key(|&(frame, _)| frame);
+
+        Ok(KeyScript { entries })
+    }
+
+    // hold `keys` from `frame` onwards, for frames after the last entry
+    pub fn push(&mut self, frame: u32, keys: u16) {
+        if self.entries.last().map_or(keys != 0, |&(_, held)| held != keys) {
+            self.entries.push((frame, keys));
+        }
+    }
+
+    // bitmask of the keys held during `frame`
+    pub fn keys_at(&self, frame: u32) -> u16 {
+        self.entries
+            .iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or(0, |&(_, mask)| mask)
+    }
+}
+
+// written as `KeyScript::parse` reads it
+impl fmt::Display for KeyScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(frame, mask)) in self.entries.iter().enumerate() {
+            let keys: String = (0..16).filter(|key| mask & (1 << key) != 0).map(|key| format!("{:x}", key)).collect();
+            write!(f, "{}{}:{}", if i == 0 { "" } else { "," }, frame, keys)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HeadlessConfig {
+    pub frames: u32,           // maximum number of frames to run
+    pub cycles_per_frame: u32, // instructions executed each frame
+    pub until_halt: bool,      // stop early once the program halts
+    pub keys: KeyScript,
+}
+
+impl Default for HeadlessConfig {
+    fn default() -> HeadlessConfig {
+        HeadlessConfig {
+            frames: 600,
+            cycles_per_frame: 10,
+            until_halt: false,
+            keys: KeyScript::default(),
+        }
+    }
+}
+
+// how a headless run ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // ran for every frame requested
+    Completed { frames: u32 },
+    // the program jumped to itself after `frames` frames
+    Halted { frames: u32 },
+}
+
+// an error raised by the machine during a headless run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeadlessError {
+    pub frame: u32,
+    pub error: Chip8Error,
+}
+
+impl fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame {}: {}", self.frame, self.error)
+    }
+}
+
+impl error::Error for HeadlessError {}
+
+pub fn run(chip: &mut Chip8, config: &HeadlessConfig) -> Result<Outcome, HeadlessError> {
+    run_with(chip, config, |_| {})
+}
+
+// run, calling `on_frame` with the machine at the end of every frame
+pub fn run_with<F>(chip: &mut Chip8, config: &HeadlessConfig, mut on_frame: F) -> Result<Outcome, HeadlessError>
+where
+    F: FnMut(&Chip8),
+{
+    for frame in 0..config.frames {
+        if config.until_halt && chip.halted() {
+            return Ok(Outcome::Halted { frames: frame });
+        }
+
+        let keys = config.keys.keys_at(frame);
+        for key in 0..16 {
+            chip.write_keypad(key, keys & (1 << key) != 0);
+        }
+
+        for _ in 0..config.cycles_per_frame {
+            chip.cycle().map_err(|error| HeadlessError { frame, error })?;
+        }
+        chip.tick_timers();
+        on_frame(chip);
+    }
+
+    if config.until_halt && chip.halted() {
+        return Ok(Outcome::Halted { frames: config.frames });
+    }
+    Ok(Outcome::Completed { frames: config.frames })
+}
+
+// the display as text, one line per row with no carriage returns
+pub fn display_to_text(chip: &Chip8) -> String {
+    let mut string = String::with_capacity((DISPLAY_WIDTH * 3 + 1) * DISPLAY_HEIGHT);
+    for y in 0..DISPLAY_HEIGHT {
+        for x in 0..DISPLAY_WIDTH {
+            string.push(if chip.pixel(x, y) { '⬜' } else { '⬛' });
+        }
+        string.push('\n');
+    }
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::{load, ZERO};
+
+    #[test]
+    fn parse_key_scripts() {
+        let script = KeyScript::parse("90:4a, 30:5 32:-,40:").unwrap();
+        assert_eq!(script.to_string(), "30:5,32:,40:,90:4a");
+        assert_eq!(script.keys_at(0), 0);
+        assert_eq!(script.keys_at(30), 1 << 5);
+        assert_eq!(script.keys_at(31), 1 << 5);
+        assert_eq!(script.keys_at(32), 0);
+        assert_eq!(script.keys_at(1000), (1 << 4) | (1 << 0xA));
+        assert_eq!(KeyScript::parse("").unwrap(), KeyScript::default());
+    }
+
+    #[test]
+    fn invalid_key_scripts() {
+        assert!(KeyScript::parse("30").is_err());
+        assert!(KeyScript::parse("x:5").is_err());
+        assert!(KeyScript::parse("-1:5").is_err());
+        assert!(KeyScript::parse("30:g").is_err());
+        assert!(KeyScript::parse("30:1-2").is_err(), "- only stands for no keys");
+        assert!(KeyScript::parse("30:--").is_err());
+    }
+
+    #[test]
+    fn runs_until_halt_or_frame_limit() {
+        let mut config = HeadlessConfig {
+            frames: 5,
+            ..HeadlessConfig::default()
+        };
+        let mut frames = 0;
+        let outcome = run_with(&mut load(&ZERO), &config, |_| frames += 1);
+        assert_eq!(outcome, Ok(Outcome::Completed { frames: 5 }));
+        assert_eq!(frames, 5);
+
+        config.until_halt = true;
+        let mut chip = load(&ZERO);
+        assert_eq!(run(&mut chip, &config), Ok(Outcome::Halted { frames: 1 }));
+        let top: String = display_to_text(&chip).lines().next().unwrap().chars().take(5).collect();
+        assert_eq!(top, "⬜⬜⬜⬜⬛");
+
+        // a program that never halts runs every frame
+        let mut chip = load(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!(run(&mut chip, &config), Ok(Outcome::Completed { frames: 5 }));
+    }
+
+    #[test]
+    fn errors_report_their_frame() {
+        // returns with an empty stack on the third frame
+        let config = HeadlessConfig {
+            cycles_per_frame: 1,
+            ..HeadlessConfig::default()
+        };
+        let error = run(&mut load(&[0x60, 0x01, 0x61, 0x02, 0x00, 0xEE]), &config).unwrap_err();
+        assert_eq!(error.frame, 2);
+        assert_eq!(error.error, Chip8Error::StackUnderflow { pc: 0x204 });
+    }
+
+    #[test]
+    fn text_display() {
+        let text = display_to_text(&Chip8::new());
+        assert_eq!(text.lines().count(), DISPLAY_HEIGHT);
+        assert!(text.lines().all(|line| line == "⬛".repeat(DISPLAY_WIDTH)));
+    }
+}
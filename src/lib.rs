@@ -0,0 +1,18 @@
+pub mod analysis;
+pub mod audio;
+pub mod cheats;
+pub mod chip8;
+pub mod config;
+pub mod conformance;
+pub mod database;
+pub mod frontend;
+pub mod graphics;
+pub mod headless;
+pub mod movie;
+pub mod recording;
+pub mod rom;
+pub mod screenshot;
+pub mod terminal;
+pub mod theme;
+
+pub use crate::chip8::{Chip8, Chip8Error, CpuState, Frame, Quirks, SoundEvent};
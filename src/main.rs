@@ -1,116 +1,641 @@
-mod chip8;
-use chip8::Chip8;
+mod cli;
 
-use std::io::{self, Read, Write};
-use std::thread;
+use chip8::analysis;
+use chip8::audio::{AudioBackend, NullAudio, SquareWave, TerminalBell, VisualBell, WavAudio, DEFAULT_SAMPLE_RATE};
+use chip8::cheats::{Cheat, CheatEngine};
+use chip8::config::{self, Config, RomSection, Settings};
+use chip8::conformance;
+use chip8::database::{self, Database, GameInfo};
+use chip8::frontend::{self, Action, Renderer, RunConfig, RunError};
+use chip8::graphics::{self, GraphicsRenderer};
+use chip8::headless::{self, HeadlessConfig, Outcome};
+use chip8::movie::Movie;
+use chip8::recording::GifRecorder;
+use chip8::rom::{self, LoadOptions, Rom, PROGRAM_START};
+use chip8::screenshot::{self, Palette};
+use chip8::terminal::{self, TerminalGuard, TerminalInput, TerminalRenderer};
+use chip8::theme::{ColourDepth, Colours};
+use chip8::{Chip8, Quirks};
+use cli::{
+    Audio, Command, ConfigFile, ConfigOptions, ConformanceOptions, Graphics, HeadlessOptions, InfoOptions, PlayOptions,
+    RomOptions,
+};
+
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time;
 use std::fs;
 
-use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
-fn write_to_file(filename: &str, bytes: &[u8]) {
-    let mut f = fs::File::create(&filename)
-        .expect("Unable to create .ch8 file");
-    f.write_all(bytes)
-        .expect("Unable to write to file");
+// exit statuses
+const EXIT_EXECUTION_ERROR: i32 = 1;
+const EXIT_USAGE_ERROR: i32 = 2;
+const EXIT_CHECK_FAILED: i32 = 3;
+
+fn audio_backend(options: &PlayOptions) -> Box<dyn AudioBackend> {
+    match &options.audio {
+        Audio::Bell => Box::new(TerminalBell::new(io::stdout())),
+        Audio::Visual => Box::new(VisualBell::new(io::stdout())),
+        Audio::None => Box::new(NullAudio),
+        Audio::Wav(path) => Box::new(WavAudio::new(
+            path,
+            SquareWave::new(options.pitch, options.volume, DEFAULT_SAMPLE_RATE),
+        )),
+    }
+}
+
+// a file in the working directory named after the rom and the current time
+fn output_path(options: &PlayOptions, extension: &str) -> String {
+    let stem = Path::new(&options.rom.path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("chip8");
+    let millis = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    format!("{}-{}.{}", stem, millis, extension)
 }
 
-fn load_file(filename: &str) -> Vec<u8> {
-    let mut f = fs::File::open(&filename)
-        .expect("Unable to open .ch8 file");
-    let metadata = fs::metadata(&filename)
-        .expect("Unable to read .ch8 file metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
+fn take_screenshot(chip: &Chip8, options: &PlayOptions) -> io::Result<String> {
+    let path = output_path(options, options.screenshot_format.extension());
+    screenshot::save(&path, &chip.frame(), &options.image)?;
+    Ok(path)
+}
 
-    f.read_exact(&mut buffer)
-        .expect("buffer overflow");
+// a recording in progress and the file it is being written to
+type Recording = (GifRecorder<BufWriter<fs::File>>, String);
 
-    buffer
+// start recording, or finish the current recording
+fn toggle_recording(recorder: &mut Option<Recording>, options: &PlayOptions) -> String {
+    match recorder.take() {
+        Some((recorder, path)) => match recorder.finish() {
+            Ok(_) => format!("Saved {}", path),
+            Err(e) => format!("Unable to save recording: {}", e),
+        },
+        None => {
+            let path = output_path(options, "gif");
+            match GifRecorder::create(&path, &options.image) {
+                Ok(new_recorder) => {
+                    *recorder = Some((new_recorder, path));
+                    "Recording... (Ctrl+r to stop)".to_string()
+                }
+                Err(e) => format!("Unable to start recording: {}", e),
+            }
+        }
+    }
 }
-fn load_program(chip: &mut Chip8, filename: &str) {
-    let buffer = load_file(filename);
-    
-    let mut addr = 0x0200;
 
-    for &instruction in &buffer[0..buffer.len()-(buffer.len()%2)] {
-        chip.write(addr, instruction);
-        addr += 1;
+// the configuration file to use and what is in it, if any
+fn read_config(file: &ConfigFile) -> Result<Option<(PathBuf, Config)>, String> {
+    let path = match file {
+        ConfigFile::Default => match Config::path() {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+        ConfigFile::Path(path) => PathBuf::from(path),
+        ConfigFile::Off => return Ok(None),
+    };
+    let config = Config::load(&path).map_err(|e| format!("Unable to read '{}': {}", path.display(), e))?;
+    Ok(Some((path, config)))
+}
+
+// where the rom is loaded and starts
+fn load_options(options: &RomOptions, rom: &Rom) -> LoadOptions {
+    let address = options.load_address.or(rom.load_address).unwrap_or(PROGRAM_START);
+    LoadOptions {
+        address,
+        entry: options.entry.unwrap_or(address),
     }
 }
 
-fn run_interpreter(filename: &str) {
-    // setup input
-    let mut stdout = io::stdout().into_raw_mode().unwrap();
-    let mut stdin = termion::async_stdin().keys();
+// read the rom and look it up
+//
+// Settings given on the command line win over those tuned for the rom in the
+// configuration file, then those that came with the rom, those in the
+// database and the defaults in the configuration file.
+fn setup(options: &RomOptions, config: Option<&Config>) -> Result<(Rom, Option<GameInfo>), String> {
+    let path = &options.path;
+    let mut rom = rom::read_rom(path).map_err(|e| format!("Unable to load '{}': {}", path, e))?;
 
-    // setup chip8
-    let mut chip = Chip8::new();
-    load_program(&mut chip, filename);
+    let database = match &options.database {
+        cli::Database::Bundled => Some(Database::bundled()),
+        cli::Database::Dir(dir) => Some(
+            Database::open(Path::new(dir)).map_err(|e| format!("Unable to read the database in '{}': {}", dir, e))?,
+        ),
+        cli::Database::Off => None,
+    };
+    let game = database.and_then(|database| database.lookup(&rom.program));
+    if let Some(config) = config {
+        let tuned = config.rom(&database::sha1_hex(&rom.program));
+        rom.quirks = tuned.quirks.or(rom.quirks);
+        rom.cycles_per_frame = tuned.cycles_per_frame.or(rom.cycles_per_frame);
+        rom.theme = tuned.theme.or(rom.theme);
+    }
+    if let Some(game) = &game {
+        game.apply(&mut rom);
+    }
+    if let Some(config) = config {
+        rom.quirks = rom.quirks.or(config.defaults.quirks);
+        rom.cycles_per_frame = rom.cycles_per_frame.or(config.defaults.cycles_per_frame);
+        rom.theme = rom.theme.or(config.defaults.theme);
+    }
+    Ok((rom, game))
+}
+
+// a new machine with the rom loaded
+fn machine(options: &RomOptions, rom: &Rom) -> Result<Chip8, String> {
+    let mut chip = match options.seed {
+        Some(seed) => Chip8::with_seed(seed),
+        None => Chip8::new(),
+    };
+    chip.set_quirks(options.quirks.or(rom.quirks).unwrap_or_default());
+    rom::load(&mut chip, &rom.program, &load_options(options, rom))
+        .map_err(|e| format!("Unable to load '{}': {}", options.path, e))?;
+    Ok(chip)
+}
+
+// the settings the player runs a rom with
+fn session_settings(options: &PlayOptions, rom: &Rom, config: Option<&Config>) -> Settings {
+    let configured = config.map_or_else(Settings::default, |config| {
+        config.rom(&database::sha1_hex(&rom.program)).or(&config.defaults)
+    });
+    Settings {
+        cycles_per_frame: Some(options.rom.cycles_per_frame.or(rom.cycles_per_frame).unwrap_or(cli::DEFAULT_CYCLES)),
+        quirks: Some(options.rom.quirks.or(rom.quirks).unwrap_or_default()),
+        theme: options.theme.or(rom.theme),
+        renderer: Some(options.render_mode.or(configured.renderer).unwrap_or_default()),
+        speed: configured.speed,
+        keys: configured.keys,
+    }
+}
+
+// change the configuration file's section for a rom, leaving the rest of the
+// file as it is
+fn update_section<F>(path: &Path, options: &PlayOptions, rom: &Rom, update: F) -> Result<(), String>
+where
+    F: FnOnce(&mut RomSection),
+{
+    let name = Path::new(&options.rom.path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|_| options.rom.path != "-");
+    config::update_section(path, &database::sha1_hex(&rom.program), |section| {
+        section.name = name;
+        update(section);
+    })
+    .map_err(|e| format!("Unable to save to '{}': {}", path.display(), e))
+}
 
-    // initial display
-    write!(&mut stdout, "{}", chip.display_to_string()).unwrap();
-    stdout.flush().unwrap();
+fn run_interpreter(
+    chip: &mut Chip8,
+    options: &PlayOptions,
+    settings: &mut Settings,
+    game: Option<&GameInfo>,
+    mut movie: Option<&mut Movie>,
+    cheats: &mut CheatEngine,
+    save_cheats: &dyn Fn(&[Cheat]) -> Result<String, String>,
+) -> Result<(), RunError> {
+    let theme = settings.theme;
 
-    // main loop
-    loop {
-        chip.cycle();
+    let _guard = TerminalGuard::new();
+    let mut out = io::stdout().into_raw_mode()?;
+    let mut stdin = termion::async_stdin();
 
-        if chip.display_updated() {
-            write!(&mut stdout, "{}", termion::clear::All).unwrap();
-            write!(&mut stdout, "{}{}", termion::cursor::Goto(1, 1), chip.display_to_string()).unwrap();
-            stdout.flush().unwrap();
+    // keys pressed while asking the terminal are played after
+    let (protocol, typed) = match options.graphics {
+        Graphics::Off => (None, Vec::new()),
+        Graphics::Detect(wanted) => {
+            let (support, typed) = graphics::detect(&mut out, &mut stdin)?;
+            (support.choose(wanted), typed)
         }
+    };
+    // what the game is and its keys, when it is in the database
+    let mut message = game.map(GameInfo::summary);
+    let mut renderer: Box<dyn Renderer> = match protocol {
+        Some(protocol) => {
+            let palette = theme.map_or_else(Palette::default, |theme| Palette {
+                background: theme.background(),
+                foreground: theme.foreground(),
+            });
+            Box::new(GraphicsRenderer::new(out, protocol, palette))
+        }
+        None => {
+            let mut renderer = TerminalRenderer::new(out, settings.renderer.unwrap_or_default());
+            // an explicit colour depth overrides NO_COLOR
+            if let Some(theme) = theme {
+                let depth = options.colour_depth.or_else(ColourDepth::detect);
+                renderer.set_colours(depth.map(|depth| Colours { theme, depth }));
+            }
+            if options.graphics != Graphics::Off {
+                message = Some("Terminal graphics are not supported, drawing with text".to_string());
+            }
+            Box::new(renderer)
+        }
+    };
+    if let Some(message) = message {
+        renderer.message(&message)?;
+    }
+    renderer.resize(terminal::terminal_size()?);
+    let mut input = TerminalInput::from_reader(typed, stdin)?;
+    input.set_keymap(settings.keys.unwrap_or_default());
+    let mut audio = audio_backend(options);
+    let mut recorder: Option<Recording> = None;
+
+    let title = match game {
+        Some(game) => game.title.clone(),
+        None => Path::new(&options.rom.path)
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+    };
+    let mut config = RunConfig {
+        title,
+        cycles_per_frame: settings.cycles_per_frame.unwrap_or(cli::DEFAULT_CYCLES),
+        speed: settings.speed.unwrap_or(frontend::NORMAL_SPEED),
+        status: options.status,
+        hud: options.hud,
+        keypad: options.keypad,
+        ..RunConfig::default()
+    };
+    // cheats are held every frame, however fast the program runs, and are off
+    // while recording a movie
+    let recording_movie = movie.is_some();
+    let cheats = RefCell::new(cheats);
+    let apply_cheats = |chip: &mut Chip8| {
+        if !recording_movie {
+            cheats.borrow().apply(chip);
+        }
+    };
+    let on_frame = |chip: &mut Chip8, actions: &[Action], frames| {
+        if let Some(movie) = &mut movie {
+            movie.record(frames, chip.keys());
+        }
+        let mut cheats = cheats.borrow_mut();
+        let mut message = None;
+        for action in actions {
+            message = match action {
+                Action::Screenshot => Some(match take_screenshot(chip, options) {
+                    Ok(path) => format!("Saved {}", path),
+                    Err(e) => format!("Unable to save screenshot: {}", e),
+                }),
+                Action::ToggleRecording => Some(toggle_recording(&mut recorder, options)),
+                // cheats would make the movie impossible to replay
+                Action::Command(_) if movie.is_some() => Some("Cheats are off while recording a movie".to_string()),
+                Action::Command(command) if command.trim() == "save" => {
+                    Some(save_cheats(&cheats.cheats).unwrap_or_else(|e| e))
+                }
+                Action::Command(command) => Some(cheats.command(chip, command).unwrap_or_else(|e| e)),
+                // the rest are handled by the player
+                _ => None,
+            };
+        }
+
+        if let Some((gif, _)) = &mut recorder {
+            if let Err(e) = gif.capture(&chip.frame()) {
+                recorder = None;
+                message = Some(format!("Recording failed: {}", e));
+            }
+        }
+        message
+    };
+    let result = frontend::run_with(chip, &mut config, &mut *renderer, &mut input, &mut *audio, apply_cheats, on_frame);
 
-        // delay so program can be visualised
-        thread::sleep(time::Duration::from_millis(2));
+    // finish any recording in progress
+    if let Some((gif, _)) = recorder {
+        let _ = gif.finish();
+    }
 
-        // keyboard input
-        (0..16).for_each(|x| chip.write_keypad(x, false));
-        let input = stdin.next();
-        if let Some(Ok(key)) = input {
-            match key {
-                // Exit if Ctrl+c is pressed
-                termion::event::Key::Ctrl('c') => break,
+    // keep the renderer and speed the player switched to, for --save-config
+    settings.renderer = renderer.render_mode().or(settings.renderer);
+    if config.speed != frontend::NORMAL_SPEED || settings.speed.is_some() {
+        settings.speed = Some(config.speed);
+    }
 
-                // Set keypad
-                termion::event::Key::Char('1') => chip.write_keypad(0x0, true),
-                termion::event::Key::Char('2') => chip.write_keypad(0x1, true),
-                termion::event::Key::Char('3') => chip.write_keypad(0x2, true),
-                termion::event::Key::Char('4') => chip.write_keypad(0x3, true),
+    result
+}
 
-                termion::event::Key::Char('q') => chip.write_keypad(0x4, true),
-                termion::event::Key::Char('w') => chip.write_keypad(0x5, true),
-                termion::event::Key::Char('e') => chip.write_keypad(0x6, true),
-                termion::event::Key::Char('r') => chip.write_keypad(0x7, true),
+fn run_headless(chip: &mut Chip8, options: &HeadlessOptions, rom: &Rom, movie: Option<&Movie>) -> i32 {
+    let config = match movie {
+        Some(movie) => movie.headless_config(),
+        None => HeadlessConfig {
+            cycles_per_frame: options.rom.cycles_per_frame.or(rom.cycles_per_frame).unwrap_or(cli::DEFAULT_CYCLES),
+            ..options.config.clone()
+        },
+    };
+    let mut recorder = match &options.record {
+        Some(path) => match GifRecorder::create(path, &options.image) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                eprintln!("Unable to create recording '{}': {}", path, e);
+                return EXIT_USAGE_ERROR;
+            }
+        },
+        None => None,
+    };
+    let mut record_error = None;
 
-                termion::event::Key::Char('a') => chip.write_keypad(0x8, true),
-                termion::event::Key::Char('s') => chip.write_keypad(0x9, true),
-                termion::event::Key::Char('d') => chip.write_keypad(0xa, true),
-                termion::event::Key::Char('f') => chip.write_keypad(0xb, true),
+    let result = headless::run_with(chip, &config, |chip| {
+        if let Some(gif) = &mut recorder {
+            if let Err(e) = gif.capture(&chip.frame()) {
+                record_error.get_or_insert(e);
+            }
+        }
+    });
+    if let Some(gif) = recorder {
+        if let Err(e) = record_error.map_or_else(|| gif.finish().map(|_| ()), Err) {
+            eprintln!("Unable to save recording: {}", e);
+            return EXIT_USAGE_ERROR;
+        }
+    }
 
-                termion::event::Key::Char('z') => chip.write_keypad(0xc, true),
-                termion::event::Key::Char('x') => chip.write_keypad(0xd, true),
-                termion::event::Key::Char('c') => chip.write_keypad(0xe, true),
-                termion::event::Key::Char('v') => chip.write_keypad(0xf, true),
+    let display = headless::display_to_text(chip);
+    let written = match &options.output {
+        Some(path) => fs::write(path, display),
+        None => io::stdout().write_all(display.as_bytes()),
+    };
+    if let Err(e) = written {
+        eprintln!("Unable to write display: {}", e);
+        return EXIT_USAGE_ERROR;
+    }
+    if let Some(path) = &options.screenshot {
+        if let Err(e) = screenshot::save(path, &chip.frame(), &options.image) {
+            eprintln!("Unable to save screenshot '{}': {}", path, e);
+            return EXIT_USAGE_ERROR;
+        }
+    }
 
-                _ => {}
-            };
+    match result {
+        Ok(Outcome::Completed { .. }) if config.until_halt => {
+            eprintln!("Program did not halt within {} frames", config.frames);
+            EXIT_CHECK_FAILED
+        }
+        Ok(_) => match movie.map_or(Ok(()), |movie| movie.check(chip)) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Replay did not end as recorded: {}", e);
+                EXIT_CHECK_FAILED
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            // a movie of a session that failed ends where it failed
+            match movie.map_or(Ok(()), |movie| movie.check(chip)) {
+                Ok(()) => EXIT_EXECUTION_ERROR,
+                Err(e) => {
+                    eprintln!("Replay did not end as recorded: {}", e);
+                    EXIT_CHECK_FAILED
+                }
+            }
+        }
+    }
+}
+
+// a list of addresses, shortened when there are many
+fn addresses(addresses: &[u16]) -> String {
+    const SHOWN: usize = 8;
+    let mut list: Vec<String> = addresses.iter().take(SHOWN).map(|addr| format!("{:03X}", addr)).collect();
+    if addresses.len() > SHOWN {
+        list.push(format!("and {} more", addresses.len() - SHOWN));
+    }
+    list.join(", ")
+}
+
+fn run_info(options: &InfoOptions, rom: &Rom, game: Option<&GameInfo>) {
+    let load = load_options(&options.rom, rom);
+    let quirks = options.rom.quirks.or(rom.quirks).unwrap_or_default();
+    let preset = match quirks.name() {
+        Some(name) => name,
+        None if quirks == Quirks::default() => "default",
+        None => "custom",
+    };
+
+    println!("rom           {}", options.rom.path);
+    println!("size          {} bytes", rom.program.len());
+    println!("sha-1         {}", database::sha1_hex(&rom.program));
+    match game {
+        Some(game) => {
+            println!("database      {}", game.summary());
+            if let Some(platform) = &game.platform {
+                println!("written for   {}", platform);
+            }
+        }
+        None => println!("database      not found"),
+    }
+    println!("load address  {:03X}, starting at {:03X}", load.address, load.entry);
+    println!(
+        "settings      {} quirks, {} cycles per frame",
+        preset,
+        options.rom.cycles_per_frame.or(rom.cycles_per_frame).unwrap_or(cli::DEFAULT_CYCLES)
+    );
+
+    let analysis = analysis::analyse(&rom.program, &load);
+    println!("needs         {}", analysis.platform().name());
+    println!("instructions  {} found by following the program from its start", analysis.reachable);
+    let counts: Vec<String> =
+        analysis.instructions.iter().map(|(pattern, count)| format!("{} {}", pattern, count)).collect();
+    for line in counts.chunks(8) {
+        println!("              {}", line.join("  "));
+    }
+
+    let flags = [
+        ("SUPER-CHIP instructions", &analysis.super_chip),
+        ("XO-CHIP instructions", &analysis.xo_chip),
+        ("unknown instructions", &analysis.invalid),
+        ("machine code calls (0NNN)", &analysis.machine_code),
+        ("shifts of VY into VX, which depend on the shifting quirk", &analysis.shifts),
+        ("loads and stores followed by a use of I, which depend on the memory quirk", &analysis.load_store),
+        ("jumps with an offset (BNNN), which depend on the jumping quirk", &analysis.jumps),
+        ("writes into its own code", &analysis.self_modifying),
+    ];
+    for (description, found) in flags.iter().filter(|(_, found)| !found.is_empty()) {
+        println!("{}:\n    {}", description, addresses(found));
+    }
+}
+
+// a movie, and a machine set up as it was when the movie was recorded
+fn start_replay(path: &str, rom: &Rom) -> Result<(Chip8, Movie), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Unable to read '{}': {}", path, e))?;
+    let movie = Movie::parse(&text).map_err(|e| format!("Unable to read movie '{}': {}", path, e))?;
+    let chip = movie.start(&rom.program).map_err(|e| format!("Unable to replay '{}': {}", path, e))?;
+    Ok((chip, movie))
+}
+
+// print where the configuration file is and what is in it
+fn show_config(file: &ConfigFile) -> i32 {
+    match read_config(file) {
+        Ok(Some((path, config))) => {
+            println!("# {}", path.display());
+            print!("{}", config.to_toml());
+            0
+        }
+        Ok(None) => {
+            println!("# no configuration file");
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_USAGE_ERROR
+        }
+    }
+}
+
+fn run_conformance(options: &ConformanceOptions) -> i32 {
+    match conformance::run_suite(Path::new(&options.dir), options.cycles_per_frame, options.bless) {
+        Ok(report) => {
+            println!("{}", report.table());
+            if report.passed() {
+                0
+            } else {
+                EXIT_CHECK_FAILED
+            }
+        }
+        Err(e) => {
+            eprintln!("Unable to run test roms in '{}': {}", options.dir, e);
+            EXIT_USAGE_ERROR
         }
     }
 }
 
 fn main() {
-    /*
-        let program = vec![
-            0xF0, 0x0A, // Wait for keypad input and store in V0
-            0x00, 0xE0, // Clear display
-            0xF0, 0x29, // sets I to the font character for V0
-            0xD0, 0x04, // Draw four lines of the sprite stored in V0
-            0xF0, 0x29, // sets I to the font character for V0
-            0x12, 0x00, // Jump to location 200 in memory
-        ];
-    */
-
-    run_interpreter("examples/snake.ch8");
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(EXIT_USAGE_ERROR);
+        }
+    };
+
+    // only the player reads the configuration file
+    let (rom_options, config_file) = match &command {
+        Command::Play(options) => (&options.rom, Some(&options.config)),
+        Command::Headless(options) => (&options.rom, None),
+        Command::Info(options) => (&options.rom, None),
+        Command::Config(ConfigOptions { config, play: Some(options) }) => (&options.rom, Some(config)),
+        Command::Config(ConfigOptions { config, play: None }) => process::exit(show_config(config)),
+        Command::Conformance(options) => process::exit(run_conformance(options)),
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+    };
+
+    // a movie needs to know the seed
+    let mut rom_options = rom_options.clone();
+    if let Command::Play(PlayOptions { record_movie: Some(_), .. }) = &command {
+        rom_options.seed.get_or_insert_with(rand::random);
+    }
+    let rom_options = &rom_options;
+
+    let config = match config_file.map_or(Ok(None), read_config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(EXIT_USAGE_ERROR);
+        }
+    };
+    let (rom, game) = match setup(rom_options, config.as_ref().map(|(_, config)| config)) {
+        Ok(setup) => setup,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(EXIT_USAGE_ERROR);
+        }
+    };
+    // only the commands that run the rom load it, so info works on any size
+    let new_machine = || {
+        machine(rom_options, &rom).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(EXIT_USAGE_ERROR);
+        })
+    };
+
+    match &command {
+        Command::Play(options) => {
+            let mut chip = new_machine();
+            let mut settings = session_settings(options, &rom, config.as_ref().map(|(_, config)| config));
+            let mut movie = options.record_movie.as_ref().map(|_| {
+                let seed = rom_options.seed.expect("movies are recorded with a seed");
+                let cycles = settings.cycles_per_frame.unwrap_or(cli::DEFAULT_CYCLES);
+                Movie::new(&rom.program, seed, chip.quirks(), cycles, load_options(rom_options, &rom))
+            });
+            let config_path = config.as_ref().map(|(path, _)| path);
+            let hash = database::sha1_hex(&rom.program);
+            let saved = config.as_ref().and_then(|(_, config)| config.roms.get(&hash));
+            let mut cheats = CheatEngine::new(saved.map_or_else(Vec::new, |section| section.cheats.clone()));
+            let save_cheats = |cheats: &[Cheat]| match config_path {
+                Some(path) => update_section(path, options, &rom, |section| section.cheats = cheats.to_vec())
+                    .map(|()| format!("Saved {} cheats to {}", cheats.len(), path.display())),
+                None => Err("Unable to save cheats: there is no configuration file".to_string()),
+            };
+            let result = run_interpreter(
+                &mut chip,
+                options,
+                &mut settings,
+                game.as_ref(),
+                movie.as_mut(),
+                &mut cheats,
+                &save_cheats,
+            );
+            if let (Some(movie), Some(path)) = (&mut movie, &options.record_movie) {
+                // the keys held during the tick that failed, up to the frame it
+                // failed on
+                if let Err(RunError::Execution { frame, .. }) = result {
+                    movie.record(movie.frames..frame + 1, chip.keys());
+                }
+                movie.finish(&chip);
+                match fs::write(path, movie.to_toml()) {
+                    Ok(()) => eprintln!("Saved {}", path),
+                    Err(e) => eprintln!("Unable to save movie '{}': {}", path, e),
+                }
+            }
+            if options.save_config {
+                match &config {
+                    Some((path, _)) => {
+                        match update_section(path, options, &rom, |section| section.settings = settings) {
+                            Ok(()) => eprintln!("Saved settings to {}", path.display()),
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                    None => eprintln!("Unable to save settings: there is no configuration file"),
+                }
+            }
+            match result {
+                Ok(()) => {}
+                Err(e @ RunError::Execution { .. }) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(EXIT_EXECUTION_ERROR);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(EXIT_USAGE_ERROR);
+                }
+            }
+        }
+        Command::Headless(options) => {
+            let (mut chip, movie) = match &options.movie {
+                Some(path) => match start_replay(path, &rom) {
+                    Ok((chip, movie)) => (chip, Some(movie)),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(EXIT_USAGE_ERROR);
+                    }
+                },
+                None => (new_machine(), None),
+            };
+            process::exit(run_headless(&mut chip, options, &rom, movie.as_ref()))
+        }
+        Command::Info(options) => run_info(options, &rom, game.as_ref()),
+        Command::Config(ConfigOptions { play: Some(options), .. }) => {
+            let settings = session_settings(options, &rom, config.as_ref().map(|(_, config)| config));
+            match &game {
+                Some(game) => println!("# {} ({})", game.title, options.rom.path),
+                None => println!("# {}", options.rom.path),
+            }
+            println!("# sha-1 {}", database::sha1_hex(&rom.program));
+            print!("{}", settings.to_toml());
+        }
+        Command::Config(_) | Command::Conformance(_) | Command::Help => {}
+    }
 }
@@ -0,0 +1,375 @@
+// Input movies: every change to the keys held while playing a rom, by frame,
+// with the seed and settings needed to replay the session exactly.
+//
+// Movies are TOML files:
+//
+//   rom = "b232ef880bd6060fb45fa6effed7edf0ae95670e"
+//   seed = "1234"
+//   quirks = "schip"
+//   cycles = 10
+//   load_address = 512
+//   entry = 512
+//   frames = 600
+//   keys = "30:1,42:,90:4"
+//   display = "..."
+//   state = "..."
+//
+// where `keys` is a headless key script, and `display` and `state` are hashes
+// of the display and of the whole machine (registers, timers, stack, keys and
+// memory) at the end, which replays are checked against.
+
+use crate::chip8::{Chip8, Chip8Error, Quirks};
+use crate::config::{quirks_from_value, quirks_to_value};
+use crate::database::sha1_hex;
+use crate::headless::{self, HeadlessConfig, KeyScript};
+use crate::rom::{self, LoadOptions, RomError};
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::ops::Range;
+
+use toml::{Table, Value};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom: String, // SHA-1 hash of the program
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub load: LoadOptions,
+    pub frames: u32, // how many frames were recorded
+    pub keys: KeyScript,
+    pub display: Option<String>, // SHA-1 hash of the display after the last frame
+    pub state: Option<String>,   // SHA-1 hash of the machine after the last frame
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    WrongRom { expected: String, actual: String },
+    Load(RomError),
+    Execution { frame: u32, error: Chip8Error },
+    // the display or machine at the end is not the one recorded
+    Desynced { what: &'static str, expected: String, actual: String },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::WrongRom { expected, actual } => {
+                write!(f, "the movie was recorded with rom {} but this rom is {}", expected, actual)
+            }
+            ReplayError::Load(e) => write!(f, "{}", e),
+            ReplayError::Execution { frame, error } => write!(f, "frame {}: {}", frame, error),
+            ReplayError::Desynced { what, expected, actual } => {
+                write!(f, "the {} at the end is {} but {} was recorded", what, actual, expected)
+            }
+        }
+    }
+}
+
+impl error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReplayError::Load(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the hash a movie records of the display
+pub fn display_hash(chip: &Chip8) -> String {
+    sha1_hex(chip.frame().as_bytes())
+}
+
+// the hash a movie records of the whole machine
+pub fn state_hash(chip: &Chip8) -> String {
+    let state = chip.state();
+    let mut bytes = Vec::with_capacity(4096 + 64);
+    bytes.extend_from_slice(&state.pc.to_be_bytes());
+    bytes.extend_from_slice(&state.i.to_be_bytes());
+    bytes.extend_from_slice(&state.v);
+    bytes.extend_from_slice(&[state.delay_timer, state.sound_timer]);
+    bytes.extend_from_slice(&state.keys.to_be_bytes());
+    bytes.push(state.stack.len() as u8);
+    bytes.extend(state.stack.iter().flat_map(|addr| addr.to_be_bytes()));
+    bytes.extend_from_slice(chip.memory());
+    sha1_hex(&bytes)
+}
+
+impl Movie {
+    // an empty recording of `program`, which the machine is about to run
+    // with these settings
+    pub fn new(program: &[u8], seed: u64, quirks: Quirks, cycles_per_frame: u32, load: LoadOptions) -> Movie {
+        Movie {
+            rom: sha1_hex(program),
+            seed,
+            quirks,
+            cycles_per_frame,
+            load,
+            frames: 0,
+            keys: KeyScript::default(),
+            display: None,
+            state: None,
+        }
+    }
+
+    // note that `keys` were held for `frames`, which follow those already
+    // recorded
+    pub fn record(&mut self, frames: Range<u32>, keys: u16) {
+        if frames.is_empty() {
+            return;
+        }
+        self.keys.push(frames.start, keys);
+        self.frames = frames.end;
+    }
+
+    // finish the recording with the display and machine it ended on
+    pub fn finish(&mut self, chip: &Chip8) {
+        self.display = Some(display_hash(chip));
+        self.state = Some(state_hash(chip));
+    }
+
+    // a machine set up as it was when recording started
+    pub fn start(&self, program: &[u8]) -> Result<Chip8, ReplayError> {
+        let actual = sha1_hex(program);
+        if actual != self.rom {
+            return Err(ReplayError::WrongRom {
+                expected: self.rom.clone(),
+                actual,
+            });
+        }
+        let mut chip = Chip8::with_seed(self.seed);
+        chip.set_quirks(self.quirks);
+        rom::load(&mut chip, program, &self.load).map_err(ReplayError::Load)?;
+        Ok(chip)
+    }
+
+    // how to run the recording headless
+    pub fn headless_config(&self) -> HeadlessConfig {
+        HeadlessConfig {
+            frames: self.frames,
+            cycles_per_frame: self.cycles_per_frame,
+            until_halt: false,
+            keys: self.keys.clone(),
+        }
+    }
+
+    // replay the recording from the start, checking it ends as it did
+    pub fn replay(&self, program: &[u8]) -> Result<Chip8, ReplayError> {
+        let mut chip = self.start(program)?;
+        headless::run(&mut chip, &self.headless_config())
+            .map_err(|e| ReplayError::Execution { frame: e.frame, error: e.error })?;
+        self.check(&chip)?;
+        Ok(chip)
+    }
+
+    // whether a replay ended on the display and machine that were recorded
+    pub fn check(&self, chip: &Chip8) -> Result<(), ReplayError> {
+        let checks = [
+            ("display", &self.display, display_hash(chip)),
+            ("machine state", &self.state, state_hash(chip)),
+        ];
+        for (what, expected, actual) in checks {
+            match expected {
+                Some(expected) if *expected != actual => {
+                    return Err(ReplayError::Desynced {
+                        what,
+                        expected: expected.clone(),
+                        actual,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let table: Table = text.parse().map_err(|e: toml::de::Error| e.to_string().trim_end().to_string())?;
+        let get = |key: &str| table.get(key).ok_or_else(|| format!("missing '{}'", key));
+        let string = |key: &str| get(key)?.as_str().ok_or_else(|| format!("'{}' should be a string", key));
+        let number = |key: &str| {
+            let value = get(key)?.as_integer().ok_or_else(|| format!("'{}' should be a number", key))?;
+            u32::try_from(value).map_err(|_| format!("invalid '{}' {}", key, value))
+        };
+        let address = |key: &str| {
+            let value = number(key)?;
+            u16::try_from(value).map_err(|_| format!("invalid '{}' {}", key, value))
+        };
+
+        let seed = string("seed")?;
+        let hash = |key: &str| match table.get(key) {
+            Some(_) => string(key).map(|hash| Some(hash.to_string())),
+            None => Ok(None),
+        };
+        Ok(Movie {
+            rom: string("rom")?.to_ascii_lowercase(),
+            seed: seed.parse().map_err(|_| format!("invalid seed '{}'", seed))?,
+            quirks: quirks_from_value(get("quirks")?)?,
+            cycles_per_frame: number("cycles")?,
+            load: LoadOptions {
+                address: address("load_address")?,
+                entry: address("entry")?,
+            },
+            frames: number("frames")?,
+            keys: KeyScript::parse(string("keys")?)?,
+            display: hash("display")?,
+            state: hash("state")?,
+        })
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = Table::new();
+        let mut insert = |key: &str, value: Value| table.insert(key.to_string(), value);
+        insert("rom", Value::String(self.rom.clone()));
+        // as a string, since TOML integers are signed
+        insert("seed", Value::String(self.seed.to_string()));
+        insert("quirks", quirks_to_value(self.quirks));
+        insert("cycles", Value::Integer(self.cycles_per_frame.into()));
+        insert("load_address", Value::Integer(self.load.address.into()));
+        insert("entry", Value::Integer(self.load.entry.into()));
+        insert("frames", Value::Integer(self.frames.into()));
+        insert("keys", Value::String(self.keys.to_string()));
+        if let Some(display) = &self.display {
+            insert("display", Value::String(display.clone()));
+        }
+        if let Some(state) = &self.state {
+            insert("state", Value::String(state.clone()));
+        }
+        table.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{self, Input, Poll, Renderer, RunConfig};
+    use crate::audio::NullAudio;
+
+    use std::io;
+
+    const PONG: &[u8] = include_bytes!("../examples/pong.ch8");
+
+    struct NoDisplay;
+
+    impl Renderer for NoDisplay {
+        fn render(&mut self, _chip: &Chip8) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // holds each key for a few ticks, at various speeds, then quits
+    struct Player(u32);
+
+    impl Input for Player {
+        fn poll(&mut self) -> io::Result<Poll> {
+            use frontend::Action;
+            self.0 += 1;
+            let actions = match self.0 {
+                40 => vec![Action::SpeedUp],
+                80 => vec![Action::Pause],
+                90 => vec![Action::Pause, Action::SpeedDown, Action::SpeedDown],
+                300 => vec![Action::Quit],
+                _ => Vec::new(),
+            };
+            Ok(Poll {
+                keys: if self.0 % 50 < 20 { 1 << (self.0 / 50 % 2 * 4 + 1) } else { 0 },
+                actions,
+            })
+        }
+    }
+
+    #[test]
+    fn recordings_replay_exactly() {
+        let quirks = Quirks::CHIP8;
+        let load = LoadOptions::default();
+        let mut chip = Chip8::with_seed(7);
+        chip.set_quirks(quirks);
+        rom::load(&mut chip, PONG, &load).unwrap();
+
+        let mut movie = Movie::new(PONG, 7, quirks, 12, load);
+        let mut config = RunConfig {
+            cycles_per_frame: 12,
+            throttle: false,
+            ..RunConfig::default()
+        };
+        frontend::run(&mut chip, &mut config, &mut NoDisplay, &mut Player(0), &mut NullAudio, |chip, _, frames| {
+            movie.record(frames, chip.keys());
+            None
+        })
+        .unwrap();
+        movie.finish(&chip);
+        // 39 ticks at normal speed, 40 at double speed, 10 paused and 210 at
+        // half speed
+        assert_eq!(movie.frames, 39 + 80 + 105);
+
+        let movie = Movie::parse(&movie.to_toml()).unwrap();
+        let replayed = movie.replay(PONG).unwrap();
+        assert_eq!(replayed.state(), chip.state());
+        assert!(replayed.frame().as_bytes() == chip.frame().as_bytes());
+    }
+
+    #[test]
+    fn failed_sessions_replay() {
+        // count in V0 until it reaches 100, then return with nothing to
+        // return to
+        let program = [0x70, 0x01, 0x30, 0x64, 0x12, 0x00, 0x00, 0xEE];
+        let mut chip = Chip8::with_seed(3);
+        rom::load(&mut chip, &program, &LoadOptions::default()).unwrap();
+        let mut movie = Movie::new(&program, 3, Quirks::default(), 10, LoadOptions::default());
+        let mut config = RunConfig {
+            throttle: false,
+            speed: frontend::SPEEDS.len() - 1,
+            ..RunConfig::default()
+        };
+
+        // as the player records it, with the frames of the tick that failed
+        let record = |chip: &mut Chip8, _: &[frontend::Action], frames| {
+            movie.record(frames, chip.keys());
+            None
+        };
+        let result = frontend::run(&mut chip, &mut config, &mut NoDisplay, &mut Player(0), &mut NullAudio, record);
+        match result {
+            Err(frontend::RunError::Execution { frame, .. }) => movie.record(movie.frames..frame + 1, chip.keys()),
+            _ => panic!("the program should fail"),
+        }
+        movie.finish(&chip);
+        // 10 cycles a frame, 3 for each count
+        assert_eq!(movie.frames, 30);
+
+        let mut replayed = movie.start(&program).unwrap();
+        let error = headless::run(&mut replayed, &movie.headless_config()).unwrap_err();
+        assert_eq!(error.frame, 29);
+        assert!(movie.check(&replayed).is_ok());
+    }
+
+    #[test]
+    fn mismatches() {
+        let mut movie = Movie::new(PONG, 1, Quirks::default(), 10, LoadOptions::default());
+        movie.record(0..30, 0);
+        movie.record(30..60, 0x0002);
+        assert_eq!(movie.keys.to_string(), "30:1");
+        assert!(matches!(movie.replay(&PONG[1..]), Err(ReplayError::WrongRom { .. })));
+
+        movie.display = Some(sha1_hex(b""));
+        assert!(matches!(movie.replay(PONG), Err(ReplayError::Desynced { what: "display", .. })));
+
+        // the same display, but not the same machine
+        let mut chip = movie.start(PONG).unwrap();
+        movie.finish(&chip);
+        assert!(movie.check(&chip).is_ok());
+        chip.set_register(3, 1);
+        assert!(matches!(movie.check(&chip), Err(ReplayError::Desynced { what: "machine state", .. })));
+    }
+
+    #[test]
+    fn invalid() {
+        let movie = Movie::new(PONG, u64::MAX, Quirks::default(), 10, LoadOptions::default()).to_toml();
+        assert_eq!(Movie::parse(&movie).unwrap().seed, u64::MAX);
+        assert!(Movie::parse(&movie.replace("frames = 0", "")).is_err());
+        assert!(Movie::parse(&movie.replace("cycles = 10", "cycles = -10")).is_err());
+        assert!(Movie::parse(&movie.replace("entry = 512", "entry = 70000")).is_err());
+        assert!(Movie::parse(&movie.replace("keys = \"\"", "keys = \"1\"")).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// Recording the display as an animated GIF.
+//
+// A frame is captured for every 60Hz frame the machine runs. Consecutive
+// identical frames are merged into one GIF frame with a longer delay, and
+// delays are worked out from the total elapsed time so the animation does
+// not drift from 60Hz.
+
+use crate::chip8::{Frame, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::screenshot::ImageOptions;
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// GIF delays are in hundredths of a second, and most viewers slow down frames
+// shorter than 2/100s, so changes faster than that are dropped
+const MIN_DELAY: u64 = 2;
+
+pub struct GifRecorder<W: Write> {
+    encoder: gif::Encoder<W>,
+    options: ImageOptions,
+    frames: u64, // number of frames captured so far
+    pending: Option<(Frame, u64)>, // frame waiting to be written, and when it was captured
+    last: Option<(Frame, u64)>, // the frame before it, held back in case it comes back
+}
+
+impl GifRecorder<BufWriter<fs::File>> {
+    pub fn create<P: AsRef<Path>>(path: P, options: &ImageOptions) -> io::Result<Self> {
+        GifRecorder::new(BufWriter::new(fs::File::create(path)?), options)
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(out: W, options: &ImageOptions) -> io::Result<GifRecorder<W>> {
+        let scale = options.scale();
+        let width = DISPLAY_WIDTH * scale;
+        let height = DISPLAY_HEIGHT * scale;
+        if width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scale is too large for a GIF",
+            ));
+        }
+
+        let palette = options.palette;
+        let global_palette = [palette.background, palette.foreground].concat();
+
+        let mut encoder = gif::Encoder::new(out, width as u16, height as u16, &global_palette)
+            .map_err(gif_error)?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+
+        Ok(GifRecorder {
+            encoder,
+            options: *options,
+            frames: 0,
+            pending: None,
+            last: None,
+        })
+    }
+
+    // number of 60Hz frames captured so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // add the display for the next 60Hz frame
+    pub fn capture(&mut self, frame: &Frame) -> io::Result<()> {
+        let now = self.frames;
+        self.frames += 1;
+
+        match self.pending.take() {
+            // unchanged, the pending frame is shown for longer
+            Some((pending, start)) if pending == *frame => {
+                self.pending = Some((pending, start));
+            }
+            // shown too briefly to be written, replace it but keep its start
+            // time, or carry on with the frame before it if that is back
+            Some((_, start)) if delay(start, now) < MIN_DELAY => match self.last.take() {
+                Some((last, last_start)) if last == *frame => self.pending = Some((last, last_start)),
+                last => {
+                    self.last = last;
+                    self.pending = Some((frame.clone(), start));
+                }
+            },
+            Some((pending, start)) => {
+                if let Some((last, last_start)) = self.last.take() {
+                    self.write(&last, delay(last_start, start))?;
+                }
+                self.last = Some((pending, start));
+                self.pending = Some((frame.clone(), now));
+            }
+            None => self.pending = Some((frame.clone(), now)),
+        }
+        Ok(())
+    }
+
+    // write the last frame and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some((pending, start)) = self.pending.take() {
+            if let Some((last, last_start)) = self.last.take() {
+                self.write(&last, delay(last_start, start))?;
+            }
+            let delay = delay(start, self.frames).max(MIN_DELAY);
+            self.write(&pending, delay)?;
+        }
+        let mut out = self.encoder.into_inner()?;
+        out.flush()?;
+        Ok(out)
+    }
+
+    fn write(&mut self, frame: &Frame, delay: u64) -> io::Result<()> {
+        let scale = self.options.scale();
+        let width = frame.width() * scale;
+        let height = frame.height() * scale;
+
+        // palette indices, 0 for background and 1 for foreground
+        let mut buffer = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                buffer.push(frame.pixel(x / scale, y / scale) as u8);
+            }
+        }
+
+        let gif_frame = gif::Frame {
+            width: width as u16,
+            height: height as u16,
+            delay: delay.min(u16::MAX as u64) as u16,
+            buffer: buffer.into(),
+            ..gif::Frame::default()
+        };
+        self.encoder.write_frame(&gif_frame).map_err(gif_error)
+    }
+}
+
+// hundredths of a second between two 60Hz frame numbers, rounded so the
+// error never accumulates
+fn delay(start: u64, end: u64) -> u64 {
+    let centiseconds = |frame: u64| (frame * 100 + 30) / 60;
+    centiseconds(end) - centiseconds(start)
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::corner;
+    use crate::chip8::Chip8;
+
+    fn blank() -> Frame {
+        Chip8::new().frame()
+    }
+
+    // record the frames, returning the GIF's frames as (delay, whether the
+    // top left pixel is set)
+    fn record(frames: &[Frame]) -> Vec<(u16, bool)> {
+        let options = ImageOptions {
+            scale: 1,
+            ..ImageOptions::default()
+        };
+        let mut recorder = GifRecorder::new(Vec::new(), &options).unwrap();
+        for frame in frames {
+            recorder.capture(frame).unwrap();
+        }
+        assert_eq!(recorder.frames(), frames.len() as u64);
+        let gif = recorder.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!((frame.width, frame.height), (64, 32));
+            decoded.push((frame.delay, frame.buffer[0] == 1));
+        }
+        decoded
+    }
+
+    #[test]
+    fn identical_frames_are_merged() {
+        assert_eq!(record(&vec![blank(); 60]), [(100, false)]);
+        let frames: Vec<Frame> = (0..90).map(|i| if i < 30 { blank() } else { corner() }).collect();
+        assert_eq!(record(&frames), [(50, false), (100, true)]);
+    }
+
+    #[test]
+    fn short_frames_are_dropped() {
+        // changes every 1/60s are faster than the 2/100s GIF frames can be
+        // shown, yet the delays still add up to the time recorded and the
+        // frames written still alternate
+        let frames: Vec<Frame> = (0..6).map(|i| if i % 2 == 0 { blank() } else { corner() }).collect();
+        assert_eq!(record(&frames), [(5, false), (5, true)]);
+
+        // a single frame is shown for the shortest delay
+        assert_eq!(record(&[corner()]), [(2, true)]);
+    }
+
+    #[test]
+    fn delays_do_not_drift() {
+        assert_eq!(delay(0, 60), 100);
+        assert_eq!((0..60).map(|frame| delay(frame, frame + 1)).sum::<u64>(), 100);
+        assert_eq!(delay(1, 2), 1);
+    }
+}
@@ -0,0 +1,336 @@
+// Loading programs into memory.
+//
+// Programs are loaded at 0x200 and start running there, apart from those
+// written for the ETI-660, which are loaded and start at 0x600. Every byte
+// is loaded, and a program has to fit between its load address and the
+// display, which is mapped into memory at 0xF00.
+//
+// Roms are usually raw binaries, but can also be hex dumps or Octo
+// cartridges, which can bring the quirks, speed and colours they were
+// written for.
+
+mod cartridge;
+mod octo;
+
+pub use octo::AssembleError;
+
+use crate::chip8::{Chip8, Quirks, DISPLAY_OFFSET};
+use crate::theme::Theme;
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const PROGRAM_START: u16 = 0x200;
+pub const ETI660_START: u16 = 0x600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadOptions {
+    pub address: u16, // where the first byte is loaded
+    pub entry: u16,   // where the program starts running
+}
+
+impl LoadOptions {
+    // load and start at `address`
+    pub fn at(address: u16) -> LoadOptions {
+        LoadOptions { address, entry: address }
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions::at(PROGRAM_START)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomFormat {
+    Binary,    // .ch8, .sc8 and .xo8
+    HexText,   // hex digits, e.g. `00E0 A22A` or `0x00, 0xE0`
+    Cartridge, // Octo's .gif cartridges
+}
+
+impl RomFormat {
+    pub fn from_path(path: &str) -> Option<RomFormat> {
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ch8" | "sc8" | "xo8" | "c8" => Some(RomFormat::Binary),
+            "hex" | "txt" => Some(RomFormat::HexText),
+            "gif" => Some(RomFormat::Cartridge),
+            _ => None,
+        }
+    }
+
+    // guess from the contents, for roms without a known extension
+    pub fn sniff(data: &[u8]) -> RomFormat {
+        if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            RomFormat::Cartridge
+        } else if data.is_ascii() && parse_hex(&String::from_utf8_lossy(data)).is_ok_and(|bytes| !bytes.is_empty()) {
+            RomFormat::HexText
+        } else {
+            RomFormat::Binary
+        }
+    }
+}
+
+// a program and the settings that came with it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rom {
+    pub program: Vec<u8>,
+    pub quirks: Option<Quirks>,
+    pub cycles_per_frame: Option<u32>,
+    pub theme: Option<Theme>,
+    pub load_address: Option<u16>,
+}
+
+impl Rom {
+    pub fn decode(data: &[u8], format: RomFormat) -> Result<Rom, RomError> {
+        match format {
+            RomFormat::Binary => Ok(Rom {
+                program: data.to_vec(),
+                ..Rom::default()
+            }),
+            RomFormat::HexText => Ok(Rom {
+                program: parse_hex(&String::from_utf8_lossy(data))?,
+                ..Rom::default()
+            }),
+            RomFormat::Cartridge => cartridge::decode(data),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    InvalidHex { line: usize, token: String },
+    InvalidCartridge(String),
+    OctoSource(AssembleError),
+    Empty,
+    TooLarge { size: usize, available: usize, address: u16 },
+    InvalidAddress { address: u16 },
+    InvalidEntry { entry: u16 },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::InvalidHex { line, token } => write!(f, "line {}: '{}' is not hex", line, token),
+            RomError::InvalidCartridge(reason) => write!(f, "invalid Octo cartridge: {}", reason),
+            RomError::OctoSource(e) => write!(f, "the cartridge's Octo program does not assemble, {}", e),
+            RomError::Empty => write!(f, "the rom is empty"),
+            RomError::TooLarge { size, available, address } => write!(
+                f,
+                "the rom is {} bytes but only {} bytes fit when loaded at {:#05x}",
+                size, available, address
+            ),
+            RomError::InvalidAddress { address } => write!(
+                f,
+                "load address {:#05x} is outside program memory ({:#05x} to {:#05x})",
+                address,
+                PROGRAM_START,
+                DISPLAY_OFFSET - 1
+            ),
+            RomError::InvalidEntry { entry } => write!(
+                f,
+                "entry point {:#05x} is outside program memory ({:#05x} to {:#05x})",
+                entry,
+                PROGRAM_START,
+                DISPLAY_OFFSET - 2
+            ),
+        }
+    }
+}
+
+impl error::Error for RomError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            RomError::OctoSource(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+// the bytes of a hex dump
+//
+// Bytes are written as pairs of hex digits, alone or run together, with
+// an optional `0x` or `$` prefix and separated by spaces or commas. Lines
+// can start with an address followed by a colon, and `#`, `;` and `//`
+// start comments.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, RomError> {
+    let mut bytes = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = ["#", ";", "//"]
+            .iter()
+            .fold(line, |line, comment| line.split(comment).next().unwrap());
+        let mut tokens = line.split(|c: char| c == ',' || c.is_whitespace()).filter(|token| !token.is_empty());
+        let mut first = tokens.next();
+        if first.is_some_and(|token| token.ends_with(':')) {
+            first = tokens.next();
+        }
+
+        for token in first.into_iter().chain(tokens) {
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+                .or_else(|| token.strip_prefix('$'))
+                .unwrap_or(token);
+            if digits.is_empty() || digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(RomError::InvalidHex {
+                    line: number + 1,
+                    token: token.to_string(),
+                });
+            }
+            for i in (0..digits.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(&digits[i..i + 2], 16).unwrap());
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+// read a file, or stdin when the path is `-`
+pub fn read(path: &str) -> Result<Vec<u8>, RomError> {
+    let program = if path == "-" {
+        let mut program = Vec::new();
+        io::stdin().lock().read_to_end(&mut program)?;
+        program
+    } else {
+        fs::read(path)?
+    };
+    Ok(program)
+}
+
+// copy a program into memory and point the program counter at its entry
+pub fn load(chip: &mut Chip8, program: &[u8], options: &LoadOptions) -> Result<(), RomError> {
+    let LoadOptions { address, entry } = *options;
+    if !(PROGRAM_START..DISPLAY_OFFSET).contains(&address) {
+        return Err(RomError::InvalidAddress { address });
+    }
+    // an instruction is two bytes, and has to be before the display
+    if !(PROGRAM_START..DISPLAY_OFFSET - 1).contains(&entry) {
+        return Err(RomError::InvalidEntry { entry });
+    }
+    if program.is_empty() {
+        return Err(RomError::Empty);
+    }
+    let available = (DISPLAY_OFFSET - address) as usize;
+    if program.len() > available {
+        return Err(RomError::TooLarge {
+            size: program.len(),
+            available,
+            address,
+        });
+    }
+
+    for (addr, &byte) in (address..).zip(program) {
+        chip.write(addr, byte);
+    }
+    chip.set_pc(entry);
+    Ok(())
+}
+
+// read a rom in any format from a file (or stdin), with the format taken
+// from the extension or guessed from the contents
+pub fn read_rom(path: &str) -> Result<Rom, RomError> {
+    let data = read(path)?;
+    let format = RomFormat::from_path(path).unwrap_or_else(|| RomFormat::sniff(&data));
+    Rom::decode(&data, format)
+}
+
+// read a rom and load its program, returning the rom for its settings
+pub fn load_file(chip: &mut Chip8, path: &str, options: &LoadOptions) -> Result<Rom, RomError> {
+    let rom = read_rom(path)?;
+    load(chip, &rom.program, options)?;
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_byte_is_loaded() {
+        let mut chip = Chip8::with_seed(0);
+        load(&mut chip, &[0x12, 0x34, 0x56], &LoadOptions::default()).unwrap();
+        assert_eq!([chip.read(0x200), chip.read(0x201), chip.read(0x202)], [0x12, 0x34, 0x56]);
+        assert_eq!(chip.pc(), 0x200);
+
+        // as large as fits below the display
+        let program = vec![0xAA; 0xF00 - 0x200];
+        load(&mut chip, &program, &LoadOptions::default()).unwrap();
+        assert_eq!(chip.read(0xEFF), 0xAA);
+        assert_eq!(chip.read(0xF00), 0);
+    }
+
+    #[test]
+    fn load_address_and_entry() {
+        let mut chip = Chip8::with_seed(0);
+        load(&mut chip, &[0x00, 0xE0], &LoadOptions::at(ETI660_START)).unwrap();
+        assert_eq!(chip.read(0x600), 0x00);
+        assert_eq!(chip.read(0x601), 0xE0);
+        assert_eq!(chip.pc(), 0x600);
+
+        let options = LoadOptions { address: 0x200, entry: 0x204 };
+        load(&mut chip, &[0; 6], &options).unwrap();
+        assert_eq!(chip.pc(), 0x204);
+    }
+
+    #[test]
+    fn invalid_roms() {
+        let mut chip = Chip8::with_seed(0);
+        let error = load(&mut chip, &[], &LoadOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "the rom is empty");
+
+        let error = load(&mut chip, &[0; 0x901], &LoadOptions::at(0x600)).unwrap_err();
+        assert_eq!(error.to_string(), "the rom is 2305 bytes but only 2304 bytes fit when loaded at 0x600");
+
+        let error = load(&mut chip, &[0; 2], &LoadOptions::at(0x100)).unwrap_err();
+        assert_eq!(error.to_string(), "load address 0x100 is outside program memory (0x200 to 0xeff)");
+        assert!(matches!(
+            load(&mut chip, &[0; 2], &LoadOptions { address: 0x200, entry: 0xEFF }),
+            Err(RomError::InvalidEntry { entry: 0xEFF })
+        ));
+
+        assert!(matches!(read("does/not/exist.ch8"), Err(RomError::Io(_))));
+        // nothing is loaded when the rom is invalid
+        assert_eq!(chip.read(0x200), 0);
+    }
+
+    #[test]
+    fn hex_dumps() {
+        let text = "# pong\n0200: 00E0 a22a\n0x60, 0x0C // comment\n$12 ; jump\n";
+        assert_eq!(parse_hex(text).unwrap(), [0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x12]);
+
+        let error = parse_hex("00E0\n0200: A2G0").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: 'A2G0' is not hex");
+        assert!(parse_hex("00E").is_err());
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(RomFormat::from_path("games/PONG.CH8"), Some(RomFormat::Binary));
+        assert_eq!(RomFormat::from_path("cart.gif"), Some(RomFormat::Cartridge));
+        assert_eq!(RomFormat::from_path("dump.txt"), Some(RomFormat::HexText));
+        assert_eq!(RomFormat::from_path("-"), None);
+
+        assert_eq!(RomFormat::sniff(b"GIF89a..."), RomFormat::Cartridge);
+        assert_eq!(RomFormat::sniff(b"00E0 1200\n"), RomFormat::HexText);
+        assert_eq!(RomFormat::sniff(&[0x00, 0xE0, 0x12, 0x00]), RomFormat::Binary);
+        // text that is not hex is a binary that happens to be printable
+        assert_eq!(RomFormat::sniff(b"hello"), RomFormat::Binary);
+
+        let rom = Rom::decode(b"12 00", RomFormat::HexText).unwrap();
+        assert_eq!(rom.program, [0x12, 0x00]);
+        assert_eq!(rom.quirks, None);
+    }
+}
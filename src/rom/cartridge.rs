@@ -0,0 +1,148 @@
+// Octo cartridges, GIF images carrying a program and its settings.
+//
+// The payload is hidden in the low two bits of each pixel's palette index,
+// four pixels to a byte with the most significant bits first, through every
+// frame in turn. It starts with its length as a 32-bit big endian number,
+// followed by JSON holding the program and Octo's options.
+//
+// Cartridges hold the program as Octo source code, which is assembled as
+// Octo would.
+//
+// See: https://github.com/JohnEarnest/Octo
+
+use super::octo;
+use super::{Rom, RomError};
+use crate::chip8::Quirks;
+use crate::screenshot::parse_rgb;
+use crate::theme::Theme;
+
+use serde_json::Value;
+
+pub fn decode(data: &[u8]) -> Result<Rom, RomError> {
+    let payload = payload(data)?;
+    let json: Value = serde_json::from_slice(&payload).map_err(|e| invalid(&e.to_string()))?;
+    let source = json["program"]
+        .as_str()
+        .ok_or_else(|| invalid("no program in the payload"))?;
+    let program = octo::assemble(source).map_err(RomError::OctoSource)?;
+
+    let options = &json["options"];
+    Ok(Rom {
+        program,
+        quirks: options.is_object().then(|| quirks(options)),
+        cycles_per_frame: options["tickrate"].as_u64().map(|rate| rate as u32),
+        theme: theme(options),
+        load_address: None,
+    })
+}
+
+fn invalid(reason: &str) -> RomError {
+    RomError::InvalidCartridge(reason.to_string())
+}
+
+fn payload(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(|e| invalid(&e.to_string()))?;
+
+    let mut bytes = Vec::new();
+    let mut bits = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| invalid(&e.to_string()))? {
+        bits.extend(frame.buffer.iter().map(|index| index & 0b11));
+    }
+    for chunk in bits.chunks_exact(4) {
+        bytes.push(chunk.iter().fold(0, |byte, bits| byte << 2 | bits));
+    }
+
+    if bytes.len() < 4 {
+        return Err(invalid("no payload"));
+    }
+    let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    bytes
+        .get(4..4 + length)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| invalid("the payload is cut short"))
+}
+
+// Octo's quirk options, where a missing option is off
+fn quirks(options: &Value) -> Quirks {
+    let option = |name: &str| options[name].as_bool().unwrap_or(false);
+    Quirks {
+        vf_reset: option("logicQuirks"),
+        memory: !option("loadStoreQuirks"),
+        display_wait: option("vBlankQuirks"),
+        clipping: option("clipQuirks"),
+        shifting: option("shiftQuirks"),
+        jumping: option("jumpQuirks"),
+    }
+}
+
+fn theme(options: &Value) -> Option<Theme> {
+    let colour = |name: &str| options[name].as_str().and_then(|colour| parse_rgb(colour).ok());
+    Some(Theme {
+        colours: [
+            colour("backgroundColor")?,
+            colour("fillColor")?,
+            colour("fillColor2")?,
+            colour("blendColor")?,
+        ],
+    })
+}
+
+// a cartridge with a blank label, for tests
+#[cfg(test)]
+pub fn encode(json: &str) -> Vec<u8> {
+    let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+    payload.extend_from_slice(json.as_bytes());
+    let mut pixels: Vec<u8> = payload
+        .iter()
+        .flat_map(|byte| [byte >> 6, byte >> 4 & 0b11, byte >> 2 & 0b11, byte & 0b11])
+        .collect();
+    let width = 32;
+    pixels.resize(pixels.len().div_ceil(width) * width, 0);
+
+    let mut out = Vec::new();
+    let palette = [0u8; 4 * 3];
+    let mut encoder = gif::Encoder::new(&mut out, width as u16, (pixels.len() / width) as u16, &palette).unwrap();
+    let frame = gif::Frame::from_indexed_pixels(width as u16, (pixels.len() / width) as u16, pixels, None);
+    encoder.write_frame(&frame).unwrap();
+    drop(encoder);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_and_options() {
+        let json = r##"{
+            "program": ": main\n0x00 0xE0 # clear\n18 0b00000000",
+            "options": {
+                "tickrate": 500,
+                "shiftQuirks": true,
+                "loadStoreQuirks": true,
+                "backgroundColor": "#996600",
+                "fillColor": "#FFCC00",
+                "fillColor2": "#FF6600",
+                "blendColor": "#662200"
+            }
+        }"##;
+        let rom = decode(&encode(json)).unwrap();
+        assert_eq!(rom.program, [0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(rom.cycles_per_frame, Some(500));
+        let quirks = rom.quirks.unwrap();
+        assert!(quirks.shifting && !quirks.memory && !quirks.jumping);
+        assert_eq!(rom.theme.unwrap().colours[0], [0x99, 0x66, 0x00]);
+        assert_eq!(rom.theme.unwrap().colours[3], [0x66, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn source_is_assembled() {
+        let json = r#"{"program": ": main\n  clear\n  loop again", "options": {}}"#;
+        assert_eq!(decode(&encode(json)).unwrap().program, [0x00, 0xE0, 0x12, 0x02]);
+        let json = r#"{"program": ": main\n  clear\n  loop", "options": {}}"#;
+        assert!(matches!(decode(&encode(json)), Err(RomError::OctoSource(_))));
+        assert!(matches!(decode(b"GIF89a"), Err(RomError::InvalidCartridge(_))));
+    }
+}
@@ -0,0 +1,1251 @@
+// An assembler for Octo, the language Octo cartridges hold their programs in.
+//
+// Programs assemble to the same bytes Octo gives them: a jump to `main` is
+// placed at 0x200 unless `main` is the first thing in the program,
+// comparisons other than `==` and `!=` go through VF (or the register
+// aliased as `compare-temp`), and `:calc` expressions are evaluated right to
+// left with no operator precedence. The debugger directives `:breakpoint`
+// and `:monitor` are skipped.
+//
+// See: https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::f64::consts;
+use std::fmt;
+use std::mem;
+
+const START: usize = 0x200;
+const END: usize = 0x10000; // XO-CHIP programs can fill 64K
+
+// expansions allowed before a macro is taken to be recursive
+const MAX_EXPANSIONS: usize = 100_000;
+
+// how deeply expressions can nest before the source is rejected, rather than
+// running out of stack
+const MAX_NESTING: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AssembleError {}
+
+type Result<T> = std::result::Result<T, AssembleError>;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    quoted: bool, // a string literal
+    line: usize,
+}
+
+// split source into words and string literals, dropping `#` comments
+fn tokenize(source: &str) -> Vec<Token> {
+    fn push(tokens: &mut Vec<Token>, text: &mut String, line: usize) {
+        if !text.is_empty() {
+            tokens.push(Token {
+                text: mem::take(text),
+                quoted: false,
+                line,
+            });
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                push(&mut tokens, &mut text, line);
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '"' => {
+                push(&mut tokens, &mut text, line);
+                let start = line;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some('0') => text.push('\0'),
+                            Some(c) => text.push(c),
+                            None => {}
+                        },
+                        c => {
+                            line += (c == '\n') as usize;
+                            text.push(c);
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    text: mem::take(&mut text),
+                    quoted: true,
+                    line: start,
+                });
+            }
+            c if c.is_whitespace() => {
+                push(&mut tokens, &mut text, line);
+                line += (c == '\n') as usize;
+            }
+            c => text.push(c),
+        }
+    }
+    push(&mut tokens, &mut text, line);
+    tokens
+}
+
+// numbers are decimal, or hex or binary with 0x and 0b, optionally negative
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+// words that are part of statements, so can't name labels
+const KEYWORDS: &[&str] = &[
+    ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=", "-", "{", "}", "(", ")",
+    "then", "begin", "else", "end", "loop", "again", "while", "if", "key", "-key", "random", "hex", "bighex", "long",
+    "i", "delay", "buzzer", "pitch",
+];
+
+const BINARY: &[&str] = &[
+    "-", "+", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", "==", "!=", ">=", ">",
+];
+
+const UNARY: &[&str] = &["-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor", "@"];
+
+#[derive(Clone, Copy, Debug)]
+enum Fixup {
+    Address,            // the low 12 bits of an instruction
+    Long,               // a 16-bit address
+    Unpack(Option<u8>), // a nibble and the top 4 bits, or the top byte when long
+    Low,                // the low byte
+}
+
+// a use of a label before it was defined
+struct Reference {
+    at: usize, // index into the rom
+    name: String,
+    fixup: Fixup,
+    line: usize,
+}
+
+enum Operand {
+    Value(i64),
+    Label(String),
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+struct StringMode {
+    alphabet: Vec<char>,
+    body: Vec<Token>,
+}
+
+enum Rhs {
+    Register(u8),
+    Value(u8),
+    None,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>, // from 0x200
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    string_modes: HashMap<String, Vec<StringMode>>,
+    references: Vec<Reference>,
+    branches: Vec<usize>,            // `if` and `else` jumps waiting for the next `else` or `end`
+    loops: Vec<(usize, Vec<usize>)>, // loop starts and their `while` jumps, waiting for `again`
+    next_label: Option<String>,
+    main_jump: bool, // whether 0x200 holds a jump to main
+    expansions: usize,
+    nesting: usize, // expressions and terms being read
+}
+
+pub fn assemble(source: &str) -> std::result::Result<Vec<u8>, AssembleError> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source).into(),
+        line: 1,
+        rom: Vec::new(),
+        here: START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        string_modes: HashMap::new(),
+        references: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+        next_label: None,
+        main_jump: true,
+        expansions: 0,
+        nesting: 0,
+    };
+    // the jump to main, removed if main turns out to be first
+    assembler.inst(0x00, 0x00)?;
+    while let Some(token) = assembler.tokens.pop_front() {
+        assembler.line = token.line;
+        assembler.statement(token)?;
+    }
+    assembler.finish()
+}
+
+impl Assembler {
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(AssembleError {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token)
+            }
+            None => self.error("the program ends in the middle of a statement".to_string()),
+        }
+    }
+
+    fn word(&mut self) -> Result<String> {
+        let token = self.next()?;
+        if token.quoted {
+            return self.error(format!("expected a name or number, found the string \"{}\"", token.text));
+        }
+        Ok(token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().filter(|token| !token.quoted).map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        let found = self.word()?;
+        if found != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, found));
+        }
+        Ok(())
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Some(register);
+        }
+        match text.as_bytes() {
+            [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|digit| digit as u8),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8> {
+        let text = self.word()?;
+        match self.register_of(&text) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found '{}'", text)),
+        }
+    }
+
+    fn next_is_register(&self) -> bool {
+        self.peek().is_some_and(|text| self.register_of(text).is_some())
+    }
+
+    // write a byte at `here`
+    fn byte(&mut self, byte: u8) -> Result<()> {
+        if self.here >= END {
+            return self.error("the program does not fit in 64K".to_string());
+        }
+        let index = self.here - START;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn inst(&mut self, high: u8, low: u8) -> Result<()> {
+        if let Some(name) = self.next_label.take() {
+            self.define(name, self.here + 1)?;
+        }
+        self.byte(high)?;
+        self.byte(low)
+    }
+
+    fn define(&mut self, name: String, address: usize) -> Result<()> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return self.error(format!("'{}' is already defined", name));
+        }
+        if KEYWORDS.contains(&name.as_str()) || self.register_of(&name).is_some() || number(&name).is_some() {
+            return self.error(format!("'{}' can't be used as a name", name));
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    // a number, constant or label that is already defined
+    fn known(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|&address| address as f64))
+    }
+
+    // a value that has to be known now, which can be a `{ ... }` expression
+    fn value(&mut self) -> Result<f64> {
+        let text = self.word()?;
+        if text == "{" {
+            let value = self.expression()?;
+            self.expect("}")?;
+            return Ok(value);
+        }
+        match self.known(&text) {
+            Some(value) => Ok(value),
+            None => self.error(format!("'{}' is not a number or a defined name", text)),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        Ok(self.value()?.floor() as i64)
+    }
+
+    fn short(&mut self) -> Result<u8> {
+        let value = self.integer()?;
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("{} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn tiny(&mut self) -> Result<u8> {
+        let value = self.integer()?;
+        if !(0..=15).contains(&value) {
+            return self.error(format!("{} does not fit in a nibble", value));
+        }
+        Ok(value as u8)
+    }
+
+    // an address, which can be a label defined further on
+    fn operand(&mut self) -> Result<Operand> {
+        match self.peek() {
+            Some(text) if text == "{" || self.known(text).is_some() => Ok(Operand::Value(self.integer()?)),
+            Some(text) if !KEYWORDS.contains(&text) && self.register_of(text).is_none() => {
+                Ok(Operand::Label(self.word()?))
+            }
+            _ => {
+                let found = self.next()?.text;
+                self.error(format!("expected an address, found '{}'", found))
+            }
+        }
+    }
+
+    fn refer(&mut self, at: usize, name: String, fixup: Fixup) {
+        self.references.push(Reference {
+            at,
+            name,
+            fixup,
+            line: self.line,
+        });
+    }
+
+    // an instruction with a 12-bit address, e.g. 0x1 for jumps
+    fn address_inst(&mut self, op: u8, operand: Operand) -> Result<()> {
+        match operand {
+            Operand::Value(address) => {
+                if !(0..=0xFFF).contains(&address) {
+                    return self.error(format!("address {:#x} is out of range", address));
+                }
+                self.inst(op << 4 | (address >> 8) as u8, address as u8)
+            }
+            Operand::Label(name) => {
+                self.refer(self.here - START, name, Fixup::Address);
+                self.inst(op << 4, 0)
+            }
+        }
+    }
+
+    fn statement(&mut self, token: Token) -> Result<()> {
+        if token.quoted {
+            return self.error(format!("unexpected string \"{}\"", token.text));
+        }
+        let text = token.text;
+        match text.as_str() {
+            ":" => {
+                let name = self.word()?;
+                // main straight after the jump to it makes the jump unneeded
+                if name == "main" && self.main_jump && self.here == START + 2 && self.rom.len() == 2 {
+                    self.rom.clear();
+                    self.here = START;
+                    self.main_jump = false;
+                }
+                self.define(name, self.here)
+            }
+            ":alias" => {
+                let name = self.word()?;
+                let register = if self.peek() == Some("{") { self.integer()? } else { self.register()? as i64 };
+                if !(0..16).contains(&register) {
+                    return self.error(format!("there is no register {}", register));
+                }
+                self.aliases.insert(name, register as u8);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.word()?;
+                let value = self.value()?;
+                self.constant(name, value)
+            }
+            ":calc" => {
+                let name = self.word()?;
+                self.expect("{")?;
+                let value = self.expression()?;
+                self.expect("}")?;
+                self.constant(name, value)
+            }
+            ":byte" => {
+                let byte = self.short()?;
+                self.byte(byte)
+            }
+            ":pointer" => match self.operand()? {
+                Operand::Value(address) => {
+                    self.byte((address >> 8) as u8)?;
+                    self.byte(address as u8)
+                }
+                Operand::Label(name) => {
+                    self.refer(self.here - START, name, Fixup::Long);
+                    self.byte(0)?;
+                    self.byte(0)
+                }
+            },
+            ":org" => {
+                let address = self.integer()?;
+                if !(START as i64..END as i64).contains(&address) {
+                    return self.error(format!("can't assemble at {:#x}", address));
+                }
+                self.here = address as usize;
+                Ok(())
+            }
+            ":unpack" => {
+                let nibble = if self.peek() == Some("long") {
+                    self.next()?;
+                    None
+                } else {
+                    Some(self.tiny()?)
+                };
+                let high = self.aliases.get("unpack-hi").copied().unwrap_or(0);
+                let low = self.aliases.get("unpack-lo").copied().unwrap_or(1);
+                match self.operand()? {
+                    Operand::Value(address) => {
+                        let top = match nibble {
+                            Some(nibble) if address <= 0xFFF => nibble << 4 | (address >> 8) as u8,
+                            Some(_) => return self.error(format!("address {:#x} is out of range", address)),
+                            None => (address >> 8) as u8,
+                        };
+                        self.inst(0x60 | high, top)?;
+                        self.inst(0x60 | low, address as u8)
+                    }
+                    Operand::Label(name) => {
+                        self.refer(self.here - START + 1, name.clone(), Fixup::Unpack(nibble));
+                        self.inst(0x60 | high, 0)?;
+                        self.refer(self.here - START + 1, name, Fixup::Low);
+                        self.inst(0x60 | low, 0)
+                    }
+                }
+            }
+            ":next" => {
+                self.next_label = Some(self.word()?);
+                Ok(())
+            }
+            ":breakpoint" => self.next().map(drop),
+            ":monitor" => {
+                for _ in 0..2 {
+                    if self.peek() == Some("{") {
+                        self.value()?;
+                    } else {
+                        self.next()?;
+                    }
+                }
+                Ok(())
+            }
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(token) if token.quoted => Some(self.next()?.text),
+                    _ => None,
+                };
+                if self.value()? == 0.0 {
+                    let message = message.map_or(String::new(), |message| format!(": {}", message));
+                    return self.error(format!("assertion failed{}", message));
+                }
+                Ok(())
+            }
+            ":macro" => {
+                let name = self.word()?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.word()?;
+                    if arg == "{" {
+                        break;
+                    }
+                    args.push(arg);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { args, body, calls: 0 });
+                Ok(())
+            }
+            ":stringmode" => {
+                let name = self.word()?;
+                let alphabet = self.next()?;
+                if !alphabet.quoted {
+                    return self.error(format!("expected the characters for '{}' as a string", name));
+                }
+                self.expect("{")?;
+                let body = self.block()?;
+                let mode = StringMode {
+                    alphabet: alphabet.text.chars().collect(),
+                    body,
+                };
+                self.string_modes.entry(name).or_default().push(mode);
+                Ok(())
+            }
+            ":call" => {
+                let operand = self.operand()?;
+                self.address_inst(0x2, operand)
+            }
+            ";" | "return" => self.inst(0x00, 0xEE),
+            "clear" => self.inst(0x00, 0xE0),
+            "exit" => self.inst(0x00, 0xFD),
+            "lores" => self.inst(0x00, 0xFE),
+            "hires" => self.inst(0x00, 0xFF),
+            "scroll-right" => self.inst(0x00, 0xFB),
+            "scroll-left" => self.inst(0x00, 0xFC),
+            "scroll-down" => {
+                let rows = self.tiny()?;
+                self.inst(0x00, 0xC0 | rows)
+            }
+            "scroll-up" => {
+                let rows = self.tiny()?;
+                self.inst(0x00, 0xD0 | rows)
+            }
+            "audio" => self.inst(0xF0, 0x02),
+            "plane" => {
+                let planes = self.tiny()?;
+                if planes > 3 {
+                    return self.error(format!("there is no plane {}", planes));
+                }
+                self.inst(0xF0 | planes, 0x01)
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x33)
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let op = if text == "save" { 0x2 } else { 0x3 };
+                    return self.inst(0x50 | x, y << 4 | op);
+                }
+                self.inst(0xF0 | x, if text == "save" { 0x55 } else { 0x65 })
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x75)
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x85)
+            }
+            "sprite" => {
+                let (x, y) = (self.register()?, self.register()?);
+                let rows = self.tiny()?;
+                self.inst(0xD0 | x, y << 4 | rows)
+            }
+            "jump" | "jump0" | "native" => {
+                let operand = self.operand()?;
+                let op = match text.as_str() {
+                    "jump" => 0x1,
+                    "jump0" => 0xB,
+                    _ => 0x0,
+                };
+                self.address_inst(op, operand)
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let low = match text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.inst(0xF0 | x, low)
+            }
+            "i" => self.i_statement(),
+            "loop" => {
+                self.loops.push((self.here, Vec::new()));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return self.error("'while' outside a loop".to_string());
+                }
+                let condition = self.condition()?;
+                self.emit_condition(condition, true)?;
+                let at = self.here - START;
+                self.loops.last_mut().unwrap().1.push(at);
+                self.inst(0x10, 0x00)
+            }
+            "again" => {
+                let (start, exits) = match self.loops.pop() {
+                    Some(open) => open,
+                    None => return self.error("'again' without a 'loop'".to_string()),
+                };
+                self.address_inst(0x1, Operand::Value(start as i64))?;
+                for at in exits {
+                    self.patch(at)?;
+                }
+                Ok(())
+            }
+            "if" => {
+                let condition = self.condition()?;
+                match self.word()?.as_str() {
+                    "then" => self.emit_condition(condition, false),
+                    "begin" => {
+                        self.emit_condition(condition, true)?;
+                        self.branches.push(self.here - START);
+                        self.inst(0x10, 0x00)
+                    }
+                    other => self.error(format!("expected 'then' or 'begin', found '{}'", other)),
+                }
+            }
+            "else" => {
+                let at = match self.branches.pop() {
+                    Some(at) => at,
+                    None => return self.error("'else' without an 'if ... begin'".to_string()),
+                };
+                self.branches.push(self.here - START);
+                self.inst(0x10, 0x00)?;
+                self.patch(at)
+            }
+            "end" => match self.branches.pop() {
+                Some(at) => self.patch(at),
+                None => self.error("'end' without an 'if ... begin'".to_string()),
+            },
+            _ if self.register_of(&text).is_some() => {
+                let x = self.register_of(&text).unwrap();
+                self.register_statement(x)
+            }
+            _ if number(&text).is_some() => {
+                self.tokens.push_front(Token {
+                    text,
+                    quoted: false,
+                    line: self.line,
+                });
+                let byte = self.short()?;
+                self.byte(byte)
+            }
+            _ if self.macros.contains_key(&text) => self.expand_macro(&text),
+            _ if self.string_modes.contains_key(&text) => self.expand_string(&text),
+            _ if text.starts_with(':') || KEYWORDS.contains(&text.as_str()) => {
+                self.error(format!("unexpected '{}'", text))
+            }
+            // a label on its own calls it
+            _ => {
+                self.tokens.push_front(Token {
+                    text,
+                    quoted: false,
+                    line: self.line,
+                });
+                let operand = self.operand()?;
+                self.address_inst(0x2, operand)
+            }
+        }
+    }
+
+    fn constant(&mut self, name: String, value: f64) -> Result<()> {
+        if self.labels.contains_key(&name) {
+            return self.error(format!("'{}' is already defined", name));
+        }
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    // point the jump at `at` to `here`
+    fn patch(&mut self, at: usize) -> Result<()> {
+        if self.here > 0xFFF {
+            return self.error(format!("address {:#x} is out of range", self.here));
+        }
+        self.rom[at] = 0x10 | (self.here >> 8) as u8;
+        self.rom[at + 1] = self.here as u8;
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<()> {
+        match self.word()?.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") | Some("bighex") => {
+                    let low = if self.word()? == "hex" { 0x29 } else { 0x30 };
+                    let x = self.register()?;
+                    self.inst(0xF0 | x, low)
+                }
+                Some("long") => {
+                    self.next()?;
+                    // `:next` names the address rather than the instruction
+                    if let Some(name) = self.next_label.take() {
+                        self.define(name, self.here + 2)?;
+                    }
+                    self.inst(0xF0, 0x00)?;
+                    match self.operand()? {
+                        Operand::Value(address) => {
+                            if !(0..=0xFFFF).contains(&address) {
+                                return self.error(format!("address {:#x} is out of range", address));
+                            }
+                            self.byte((address >> 8) as u8)?;
+                            self.byte(address as u8)
+                        }
+                        Operand::Label(name) => {
+                            self.refer(self.here - START, name, Fixup::Long);
+                            self.byte(0)?;
+                            self.byte(0)
+                        }
+                    }
+                }
+                _ => {
+                    let operand = self.operand()?;
+                    self.address_inst(0xA, operand)
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.inst(0xF0 | x, 0x1E)
+            }
+            other => self.error(format!("expected ':=' or '+=' after 'i', found '{}'", other)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<()> {
+        let op = self.word()?;
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.short()?;
+                    self.inst(0xC0 | x, mask)
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.inst(0xF0 | x, 0x0A)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.inst(0xF0 | x, 0x07)
+                }
+                _ if self.next_is_register() => {
+                    let y = self.register()?;
+                    self.inst(0x80 | x, y << 4)
+                }
+                _ => {
+                    let value = self.short()?;
+                    self.inst(0x60 | x, value)
+                }
+            },
+            "+=" | "-=" if !self.next_is_register() => {
+                let value = self.short()?;
+                let value = if op == "-=" { value.wrapping_neg() } else { value };
+                self.inst(0x70 | x, value)
+            }
+            _ => {
+                let low = match op.as_str() {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    "<<=" => 0xE,
+                    _ => return self.error(format!("unknown operator '{}'", op)),
+                };
+                let y = self.register()?;
+                self.inst(0x80 | x, y << 4 | low)
+            }
+        }
+    }
+
+    fn condition(&mut self) -> Result<(u8, String, Rhs)> {
+        let x = self.register()?;
+        let op = self.word()?;
+        let rhs = match op.as_str() {
+            "key" | "-key" => Rhs::None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" if self.next_is_register() => Rhs::Register(self.register()?),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => Rhs::Value(self.short()?),
+            _ => return self.error(format!("unknown comparison '{}'", op)),
+        };
+        Ok((x, op, rhs))
+    }
+
+    // the instruction that skips the next when the condition is false, or
+    // when it is true if `negated`
+    fn emit_condition(&mut self, (x, op, rhs): (u8, String, Rhs), negated: bool) -> Result<()> {
+        let op = match op.as_str() {
+            "==" if negated => "!=",
+            "!=" if negated => "==",
+            "key" if negated => "-key",
+            "-key" if negated => "key",
+            "<" if negated => ">=",
+            ">" if negated => "<=",
+            ">=" if negated => "<",
+            "<=" if negated => ">",
+            op => op,
+        };
+        let temp = self.aliases.get("compare-temp").copied().unwrap_or(0xF);
+        match (op, rhs) {
+            ("==", Rhs::Register(y)) => self.inst(0x90 | x, y << 4),
+            ("==", Rhs::Value(value)) => self.inst(0x40 | x, value),
+            ("!=", Rhs::Register(y)) => self.inst(0x50 | x, y << 4),
+            ("!=", Rhs::Value(value)) => self.inst(0x30 | x, value),
+            ("key", _) => self.inst(0xE0 | x, 0xA1),
+            ("-key", _) => self.inst(0xE0 | x, 0x9E),
+            (op, rhs) => {
+                match rhs {
+                    Rhs::Register(y) => self.inst(0x80 | temp, y << 4)?,
+                    Rhs::Value(value) => self.inst(0x60 | temp, value)?,
+                    Rhs::None => unreachable!(),
+                }
+                let (subtract, skip) = match op {
+                    ">" => (0x5, 0x3F),
+                    "<" => (0x7, 0x3F),
+                    ">=" => (0x7, 0x4F),
+                    _ => (0x5, 0x4F),
+                };
+                self.inst(0x80 | temp, x << 4 | subtract)?;
+                self.inst(skip, 0x01)
+            }
+        }
+    }
+
+    // the tokens up to the `}` closing a block, whose `{` has been read
+    fn block(&mut self) -> Result<Vec<Token>> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            if !token.quoted {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" => depth -= 1,
+                    _ => {}
+                }
+            }
+            if depth == 0 {
+                return Ok(body);
+            }
+            body.push(token);
+        }
+    }
+
+    // put `body` back in front of the tokens to come, with `substitute`
+    // giving replacements for some words
+    fn expand<F>(&mut self, body: &[Token], substitute: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<Token>,
+    {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return self.error("macros expand forever".to_string());
+        }
+        let line = self.line;
+        for token in body.iter().rev() {
+            let token = match token.quoted {
+                false => substitute(&token.text).unwrap_or_else(|| token.clone()),
+                true => token.clone(),
+            };
+            self.tokens.push_front(Token { line, ..token });
+        }
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<()> {
+        let count = self.macros[name].args.len();
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+        let definition = self.macros.get_mut(name).unwrap();
+        let calls = definition.calls;
+        definition.calls += 1;
+        let (names, body) = (definition.args.clone(), definition.body.clone());
+        self.expand(&body, |text| {
+            if text == "CALLS" {
+                return Some(word(calls.to_string()));
+            }
+            names.iter().position(|arg| arg == text).map(|i| args[i].clone())
+        })
+    }
+
+    fn expand_string(&mut self, name: &str) -> Result<()> {
+        let text = self.next()?;
+        if !text.quoted {
+            return self.error(format!("expected a string after '{}'", name));
+        }
+        let mut expansions = Vec::new();
+        for (index, c) in text.text.chars().enumerate() {
+            let modes = &self.string_modes[name];
+            let (mode, value) = match modes
+                .iter()
+                .find_map(|mode| Some((mode, mode.alphabet.iter().position(|&a| a == c)?)))
+            {
+                Some(found) => found,
+                None => return self.error(format!("'{}' can't be written with '{}'", c, name)),
+            };
+            expansions.push((mode.body.clone(), [c as usize, index, value]));
+        }
+        // expanded last first, as each goes in front of the rest
+        for (body, [c, index, value]) in expansions.into_iter().rev() {
+            self.expand(&body, |text| match text {
+                "CHAR" => Some(word(c.to_string())),
+                "INDEX" => Some(word(index.to_string())),
+                "VALUE" => Some(word(value.to_string())),
+                _ => None,
+            })?;
+        }
+        Ok(())
+    }
+
+    // reads with `read`, one level deeper into an expression
+    fn nested(&mut self, read: fn(&mut Assembler) -> Result<f64>) -> Result<f64> {
+        if self.nesting == MAX_NESTING {
+            return self.error(format!("expression nested more than {} deep", MAX_NESTING));
+        }
+        self.nesting += 1;
+        let value = read(self);
+        self.nesting -= 1;
+        value
+    }
+
+    fn expression(&mut self) -> Result<f64> {
+        self.nested(Assembler::binary)
+    }
+
+    fn binary(&mut self) -> Result<f64> {
+        let left = self.term()?;
+        match self.peek() {
+            Some(op) if BINARY.contains(&op) => {
+                let op = self.word()?;
+                let right = self.expression()?;
+                Ok(binary(&op, left, right))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn term(&mut self) -> Result<f64> {
+        self.nested(Assembler::factor)
+    }
+
+    fn factor(&mut self) -> Result<f64> {
+        let token = self.next()?;
+        if token.quoted {
+            return self.error(format!("unexpected string \"{}\" in an expression", token.text));
+        }
+        let text = token.text;
+        match text.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "@" => {
+                let address = self.term()? as usize;
+                Ok(address.checked_sub(START).and_then(|i| self.rom.get(i)).copied().unwrap_or(0) as f64)
+            }
+            "strlen" => {
+                let string = self.next()?;
+                Ok(string.text.chars().count() as f64)
+            }
+            op if UNARY.contains(&op) => Ok(unary(op, self.term()?)),
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            _ => match self.known(&text).or_else(|| self.register_of(&text).map(f64::from)) {
+                Some(value) => Ok(value),
+                None => self.error(format!("'{}' is not a number or a defined name", text)),
+            },
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        if !self.branches.is_empty() {
+            return self.error("an 'if ... begin' has no 'end'".to_string());
+        }
+        if !self.loops.is_empty() {
+            return self.error("a 'loop' has no 'again'".to_string());
+        }
+        let main = match self.labels.get("main") {
+            Some(&main) => main,
+            None => return self.error("there is no 'main' label".to_string()),
+        };
+        if self.main_jump {
+            if main > 0xFFF {
+                return self.error(format!("main at {:#x} is out of range", main));
+            }
+            self.rom[0] = 0x10 | (main >> 8) as u8;
+            self.rom[1] = main as u8;
+        }
+
+        for reference in mem::take(&mut self.references) {
+            self.line = reference.line;
+            let address = match self.labels.get(&reference.name) {
+                Some(&address) => address,
+                None => match self.constants.get(&reference.name) {
+                    Some(&value) => value.floor() as usize,
+                    None => return self.error(format!("'{}' is never defined", reference.name)),
+                },
+            };
+            let at = reference.at;
+            let short = address <= 0xFFF;
+            match reference.fixup {
+                Fixup::Address if short => {
+                    self.rom[at] |= (address >> 8) as u8;
+                    self.rom[at + 1] = address as u8;
+                }
+                Fixup::Unpack(Some(nibble)) if short => self.rom[at] = nibble << 4 | (address >> 8) as u8,
+                Fixup::Address | Fixup::Unpack(Some(_)) => {
+                    return self.error(format!("'{}' at {:#x} is out of range", reference.name, address))
+                }
+                Fixup::Unpack(None) => self.rom[at] = (address >> 8) as u8,
+                Fixup::Long => {
+                    self.rom[at] = (address >> 8) as u8;
+                    self.rom[at + 1] = address as u8;
+                }
+                Fixup::Low => self.rom[at] = address as u8,
+            }
+        }
+        Ok(self.rom)
+    }
+}
+
+fn word(text: String) -> Token {
+    Token {
+        text,
+        quoted: false,
+        line: 0,
+    }
+}
+
+fn unary(op: &str, value: f64) -> f64 {
+    match op {
+        "-" => -value,
+        "~" => !(value as i64) as f64,
+        "!" => (value == 0.0) as u8 as f64,
+        "sin" => value.sin(),
+        "cos" => value.cos(),
+        "tan" => value.tan(),
+        "exp" => value.exp(),
+        "log" => value.ln(),
+        "abs" => value.abs(),
+        "sqrt" => value.sqrt(),
+        "sign" => value.signum() * (value != 0.0) as u8 as f64,
+        "ceil" => value.ceil(),
+        _ => value.floor(),
+    }
+}
+
+fn binary(op: &str, left: f64, right: f64) -> f64 {
+    let (a, b) = (left as i64, right as i64);
+    let boolean = |b: bool| b as u8 as f64;
+    match op {
+        "-" => left - right,
+        "+" => left + right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => left % right,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+        ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "<" => boolean(left < right),
+        "<=" => boolean(left <= right),
+        "==" => boolean(left == right),
+        "!=" => boolean(left != right),
+        ">=" => boolean(left >= right),
+        _ => boolean(left > right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::load;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(assemble(": main 0x00 0xE0 18 0b10 # halt\n").unwrap(), [0x00, 0xE0, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn loops_and_comparisons() {
+        let source = "
+            : main
+              clear
+              v0 := 0
+              loop
+                i := digit
+                sprite v0 v1 5
+                v0 += 6
+                while v0 < 30
+              again
+              jump halt
+            : digit 0xF0 0x90 0x90 0x90 0xF0
+            : halt jump halt
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            [
+                0x00, 0xE0, 0x60, 0x00, 0xA2, 0x16, 0xD0, 0x15, 0x70, 0x06, 0x6F, 0x1E, 0x8F, 0x07, 0x4F, 0x01, 0x12,
+                0x14, 0x12, 0x04, 0x12, 0x1B, 0xF0, 0x90, 0x90, 0x90, 0xF0, 0x12, 0x1B,
+            ]
+        );
+
+        let mut chip = load(&rom);
+        for _ in 0..100 {
+            chip.cycle().unwrap();
+        }
+        assert_eq!(chip.registers()[0], 30);
+        assert!(chip.pixel(24, 0));
+    }
+
+    #[test]
+    fn main_jump() {
+        assert_eq!(assemble(": draw ; : main draw").unwrap(), [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+        assert_eq!(assemble(": main : spin jump spin").unwrap(), [0x12, 0x00]);
+    }
+
+    #[test]
+    fn branches() {
+        let source = "
+            : main
+              if v1 == 2 then v2 := 3
+              if v1 != v2 begin
+                v3 += v4
+              else
+                v3 -= 1
+              end
+              if v5 key then ;
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x41, 0x02, 0x62, 0x03, 0x91, 0x20, 0x12, 0x0C, 0x83, 0x44, 0x12, 0x0E, 0x73, 0xFF, 0xE5, 0xA1, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+            :alias x v4
+            :const size 8
+            :calc double { size * 2 + 1 }
+            :macro twice op { op op }
+            :stringmode text \"ab\" { :byte { VALUE + INDEX } }
+            : main
+              x := double
+              twice clear
+              text \"ba\"
+              :unpack 0xA data
+              i := long data
+            : data
+              :pointer main
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x64, 0x18, 0x00, 0xE0, 0x00, 0xE0, 0x01, 0x01, 0x60, 0xA2, 0x61, 0x10, 0xF0, 0x00, 0x02, 0x10, 0x02,
+                0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = assemble(": main\n  clear\n  v0 := nowhere\n").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(error.to_string(), "line 3: 'nowhere' is not a number or a defined name");
+        assert_eq!(assemble(": main\n  jump missing\n").unwrap_err().line, 2);
+        assert_eq!(assemble(": start ;").unwrap_err().message, "there is no 'main' label");
+        assert!(assemble(": main loop").is_err());
+        assert!(assemble(":macro forever { forever } : main forever").is_err());
+    }
+
+    #[test]
+    fn org_and_next() {
+        assert_eq!(assemble(": main :next value v0 := 5 i := value").unwrap(), [0x60, 0x05, 0xA2, 0x01]);
+        assert_eq!(assemble(": main :org 0x204 clear").unwrap(), [0x00, 0x00, 0x00, 0x00, 0x00, 0xE0]);
+        assert_eq!(assemble(":org 0x100 : main").unwrap_err().message, "can't assemble at 0x100");
+        assert_eq!(assemble(":org 0x10000 : main").unwrap_err().message, "can't assemble at 0x10000");
+    }
+
+    #[test]
+    fn out_of_range_labels() {
+        let far = ":org 0x1000 : far 0";
+        assert_eq!(assemble(&format!(": main i := long far {}", far)).unwrap()[..4], [0xF0, 0x00, 0x10, 0x00]);
+        for code in ["jump far", "i := far", ":unpack 0xA far"] {
+            let error = assemble(&format!(": main {} {}", code, far)).unwrap_err();
+            assert_eq!(error.message, "'far' at 0x1000 is out of range", "{}", code);
+        }
+    }
+
+    #[test]
+    fn string_mode_errors() {
+        let error = assemble(":stringmode text 65 { } : main").unwrap_err();
+        assert_eq!(error.message, "expected the characters for 'text' as a string");
+        let mode = ":stringmode text \"ab\" { :byte VALUE } : main";
+        assert_eq!(assemble(&format!("{} text 5", mode)).unwrap_err().message, "expected a string after 'text'");
+        assert_eq!(assemble(&format!("{} text \"c\"", mode)).unwrap_err().message, "'c' can't be written with 'text'");
+    }
+
+    #[test]
+    fn deep_nesting() {
+        assert_eq!(assemble(":calc x { ( ( 1 ) ) } : main :byte x").unwrap(), [0x01]);
+        let nested = format!(": main :byte {{ {}1{} }}", "( ".repeat(200_000), " )".repeat(200_000));
+        assert_eq!(assemble(&nested).unwrap_err().message, "expression nested more than 256 deep");
+        let negated = format!(": main :byte {{ {}1 }}", "- ".repeat(200_000));
+        assert_eq!(assemble(&negated).unwrap_err().message, "expression nested more than 256 deep");
+        let sum = format!(": main :byte {{ 1{} }}", " + 1".repeat(200_000));
+        assert_eq!(assemble(&sum).unwrap_err().message, "expression nested more than 256 deep");
+    }
+}
@@ -0,0 +1,250 @@
+// Saving the display as an image.
+//
+// Supports PNG (scaled, with a two colour palette) and binary portable
+// bitmap (PBM) files.
+
+use crate::chip8::Frame;
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub type Rgb = [u8; 3];
+
+// colours for unset and set pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: Rgb,
+    pub foreground: Rgb,
+}
+
+impl Palette {
+    // parse a palette written as `RRGGBB,RRGGBB` (background, foreground)
+    pub fn parse(s: &str) -> Result<Palette, String> {
+        let (background, foreground) = s
+            .split_once(',')
+            .ok_or_else(|| format!("palette '{}' should be two colours, e.g. 000000,ffffff", s))?;
+        Ok(Palette {
+            background: parse_rgb(background)?,
+            foreground: parse_rgb(foreground)?,
+        })
+    }
+
+    pub fn colour(&self, set: bool) -> Rgb {
+        if set {
+            self.foreground
+        } else {
+            self.background
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            background: [0x00, 0x00, 0x00],
+            foreground: [0xFF, 0xFF, 0xFF],
+        }
+    }
+}
+
+// parse a colour written as `RRGGBB`, with an optional leading `#`
+pub fn parse_rgb(s: &str) -> Result<Rgb, String> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid colour '{}', expected RRGGBB", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Pbm,
+}
+
+impl ImageFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ImageFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "pbm" => Some(ImageFormat::Pbm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Pbm => "pbm",
+        }
+    }
+}
+
+// the largest scale images are written at, 4096x2048 pixels for a
+// SUPER-CHIP display
+pub const MAX_SCALE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageOptions {
+    pub scale: usize, // width and height of each display pixel in the image
+    pub palette: Palette,
+}
+
+impl ImageOptions {
+    // the scale used, kept within 1 and `MAX_SCALE`
+    pub fn scale(&self) -> usize {
+        self.scale.clamp(1, MAX_SCALE)
+    }
+}
+
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            scale: 8,
+            palette: Palette::default(),
+        }
+    }
+}
+
+// write the frame to `path`, the format is chosen by the file extension
+pub fn save<P: AsRef<Path>>(path: P, frame: &Frame, options: &ImageOptions) -> io::Result<()> {
+    let format = ImageFormat::from_path(&path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unknown image format, expected a .png or .pbm file",
+        )
+    })?;
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    match format {
+        ImageFormat::Png => write_png(&mut out, frame, options)?,
+        ImageFormat::Pbm => write_pbm(&mut out, frame, options.scale)?,
+    }
+    out.flush()
+}
+
+// the frame scaled up as RGB triples, row by row
+pub fn to_rgb(frame: &Frame, options: &ImageOptions) -> Vec<u8> {
+    let scale = options.scale();
+    let mut data = Vec::with_capacity(frame.width() * frame.height() * scale * scale * 3);
+    for y in 0..frame.height() * scale {
+        for x in 0..frame.width() * scale {
+            data.extend_from_slice(&options.palette.colour(frame.pixel(x / scale, y / scale)));
+        }
+    }
+    data
+}
+
+pub fn write_png<W: Write>(out: W, frame: &Frame, options: &ImageOptions) -> io::Result<()> {
+    let scale = options.scale();
+    let width = (frame.width() * scale) as u32;
+    let height = (frame.height() * scale) as u32;
+
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer
+        .write_image_data(&to_rgb(frame, options))
+        .map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+// binary PBM (P4), set pixels are written as white and unset pixels as black
+// so the image looks like the display
+pub fn write_pbm<W: Write>(mut out: W, frame: &Frame, scale: usize) -> io::Result<()> {
+    let scale = scale.clamp(1, MAX_SCALE);
+    let width = frame.width() * scale;
+    let height = frame.height() * scale;
+    write!(out, "P4\n{} {}\n", width, height)?;
+
+    let mut row = vec![0u8; width.div_ceil(8)];
+    for y in 0..height {
+        row.fill(0);
+        for x in 0..width {
+            // in PBM a 1 bit is black
+            if !frame.pixel(x / scale, y / scale) {
+                row[x / 8] |= 0b1000_0000 >> (x % 8);
+            }
+        }
+        out.write_all(&row)?;
+    }
+    Ok(())
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::fixtures::corner;
+
+    #[test]
+    fn parse_colours() {
+        assert_eq!(parse_rgb("ff8000"), Ok([0xFF, 0x80, 0x00]));
+        assert_eq!(parse_rgb(" #0A0b0C"), Ok([0x0A, 0x0B, 0x0C]));
+        assert!(parse_rgb("ff80").is_err());
+        assert!(parse_rgb("ff80zz").is_err());
+        assert!(parse_rgb("+f8000").is_err());
+
+        let palette = Palette::parse("102030,#ffffff").unwrap();
+        assert_eq!(palette.colour(false), [0x10, 0x20, 0x30]);
+        assert_eq!(palette.colour(true), [0xFF; 3]);
+        assert!(Palette::parse("102030").is_err());
+        assert!(Palette::parse("102030,ffffff,000000").is_err());
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let options = ImageOptions {
+            scale: 2,
+            palette: Palette::parse("102030,ff8000").unwrap(),
+        };
+        let mut png = Vec::new();
+        write_png(&mut png, &corner(), &options).unwrap();
+
+        let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        let pixel = |x: usize, y: usize| &data[(y * 128 + x) * 3..(y * 128 + x) * 3 + 3];
+        assert_eq!(pixel(1, 1), [0xFF, 0x80, 0x00]);
+        assert_eq!(pixel(2, 0), [0x10, 0x20, 0x30]);
+        assert_eq!(pixel(0, 2), [0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn pbm_bits() {
+        let mut pbm = Vec::new();
+        write_pbm(&mut pbm, &corner(), 2).unwrap();
+        let header = b"P4\n128 64\n";
+        assert_eq!(&pbm[..header.len()], header);
+        let rows = &pbm[header.len()..];
+        assert_eq!(rows.len(), 16 * 64);
+        // unset pixels are black, a 1 bit
+        assert_eq!(rows[..2], [0b0011_1111, 0xFF]);
+        assert_eq!(rows[16..18], [0b0011_1111, 0xFF]);
+        assert!(rows[32..].iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn scale_is_limited() {
+        let options = ImageOptions {
+            scale: 100_000,
+            ..ImageOptions::default()
+        };
+        assert_eq!(options.scale(), MAX_SCALE);
+        assert_eq!(to_rgb(&corner(), &options).len(), 64 * 32 * MAX_SCALE * MAX_SCALE * 3);
+        let mut pbm = Vec::new();
+        write_pbm(&mut pbm, &corner(), 0).unwrap();
+        assert!(pbm.starts_with(b"P4\n64 32\n"));
+    }
+}
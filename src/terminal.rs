@@ -0,0 +1,1016 @@
+// The terminal frontend, drawing with termion and reading the keyboard.
+
+use crate::chip8::{Chip8, Frame, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::frontend::{Action, Input, Poll, Renderer, TerminalSize};
+use crate::screenshot::Rgb;
+use crate::theme::{Colours, Theme};
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use termion::event::{Event, Key, MouseButton, MouseEvent};
+use termion::input::{Events, TermRead};
+use termion::AsyncReader;
+
+// how pixels are drawn as characters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    #[default]
+    Squares,    // a ⬛ or ⬜ emoji for each pixel, two columns wide
+    HalfBlocks, // ▀ ▄ █ for two pixels stacked in a cell
+    Braille,    // braille patterns for 2x4 pixels in a cell
+    Ascii,      // ' . : for two pixels stacked in a cell
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 4] = [
+        RenderMode::Squares,
+        RenderMode::HalfBlocks,
+        RenderMode::Braille,
+        RenderMode::Ascii,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RenderMode::Squares => "squares",
+            RenderMode::HalfBlocks => "half-blocks",
+            RenderMode::Braille => "braille",
+            RenderMode::Ascii => "ascii",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RenderMode> {
+        RenderMode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    // the mode after this one, wrapping around
+    pub fn next(self) -> RenderMode {
+        let index = RenderMode::ALL.iter().position(|&mode| mode == self).unwrap();
+        RenderMode::ALL[(index + 1) % RenderMode::ALL.len()]
+    }
+
+    // pixels in each cell, as (width, height)
+    fn cell_size(self) -> (usize, usize) {
+        match self {
+            RenderMode::Squares => (1, 1),
+            RenderMode::HalfBlocks | RenderMode::Ascii => (1, 2),
+            RenderMode::Braille => (2, 4),
+        }
+    }
+
+    // terminal columns taken by each cell
+    fn cell_columns(self) -> u16 {
+        match self {
+            RenderMode::Squares => 2,
+            _ => 1,
+        }
+    }
+
+    // the character for a cell, where bit `y * width + x` of `cell` is set
+    // for each lit pixel
+    fn glyph(self, cell: u8) -> char {
+        match self {
+            RenderMode::Squares => ['⬛', '⬜'][cell as usize],
+            RenderMode::HalfBlocks => [' ', '▀', '▄', '█'][cell as usize],
+            RenderMode::Ascii => [' ', '\'', '.', ':'][cell as usize],
+            RenderMode::Braille => {
+                // braille dots are numbered down the left column and then the
+                // right, with the bottom row added last
+                const DOTS: [u32; 8] = [0x01, 0x08, 0x02, 0x10, 0x04, 0x20, 0x40, 0x80];
+                let dots = (0..8)
+                    .filter(|bit| cell & (1 << bit) != 0)
+                    .fold(0, |dots, bit| dots | DOTS[bit]);
+                char::from_u32(0x2800 + dots).unwrap()
+            }
+        }
+    }
+}
+
+impl RenderMode {
+    // the text for a cell drawn with a theme, and its (foreground,
+    // background) colours
+    //
+    // Squares and half-blocks colour each pixel, the other modes colour the
+    // whole cell.
+    fn coloured_glyph(self, cell: u8, theme: &Theme) -> (String, (Rgb, Rgb)) {
+        let colour = |cell: u8| theme.colours[cell as usize & 1];
+        match self {
+            RenderMode::Squares => ("  ".to_string(), (colour(cell), colour(cell))),
+            RenderMode::HalfBlocks => ("▀".to_string(), (colour(cell), colour(cell >> 1))),
+            _ => (self.glyph(cell).to_string(), (theme.foreground(), theme.background())),
+        }
+    }
+}
+
+impl RenderMode {
+    // this mode followed by the denser modes to use when it does not fit
+    fn fallbacks(self) -> &'static [RenderMode] {
+        match self {
+            RenderMode::Squares => &[RenderMode::Squares, RenderMode::HalfBlocks, RenderMode::Braille],
+            RenderMode::HalfBlocks => &[RenderMode::HalfBlocks, RenderMode::Braille],
+            RenderMode::Braille => &[RenderMode::Braille],
+            // without unicode there is nothing denser
+            RenderMode::Ascii => &[RenderMode::Ascii],
+        }
+    }
+
+    // the display size in cells, as (columns, rows)
+    fn cells(self) -> (usize, usize) {
+        let (width, height) = self.cell_size();
+        (DISPLAY_WIDTH.div_ceil(width), DISPLAY_HEIGHT.div_ceil(height))
+    }
+
+    // the terminal size needed for the display and the message below it, as
+    // (columns, rows)
+    fn needs(self) -> (u16, u16) {
+        let (columns, rows) = self.cells();
+        (columns as u16 * self.cell_columns(), rows as u16 + 2)
+    }
+}
+
+// Draws the display in one of the `RenderMode`s, centred in the terminal.
+//
+// When the terminal is too small for the chosen mode a denser one is used
+// instead. The cells last drawn are kept, and only the cells that changed
+// since are written, batched into a single write per frame.
+pub struct TerminalRenderer<W: Write> {
+    out: W,
+    preferred: RenderMode, // the mode that was asked for
+    mode: RenderMode,      // the mode in use
+    colours: Option<Colours>, // plain characters when not set
+    size: Option<TerminalSize>,
+    too_small: bool,
+    origin: (u16, u16), // top left cell of the display
+    buffer: Vec<u8>,
+    drawn: Option<Vec<u8>>, // the cells currently on the terminal, by row
+    message: String,
+    message_changed: bool,
+    panel: Vec<String>, // lines below the message
+    panel_changed: bool,
+    keypad: Option<Keypad>, // drawn to the right of the display when set
+}
+
+impl<W: Write> TerminalRenderer<W> {
+    // the display is drawn in the top left until the terminal size is known
+    pub fn new(out: W, mode: RenderMode) -> TerminalRenderer<W> {
+        TerminalRenderer {
+            out,
+            preferred: mode,
+            mode,
+            colours: None,
+            size: None,
+            too_small: false,
+            origin: (1, 1),
+            buffer: Vec::new(),
+            drawn: None,
+            message: String::new(),
+            message_changed: false,
+            panel: Vec::new(),
+            panel_changed: false,
+            keypad: None,
+        }
+    }
+
+    pub fn mode(&self) -> RenderMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: RenderMode) {
+        self.preferred = mode;
+        self.layout();
+    }
+
+    pub fn set_colours(&mut self, colours: Option<Colours>) {
+        self.colours = colours;
+        self.invalidate();
+    }
+
+    // redraw everything on the next render, for when the terminal has been
+    // written to by something else
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+        if let Some(keypad) = &mut self.keypad {
+            keypad.invalidate();
+        }
+    }
+
+    // pick the mode and position for the terminal size
+    fn layout(&mut self) {
+        self.invalidate();
+        self.mode = self.preferred;
+        self.too_small = false;
+        self.origin = (1, 1);
+        let Some(size) = self.size else { return };
+
+        let fits = |mode: &&RenderMode| {
+            let (columns, rows) = self.needs(**mode);
+            columns <= size.columns && rows <= size.rows
+        };
+        match self.preferred.fallbacks().iter().find(fits) {
+            Some(&mode) => {
+                let (columns, rows) = self.needs(mode);
+                self.mode = mode;
+                self.origin = ((size.columns - columns) / 2 + 1, (size.rows - rows) / 2 + 1);
+            }
+            None => self.too_small = true,
+        }
+        let display_columns = self.mode.needs().0;
+        if let Some(keypad) = &mut self.keypad {
+            keypad.place((self.origin.0 + display_columns, self.origin.1));
+        }
+    }
+
+    // the terminal size needed for a mode with the panel below the message,
+    // lines of the panel wider than the terminal are cut short
+    fn needs(&self, mode: RenderMode) -> (u16, u16) {
+        let (columns, rows) = mode.needs();
+        let keypad_columns = if self.keypad.is_some() { Keypad::COLUMNS } else { 0 };
+        (columns + keypad_columns, rows + self.panel.len() as u16)
+    }
+
+    // the row below the display that the message is shown on
+    fn message_row(&self) -> u16 {
+        self.origin.1 + self.mode.cells().1 as u16 + 1
+    }
+
+    fn draw_panel(&mut self) -> io::Result<()> {
+        let width = self.size.map_or(usize::MAX, |size| (size.columns + 1 - self.origin.0) as usize);
+        for (row, line) in (self.message_row() + 1..).zip(&self.panel) {
+            let line: String = line.chars().take(width).collect();
+            write!(
+                self.buffer,
+                "{}{}{}",
+                termion::cursor::Goto(self.origin.0, row),
+                termion::clear::CurrentLine,
+                line
+            )?;
+        }
+        Ok(())
+    }
+
+    fn draw_too_small(&mut self) -> io::Result<()> {
+        let size = self.size.unwrap_or_default();
+        let &densest = self.preferred.fallbacks().last().unwrap();
+        let (columns, rows) = self.needs(densest);
+        write!(
+            self.buffer,
+            "{}{}{}Terminal too small: {}x{} needed, {}x{} available",
+            termion::cursor::Hide,
+            termion::clear::All,
+            termion::cursor::Goto(1, 1),
+            columns,
+            rows,
+            size.columns,
+            size.rows
+        )
+    }
+
+    fn draw_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let (width, height) = self.mode.cell_size();
+        let (columns, rows) = self.mode.cells();
+
+        let mut cells = vec![0; columns * rows];
+        for y in 0..frame.height() {
+            for x in 0..frame.width() {
+                if frame.pixel(x, y) {
+                    cells[y / height * columns + x / width] |= 1 << (y % height * width + x % width);
+                }
+            }
+        }
+
+        let previous = self.drawn.take();
+        if previous.is_none() {
+            write!(self.buffer, "{}{}", termion::cursor::Hide, termion::clear::All)?;
+            // clicks are only reported while the keypad is shown
+            if self.keypad.is_some() {
+                self.buffer.extend_from_slice(ENTER_MOUSE.as_bytes());
+            }
+            self.message_changed = true;
+            self.panel_changed = true;
+        }
+
+        // where the cursor is, as (column, row) in cells, and the colours
+        // that are set
+        let mut cursor = None;
+        let mut style = None;
+        for row in 0..rows {
+            for column in 0..columns {
+                let cell = cells[row * columns + column];
+                if previous.as_ref().is_some_and(|previous| previous[row * columns + column] == cell) {
+                    continue;
+                }
+                if cursor != Some((column, row)) {
+                    let x = self.origin.0 + column as u16 * self.mode.cell_columns();
+                    write!(self.buffer, "{}", termion::cursor::Goto(x, self.origin.1 + row as u16))?;
+                }
+                match self.colours {
+                    Some(colours) => {
+                        let (text, colour) = self.mode.coloured_glyph(cell, &colours.theme);
+                        if style != Some(colour) {
+                            let (foreground, background) = colour;
+                            self.buffer.extend_from_slice(colours.depth.foreground(foreground).as_bytes());
+                            self.buffer.extend_from_slice(colours.depth.background(background).as_bytes());
+                            style = Some(colour);
+                        }
+                        self.buffer.extend_from_slice(text.as_bytes());
+                    }
+                    None => {
+                        let mut utf8 = [0; 4];
+                        self.buffer
+                            .extend_from_slice(self.mode.glyph(cell).encode_utf8(&mut utf8).as_bytes());
+                    }
+                }
+                cursor = Some((column + 1, row));
+            }
+        }
+        if style.is_some() {
+            write!(self.buffer, "{}", termion::style::Reset)?;
+        }
+
+        self.drawn = Some(cells);
+        Ok(())
+    }
+}
+
+impl<W: Write> Renderer for TerminalRenderer<W> {
+    fn render(&mut self, chip: &Chip8) -> io::Result<()> {
+        self.buffer.clear();
+        if self.too_small {
+            // drawn once, until the terminal is resized
+            if self.drawn.is_none() {
+                self.draw_too_small()?;
+                self.drawn = Some(Vec::new());
+            }
+            self.message_changed = false;
+            self.panel_changed = false;
+        } else {
+            self.draw_frame(&chip.frame())?;
+            if let Some(keypad) = &mut self.keypad {
+                keypad.draw(&mut self.buffer, chip.keys())?;
+            }
+        }
+        if self.message_changed {
+            write!(
+                self.buffer,
+                "{}{}{}",
+                termion::cursor::Goto(self.origin.0, self.message_row()),
+                termion::clear::CurrentLine,
+                self.message
+            )?;
+            self.message_changed = false;
+        }
+        if self.panel_changed {
+            self.draw_panel()?;
+            self.panel_changed = false;
+        }
+
+        if !self.buffer.is_empty() {
+            self.out.write_all(&self.buffer)?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.message = message.to_string();
+        self.message_changed = true;
+        Ok(())
+    }
+
+    fn next_mode(&mut self) -> Option<String> {
+        self.set_mode(self.preferred.next());
+        if self.mode == self.preferred {
+            Some(format!("Renderer: {}", self.mode.name()))
+        } else {
+            Some(format!("Renderer: {} ({} does not fit)", self.mode.name(), self.preferred.name()))
+        }
+    }
+
+    fn render_mode(&self) -> Option<RenderMode> {
+        Some(self.preferred)
+    }
+
+    fn resize(&mut self, size: TerminalSize) {
+        self.size = Some(size);
+        self.layout();
+    }
+
+    fn panel(&mut self, lines: &[String]) {
+        let relayout = lines.len() != self.panel.len();
+        self.panel = lines.to_vec();
+        self.panel_changed = true;
+        if relayout {
+            self.layout();
+        }
+    }
+
+    fn show_keypad(&mut self, show: bool) {
+        if show != self.keypad.is_some() {
+            if !show {
+                let _ = self.out.write_all(EXIT_MOUSE.as_bytes());
+            }
+            self.keypad = show.then(Keypad::new);
+            self.layout();
+        }
+    }
+
+    fn key_at(&self, column: u16, row: u16) -> Option<u8> {
+        match &self.keypad {
+            Some(keypad) if !self.too_small => keypad.key_at(column, row),
+            _ => None,
+        }
+    }
+}
+
+impl<W: Write> Drop for TerminalRenderer<W> {
+    fn drop(&mut self) {
+        // leave the cursor below the display
+        let row = self.message_row() + self.panel.len() as u16 + 1;
+        let _ = write!(self.out, "{}{}", termion::cursor::Goto(1, row), termion::cursor::Show);
+        if self.keypad.is_some() {
+            let _ = self.out.write_all(EXIT_MOUSE.as_bytes());
+        }
+        let _ = self.out.flush();
+    }
+}
+
+// turn mouse reporting on and off, with clicks reported as SGR sequences
+pub const ENTER_MOUSE: &str = "\x1b[?1000h\x1b[?1002h\x1b[?1015h\x1b[?1006h";
+pub const EXIT_MOUSE: &str = "\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l";
+
+// the keypad as laid out on the COSMAC VIP
+const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// An on-screen keypad, drawn beside the display. Keys are shown inverted
+// while they are held, and can be clicked.
+pub struct Keypad {
+    origin: (u16, u16),  // top left cell, including the space before the keys
+    drawn: Option<u16>, // the keys shown held
+}
+
+impl Keypad {
+    // two columns of space, then three for each key
+    pub const COLUMNS: u16 = 2 + 4 * 3;
+
+    pub fn new() -> Keypad {
+        Keypad {
+            origin: (1, 1),
+            drawn: None,
+        }
+    }
+
+    pub fn place(&mut self, origin: (u16, u16)) {
+        self.origin = origin;
+        self.invalidate();
+    }
+
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    // draw the keys if they have changed since they were last drawn
+    pub fn draw<W: Write>(&mut self, out: &mut W, keys: u16) -> io::Result<()> {
+        if self.drawn == Some(keys) {
+            return Ok(());
+        }
+        for (row, layout_row) in (self.origin.1..).zip(&KEYPAD_LAYOUT) {
+            write!(out, "{}", termion::cursor::Goto(self.origin.0 + 2, row))?;
+            for &key in layout_row {
+                if keys & (1 << key) != 0 {
+                    write!(out, "{} {:X} {}", termion::style::Invert, key, termion::style::NoInvert)?;
+                } else {
+                    write!(out, " {:X} ", key)?;
+                }
+            }
+        }
+        self.drawn = Some(keys);
+        Ok(())
+    }
+
+    pub fn key_at(&self, column: u16, row: u16) -> Option<u8> {
+        let column = column.checked_sub(self.origin.0 + 2)?;
+        let row = row.checked_sub(self.origin.1)?;
+        KEYPAD_LAYOUT.get(row as usize)?.get(column as usize / 3).copied()
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Keypad {
+        Keypad::new()
+    }
+}
+
+// Puts the terminal back the way it was when dropped, and before a panic
+// message is printed, which would otherwise be drawn in raw mode over the
+// display with the cursor hidden.
+pub struct TerminalGuard {
+    original: Option<libc::termios>,
+    previous: Arc<PanicHook>, // the panic hook to put back when dropped
+}
+
+type PanicHook = dyn Fn(&panic::PanicHookInfo) + Send + Sync;
+
+impl TerminalGuard {
+    // create before switching to raw mode
+    pub fn new() -> TerminalGuard {
+        let original = unsafe {
+            let mut termios = mem::zeroed();
+            (libc::tcgetattr(libc::STDOUT_FILENO, &mut termios) == 0).then_some(termios)
+        };
+
+        let previous: Arc<PanicHook> = panic::take_hook().into();
+        let hook = Arc::clone(&previous);
+        panic::set_hook(Box::new(move |info| {
+            restore(original.as_ref());
+            hook(info);
+        }));
+
+        TerminalGuard { original, previous }
+    }
+}
+
+impl Default for TerminalGuard {
+    fn default() -> TerminalGuard {
+        TerminalGuard::new()
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore(self.original.as_ref());
+        // hooks can't be changed while unwinding
+        if !thread::panicking() {
+            let previous = Arc::clone(&self.previous);
+            panic::set_hook(Box::new(move |info| previous(info)));
+        }
+    }
+}
+
+fn restore(original: Option<&libc::termios>) {
+    let mut stdout = io::stdout();
+    // reset colours, show the cursor, turn off the visual bell and mouse
+    // reporting
+    let _ = write!(
+        stdout,
+        "{}{}\x1b[?5l{}\r\n",
+        termion::style::Reset,
+        termion::cursor::Show,
+        EXIT_MOUSE
+    );
+    let _ = stdout.flush();
+    if let Some(termios) = original {
+        unsafe {
+            libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+pub fn terminal_size() -> io::Result<TerminalSize> {
+    let (columns, rows) = termion::terminal_size()?;
+    // not every terminal reports its size in pixels
+    let (width, height) = termion::terminal_size_pixels().unwrap_or((0, 0));
+    Ok(TerminalSize {
+        columns,
+        rows,
+        width,
+        height,
+    })
+}
+
+// reads keys and mouse clicks without blocking, and reports when the
+// terminal is resized
+//
+// Terminals only report key presses, so a key is held for the frame after
+// it is pressed (or repeated).
+pub struct TerminalInput {
+    events: Events<io::Chain<io::Cursor<Vec<u8>>, AsyncReader>>,
+    resized: Arc<AtomicBool>, // set by SIGWINCH
+    keymap: KeyMap,
+    command: Option<String>, // the command being typed after `:`
+}
+
+impl TerminalInput {
+    pub fn new() -> io::Result<TerminalInput> {
+        TerminalInput::from_reader(Vec::new(), termion::async_stdin())
+    }
+
+    // read `typed` and then from a reader already taken from
+    // `termion::async_stdin`, there can only be one
+    pub fn from_reader(typed: Vec<u8>, reader: AsyncReader) -> io::Result<TerminalInput> {
+        let resized = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGWINCH, Arc::clone(&resized))?;
+        Ok(TerminalInput {
+            events: io::Cursor::new(typed).chain(reader).events(),
+            resized,
+            keymap: KeyMap::default(),
+            command: None,
+        })
+    }
+
+    pub fn set_keymap(&mut self, keymap: KeyMap) {
+        self.keymap = keymap;
+    }
+}
+
+impl Input for TerminalInput {
+    fn poll(&mut self) -> io::Result<Poll> {
+        let mut poll = Poll::default();
+        if self.resized.swap(false, Ordering::Relaxed) {
+            poll.actions.push(Action::Resize(terminal_size()?));
+        }
+        for event in &mut self.events {
+            let key = match event? {
+                Event::Key(key) => key,
+                Event::Mouse(MouseEvent::Press(MouseButton::Left, column, row)) => {
+                    poll.actions.push(Action::MouseDown { column, row });
+                    continue;
+                }
+                Event::Mouse(MouseEvent::Release(..)) => {
+                    poll.actions.push(Action::MouseUp);
+                    continue;
+                }
+                _ => continue,
+            };
+            match key {
+                Key::Ctrl('c') => poll.actions.push(Action::Quit),
+                _ if self.command.is_some() => edit_command(&mut self.command, key, &mut poll.actions),
+                Key::Char(':') => {
+                    self.command = Some(String::new());
+                    poll.actions.push(Action::Typing(Some(String::new())));
+                }
+                Key::Ctrl('s') => poll.actions.push(Action::Screenshot),
+                Key::Ctrl('r') => poll.actions.push(Action::ToggleRecording),
+                Key::Char('\t') => poll.actions.push(Action::NextRenderMode),
+                Key::Char('p') => poll.actions.push(Action::Pause),
+                Key::Char('+') | Key::Char('=') => poll.actions.push(Action::SpeedUp),
+                Key::Char('-') => poll.actions.push(Action::SpeedDown),
+                Key::Char('i') => poll.actions.push(Action::ToggleStatus),
+                Key::Char('h') => poll.actions.push(Action::ToggleHud),
+                Key::Char('k') => poll.actions.push(Action::ToggleKeypad),
+                Key::Char(c) => {
+                    if let Some(key) = self.keymap.key(c) {
+                        poll.keys |= 1 << key;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(poll)
+    }
+}
+
+// type a key into the command, entering it with Enter or cancelling it
+// with Escape
+fn edit_command(typing: &mut Option<String>, key: Key, actions: &mut Vec<Action>) {
+    let command = match typing {
+        Some(command) => command,
+        None => return,
+    };
+    match key {
+        Key::Char('\n') => {
+            let command = mem::take(command);
+            *typing = None;
+            actions.push(Action::Typing(None));
+            actions.push(Action::Command(command));
+        }
+        Key::Esc => {
+            *typing = None;
+            actions.push(Action::Typing(None));
+        }
+        Key::Backspace => {
+            command.pop();
+            actions.push(Action::Typing(Some(command.clone())));
+        }
+        Key::Char(c) if !c.is_control() => {
+            command.push(c);
+            actions.push(Action::Typing(Some(command.clone())));
+        }
+        _ => {}
+    }
+}
+
+// the keyboard key pressed for each keypad key, from 0 to F, by default
+// laid out as
+//
+//   1 2 3 4        0 1 2 3
+//   q w e r   ->   4 5 6 7
+//   a s d f        8 9 A B
+//   z x c v        C D E F
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyMap([char; 16]);
+
+// keys the player uses itself
+const PLAYER_KEYS: &str = "\tp+=-ihk:";
+
+impl KeyMap {
+    // sixteen different characters, for the keypad keys in order
+    pub fn parse(s: &str) -> Result<KeyMap, String> {
+        let chars: Vec<char> = s.chars().collect();
+        let keys: [char; 16] = chars
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("keymap '{}' should be 16 characters, one for each key from 0 to F", s))?;
+        for (i, &c) in keys.iter().enumerate() {
+            if keys[..i].contains(&c) {
+                return Err(format!("keymap '{}' has '{}' more than once", s, c));
+            }
+            if PLAYER_KEYS.contains(c) || c.is_control() {
+                return Err(format!("keymap '{}' uses '{}', which the player needs", s, c.escape_default()));
+            }
+        }
+        Ok(KeyMap(keys))
+    }
+
+    // the keypad key for a keyboard key
+    pub fn key(&self, c: char) -> Option<u8> {
+        self.0.iter().position(|&key| key == c).map(|key| key as u8)
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap::parse("1234qwerasdfzxcv").unwrap()
+    }
+}
+
+impl fmt::Display for KeyMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|&c| write!(f, "{}", c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theme::ColourDepth;
+
+    fn output(renderer: &mut TerminalRenderer<Vec<u8>>) -> String {
+        String::from_utf8(std::mem::take(&mut renderer.out)).unwrap()
+    }
+
+    #[test]
+    fn only_changed_pixels_are_drawn() {
+        let mut chip = Chip8::with_seed(0);
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+
+        renderer.render(&chip).unwrap();
+        let first = output(&mut renderer);
+        assert!(first.contains(&termion::clear::All.to_string()));
+        assert_eq!(first.matches('⬛').count(), 64 * 32);
+
+        renderer.render(&chip).unwrap();
+        assert_eq!(output(&mut renderer), "");
+
+        // two pixels next to each other, and one on the next row
+        chip.write(0xF00 + 8 * 3 + 1, 0b0000_0011);
+        chip.write(0xF00 + 8 * 4, 0b1000_0000);
+        renderer.render(&chip).unwrap();
+        let expected = format!(
+            "{}⬜⬜{}⬜",
+            termion::cursor::Goto(14 * 2 + 1, 4),
+            termion::cursor::Goto(1, 5)
+        );
+        assert_eq!(output(&mut renderer), expected);
+    }
+
+    #[test]
+    fn messages_are_drawn_with_the_next_frame() {
+        let chip = Chip8::with_seed(0);
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+        renderer.render(&chip).unwrap();
+        output(&mut renderer);
+
+        renderer.message("Saved").unwrap();
+        assert_eq!(output(&mut renderer), "");
+        renderer.render(&chip).unwrap();
+        let expected = format!(
+            "{}{}Saved",
+            termion::cursor::Goto(1, 34),
+            termion::clear::CurrentLine
+        );
+        assert_eq!(output(&mut renderer), expected);
+
+        renderer.invalidate();
+        renderer.render(&chip).unwrap();
+        assert!(output(&mut renderer).ends_with("Saved"));
+    }
+
+    #[test]
+    fn cells_are_drawn_in_each_mode() {
+        let mut chip = Chip8::with_seed(0);
+        // the top left 2x4 pixels, with the bottom right one unlit
+        for row in 0..4 {
+            chip.write(0xF00 + 8 * row, if row == 3 { 0b1000_0000 } else { 0b1100_0000 });
+        }
+
+        let glyphs = [
+            (RenderMode::HalfBlocks, "█"),
+            (RenderMode::Ascii, ":"),
+            (RenderMode::Braille, "\u{287f}"),
+        ];
+        for (mode, glyph) in glyphs {
+            let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+            renderer.render(&chip).unwrap();
+            output(&mut renderer);
+
+            renderer.set_mode(mode);
+            renderer.render(&chip).unwrap();
+            let drawn = output(&mut renderer);
+            assert!(drawn.starts_with(&format!("{}{}{}{}", termion::cursor::Hide, termion::clear::All, termion::cursor::Goto(1, 1), glyph)));
+        }
+    }
+
+    #[test]
+    fn glyphs() {
+        assert_eq!(RenderMode::HalfBlocks.glyph(0b01), '▀');
+        assert_eq!(RenderMode::HalfBlocks.glyph(0b10), '▄');
+        assert_eq!(RenderMode::Braille.glyph(0), '\u{2800}');
+        assert_eq!(RenderMode::Braille.glyph(0b0000_0001), '⠁');
+        assert_eq!(RenderMode::Braille.glyph(0b0000_0010), '⠈');
+        assert_eq!(RenderMode::Braille.glyph(0b1000_0000), '⢀');
+        assert_eq!(RenderMode::Braille.glyph(0xFF), '⣿');
+    }
+
+    #[test]
+    fn render_mode_names() {
+        for mode in RenderMode::ALL {
+            assert_eq!(RenderMode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(RenderMode::Ascii.next(), RenderMode::Squares);
+    }
+
+    #[test]
+    fn coloured_cells() {
+        let mut chip = Chip8::with_seed(0);
+        chip.write(0xF00, 0b1000_0000);
+        let colours = Colours {
+            theme: Theme::parse("000000,ffffff").unwrap(),
+            depth: ColourDepth::TrueColour,
+        };
+        let black = ColourDepth::TrueColour.background([0; 3]);
+        let white = ColourDepth::TrueColour.background([0xFF; 3]);
+
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+        renderer.set_colours(Some(colours));
+        renderer.render(&chip).unwrap();
+        let drawn = output(&mut renderer);
+        // the first pixel is lit, then the rest of the row is drawn in one run
+        let first_row = format!("{}{}  {}{}", ColourDepth::TrueColour.foreground([0xFF; 3]), white, ColourDepth::TrueColour.foreground([0; 3]), black);
+        assert!(drawn.contains(&format!("{}{}{}", first_row, "  ".repeat(63), termion::cursor::Goto(1, 2))));
+        assert_eq!(drawn.matches(&white).count(), 1);
+        assert!(drawn.contains(&termion::style::Reset.to_string()));
+
+        renderer.set_mode(RenderMode::HalfBlocks);
+        renderer.render(&chip).unwrap();
+        let drawn = output(&mut renderer);
+        assert!(drawn.contains(&format!("{}{}▀", ColourDepth::TrueColour.foreground([0xFF; 3]), black)));
+    }
+
+    #[test]
+    fn denser_modes_are_used_in_small_terminals() {
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+        let size = TerminalSize { columns: 80, rows: 24, ..TerminalSize::default() };
+        renderer.resize(size);
+        assert_eq!(renderer.mode(), RenderMode::HalfBlocks);
+        assert_eq!(renderer.origin, (9, 4));
+
+        renderer.resize(TerminalSize { columns: 40, rows: 12, ..size });
+        assert_eq!(renderer.mode(), RenderMode::Braille);
+
+        renderer.resize(TerminalSize { columns: 140, rows: 40, ..size });
+        assert_eq!(renderer.mode(), RenderMode::Squares);
+        assert_eq!(renderer.origin, (7, 4));
+
+        let chip = Chip8::with_seed(0);
+        renderer.render(&chip).unwrap();
+        let drawn = output(&mut renderer);
+        assert!(drawn.contains(&format!("{}⬛", termion::cursor::Goto(7, 4))));
+        assert!(!drawn.contains(&termion::cursor::Goto(1, 1).to_string()));
+    }
+
+    #[test]
+    fn too_small() {
+        let chip = Chip8::with_seed(0);
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::HalfBlocks);
+        renderer.resize(TerminalSize { columns: 20, rows: 5, ..TerminalSize::default() });
+        renderer.render(&chip).unwrap();
+        assert!(output(&mut renderer).ends_with("Terminal too small: 32x10 needed, 20x5 available"));
+        renderer.render(&chip).unwrap();
+        assert_eq!(output(&mut renderer), "");
+
+        renderer.resize(TerminalSize { columns: 64, rows: 18, ..TerminalSize::default() });
+        renderer.render(&chip).unwrap();
+        assert_eq!(output(&mut renderer).matches(' ').count(), 64 * 16);
+    }
+
+    #[test]
+    fn panel_is_drawn_below_the_message() {
+        let chip = Chip8::with_seed(0);
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::Squares);
+        renderer.resize(TerminalSize { columns: 140, rows: 40, ..TerminalSize::default() });
+        assert_eq!(renderer.origin, (7, 4));
+
+        // the display moves up to make room
+        let lines = ["status".to_string(), "x".repeat(200)];
+        renderer.panel(&lines);
+        assert_eq!(renderer.origin, (7, 3));
+        renderer.render(&chip).unwrap();
+        let drawn = output(&mut renderer);
+        let status = format!("{}{}status", termion::cursor::Goto(7, 37), termion::clear::CurrentLine);
+        assert!(drawn.contains(&status));
+        let cut = format!("{}{}{}", termion::cursor::Goto(7, 38), termion::clear::CurrentLine, "x".repeat(134));
+        assert!(drawn.ends_with(&cut));
+
+        // only the panel is drawn when it changes
+        renderer.panel(&["paused".to_string(), String::new()]);
+        renderer.render(&chip).unwrap();
+        assert!(output(&mut renderer).starts_with(&format!("{}", termion::cursor::Goto(7, 37))));
+
+        renderer.panel(&vec![String::new(); 7]);
+        assert_eq!(renderer.mode(), RenderMode::HalfBlocks);
+    }
+
+    #[test]
+    fn keypad_beside_the_display() {
+        let mut chip = Chip8::with_seed(0);
+        let mut renderer = TerminalRenderer::new(Vec::new(), RenderMode::HalfBlocks);
+        renderer.resize(TerminalSize { columns: 80, rows: 24, ..TerminalSize::default() });
+        renderer.show_keypad(true);
+        assert_eq!(renderer.origin, (2, 4));
+
+        chip.write_keypad(0xC, true);
+        renderer.render(&chip).unwrap();
+        let drawn = output(&mut renderer);
+        assert!(drawn.contains(ENTER_MOUSE));
+        let first_row = format!(
+            "{} 1  2  3 {} C {}",
+            termion::cursor::Goto(68, 4),
+            termion::style::Invert,
+            termion::style::NoInvert
+        );
+        assert!(drawn.contains(&first_row));
+
+        // redrawn only when the keys change
+        renderer.render(&chip).unwrap();
+        assert_eq!(output(&mut renderer), "");
+        chip.write_keypad(0xC, false);
+        renderer.render(&chip).unwrap();
+        assert!(output(&mut renderer).contains(" 1  2  3  C "));
+
+        assert_eq!(renderer.key_at(68, 4), Some(0x1));
+        assert_eq!(renderer.key_at(76, 7), Some(0xB));
+        assert_eq!(renderer.key_at(67, 4), None);
+        assert_eq!(renderer.key_at(80, 8), None);
+    }
+
+    #[test]
+    fn keymaps() {
+        let keymap = KeyMap::default();
+        assert_eq!(keymap.key('1'), Some(0x0));
+        assert_eq!(keymap.key('v'), Some(0xF));
+        assert_eq!(keymap.key('p'), None);
+        assert_eq!(keymap.to_string(), "1234qwerasdfzxcv");
+
+        let cosmac = KeyMap::parse("x123qweasdzc4rfv").unwrap();
+        assert_eq!(cosmac.key('x'), Some(0x0));
+        assert_eq!(cosmac.key('4'), Some(0xC));
+        assert!(KeyMap::parse("1234").is_err());
+        assert!(KeyMap::parse("1234qwerasdfzxc1").is_err(), "keys are used once");
+        assert!(KeyMap::parse("1234qwerasdfzxcp").is_err(), "p pauses");
+    }
+
+    #[test]
+    fn typing_commands() {
+        let mut typing = Some(String::new());
+        let mut actions = Vec::new();
+        for key in [Key::Char('p'), Key::Char('x'), Key::Backspace, Key::Char('o'), Key::Left] {
+            edit_command(&mut typing, key, &mut actions);
+        }
+        assert_eq!(typing.as_deref(), Some("po"));
+        assert_eq!(actions.last(), Some(&Action::Typing(Some("po".to_string()))));
+
+        actions.clear();
+        edit_command(&mut typing, Key::Char('\n'), &mut actions);
+        assert_eq!(actions, [Action::Typing(None), Action::Command("po".to_string())]);
+        assert_eq!(typing, None);
+
+        let mut typing = Some("search".to_string());
+        actions.clear();
+        edit_command(&mut typing, Key::Esc, &mut actions);
+        assert_eq!(actions, [Action::Typing(None)]);
+    }
+}
@@ -0,0 +1,257 @@
+// Colour themes for the terminal renderer.
+//
+// A theme has four colours: the background, a colour for each of two
+// drawing planes, and one for pixels set in both planes. Programs that only
+// draw to a single plane use the first two. Colours are written with 16,
+// 256 or 24-bit escape sequences, depending on what the terminal supports.
+
+use crate::screenshot::{parse_rgb, Rgb};
+
+use std::env;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Theme {
+    pub colours: [Rgb; 4], // background, plane 1, plane 2, both planes
+}
+
+// See: https://jfly.uni-koeln.de/color/ for the colour-blind safe palette
+pub const THEMES: [(&str, Theme); 4] = [
+    (
+        "green",
+        Theme {
+            colours: [[0x0A, 0x14, 0x0A], [0x33, 0xFF, 0x66], [0x1A, 0x80, 0x33], [0xB3, 0xFF, 0xC6]],
+        },
+    ),
+    (
+        "amber",
+        Theme {
+            colours: [[0x1A, 0x0F, 0x00], [0xFF, 0xB0, 0x00], [0x99, 0x5C, 0x00], [0xFF, 0xDD, 0x88]],
+        },
+    ),
+    (
+        "high-contrast",
+        Theme {
+            colours: [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xFF, 0xFF, 0x00], [0x00, 0xFF, 0xFF]],
+        },
+    ),
+    (
+        "colour-blind",
+        Theme {
+            colours: [[0x00, 0x00, 0x00], [0xE6, 0x9F, 0x00], [0x56, 0xB4, 0xE9], [0xF0, 0xE4, 0x42]],
+        },
+    ),
+];
+
+impl Theme {
+    // a built-in theme by name, or colours written as `RRGGBB,RRGGBB` with
+    // optional colours for the second plane and both planes
+    pub fn parse(s: &str) -> Result<Theme, String> {
+        if let Some((_, theme)) = THEMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(*theme);
+        }
+        if !s.contains(',') {
+            let names: Vec<&str> = THEMES.iter().map(|(name, _)| *name).collect();
+            return Err(format!("unknown theme '{}', expected one of {} or colours", s, names.join(", ")));
+        }
+
+        let colours = s.split(',').map(parse_rgb).collect::<Result<Vec<Rgb>, String>>()?;
+        match colours[..] {
+            [background, foreground] => Ok(Theme {
+                colours: [background, foreground, foreground, foreground],
+            }),
+            [background, plane1, plane2, both] => Ok(Theme {
+                colours: [background, plane1, plane2, both],
+            }),
+            _ => Err(format!("theme '{}' should be two or four colours", s)),
+        }
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.colours[0]
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.colours[1]
+    }
+}
+
+// the name of a built-in theme, or the colours as `Theme::parse` reads them
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((name, _)) = THEMES.iter().find(|(_, theme)| theme == self) {
+            return f.write_str(name);
+        }
+        // two colours when both planes draw in the first plane's colour
+        let [_, plane1, plane2, both] = self.colours;
+        let count = if plane2 == plane1 && both == plane1 { 2 } else { 4 };
+        let colours: Vec<String> =
+            self.colours[..count].iter().map(|[r, g, b]| format!("{:02x}{:02x}{:02x}", r, g, b)).collect();
+        f.write_str(&colours.join(","))
+    }
+}
+
+// the colours a terminal can show
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColourDepth {
+    Ansi16,
+    Ansi256,
+    TrueColour,
+}
+
+impl ColourDepth {
+    pub fn parse(s: &str) -> Option<ColourDepth> {
+        match s {
+            "16" => Some(ColourDepth::Ansi16),
+            "256" => Some(ColourDepth::Ansi256),
+            "24bit" | "truecolor" | "truecolour" => Some(ColourDepth::TrueColour),
+            _ => None,
+        }
+    }
+
+    // guess from the environment, or `None` when NO_COLOR is set
+    //
+    // See: https://no-color.org/
+    pub fn detect() -> Option<ColourDepth> {
+        if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+            return None;
+        }
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        Some(if colorterm == "truecolor" || colorterm == "24bit" {
+            ColourDepth::TrueColour
+        } else if term.contains("256color") {
+            ColourDepth::Ansi256
+        } else {
+            ColourDepth::Ansi16
+        })
+    }
+
+    // escape sequence setting the foreground colour
+    pub fn foreground(self, colour: Rgb) -> String {
+        self.sgr(colour, 30)
+    }
+
+    // escape sequence setting the background colour
+    pub fn background(self, colour: Rgb) -> String {
+        self.sgr(colour, 40)
+    }
+
+    // `base` is 30 for the foreground and 40 for the background
+    fn sgr(self, [r, g, b]: Rgb, base: u8) -> String {
+        match self {
+            ColourDepth::TrueColour => format!("\x1b[{};2;{};{};{}m", base + 8, r, g, b),
+            ColourDepth::Ansi256 => format!("\x1b[{};5;{}m", base + 8, ansi256([r, g, b])),
+            ColourDepth::Ansi16 => {
+                let index = ansi16([r, g, b]);
+                // the bright colours are 90-97 and 100-107
+                let code = if index < 8 { base + index } else { base + 60 + index - 8 };
+                format!("\x1b[{}m", code)
+            }
+        }
+    }
+}
+
+// the xterm defaults for the 16 standard colours
+const ANSI16: [Rgb; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xCD, 0x00, 0x00],
+    [0x00, 0xCD, 0x00],
+    [0xCD, 0xCD, 0x00],
+    [0x00, 0x00, 0xEE],
+    [0xCD, 0x00, 0xCD],
+    [0x00, 0xCD, 0xCD],
+    [0xE5, 0xE5, 0xE5],
+    [0x7F, 0x7F, 0x7F],
+    [0xFF, 0x00, 0x00],
+    [0x00, 0xFF, 0x00],
+    [0xFF, 0xFF, 0x00],
+    [0x5C, 0x5C, 0xFF],
+    [0xFF, 0x00, 0xFF],
+    [0x00, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0xFF],
+];
+
+// the levels used by each channel of the 6x6x6 colour cube in the 256
+// colour palette
+const CUBE_LEVELS: [u8; 6] = [0x00, 0x5F, 0x87, 0xAF, 0xD7, 0xFF];
+
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    a.iter()
+        .zip(&b)
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+fn ansi16(colour: Rgb) -> u8 {
+    (0..16).min_by_key(|&i| distance(colour, ANSI16[i])).unwrap() as u8
+}
+
+// the nearest colour in the cube or the grey ramp
+fn ansi256(colour: Rgb) -> u8 {
+    let level = |channel: u8| {
+        (0..6)
+            .min_by_key(|&i| (channel as i32 - CUBE_LEVELS[i] as i32).abs())
+            .unwrap()
+    };
+    let [r, g, b] = colour.map(level);
+    let cube = [CUBE_LEVELS[r], CUBE_LEVELS[g], CUBE_LEVELS[b]];
+
+    let average = colour.iter().map(|&c| c as u32).sum::<u32>() / 3;
+    let grey_index = (average.saturating_sub(3) / 10).min(23);
+    let grey_level = (8 + grey_index * 10) as u8;
+
+    if distance(colour, [grey_level; 3]) < distance(colour, cube) {
+        232 + grey_index as u8
+    } else {
+        16 + (36 * r + 6 * g + b) as u8
+    }
+}
+
+// a theme and how to write it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Colours {
+    pub theme: Theme,
+    pub depth: ColourDepth,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_themes() {
+        assert_eq!(Theme::parse("Amber"), Ok(THEMES[1].1));
+        let theme = Theme::parse("000000,ff8000").unwrap();
+        assert_eq!(theme.colours, [[0, 0, 0], [0xFF, 0x80, 0], [0xFF, 0x80, 0], [0xFF, 0x80, 0]]);
+        let theme = Theme::parse("000000,111111,222222,333333").unwrap();
+        assert_eq!(theme.colours[3], [0x33, 0x33, 0x33]);
+        assert!(Theme::parse("sepia").is_err());
+        assert!(Theme::parse("000000,111111,222222").is_err());
+    }
+
+    #[test]
+    fn themes_are_written_as_they_are_parsed() {
+        for theme in ["amber", "000000,ff8000", "000000,111111,222222,333333"] {
+            assert_eq!(Theme::parse(theme).unwrap().to_string(), theme);
+        }
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let orange = [0xFF, 0x80, 0x00];
+        assert_eq!(ColourDepth::TrueColour.foreground(orange), "\x1b[38;2;255;128;0m");
+        assert_eq!(ColourDepth::TrueColour.background(orange), "\x1b[48;2;255;128;0m");
+        assert_eq!(ColourDepth::Ansi256.foreground(orange), "\x1b[38;5;208m");
+        assert_eq!(ColourDepth::Ansi16.foreground([0, 0, 0]), "\x1b[30m");
+        assert_eq!(ColourDepth::Ansi16.background([0xFF, 0xFF, 0xFF]), "\x1b[107m");
+    }
+
+    #[test]
+    fn nearest_256_colours() {
+        assert_eq!(ansi256([0, 0, 0]), 16);
+        assert_eq!(ansi256([0xFF, 0xFF, 0xFF]), 231);
+        assert_eq!(ansi256([0x80, 0x80, 0x80]), 244);
+        assert_eq!(ansi256([0x5F, 0x87, 0xAF]), 16 + 36 + 12 + 3);
+    }
+}
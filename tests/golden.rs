@@ -0,0 +1,111 @@
+// Golden image regression tests for the example roms.
+//
+// Each rom is run with a fixed seed and scripted input, and the display at a
+// few checkpoint frames is compared against the reference in
+// tests/golden/<rom>.txt. After an intentional change in behaviour, update
+// the references with:
+//
+//     BLESS=1 cargo test --test golden
+
+use chip8::headless::{self, HeadlessConfig, KeyScript};
+use chip8::rom::{self, LoadOptions};
+use chip8::Chip8;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const SEED: u64 = 0x5EED;
+
+fn load(rom: &str) -> Chip8 {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples").join(rom);
+    let mut chip = Chip8::with_seed(SEED);
+    rom::load_file(&mut chip, path.to_str().unwrap(), &LoadOptions::default())
+        .unwrap_or_else(|e| panic!("unable to load {:?}: {}", path, e));
+    chip
+}
+
+// run `rom` to each of the (ascending) checkpoint frames, returning the
+// displays in the reference file format
+fn run(rom: &str, keys: &str, checkpoints: &[u32]) -> String {
+    let mut chip = load(rom);
+    let config = HeadlessConfig {
+        frames: *checkpoints.last().unwrap(),
+        keys: KeyScript::parse(keys).unwrap(),
+        ..HeadlessConfig::default()
+    };
+
+    let mut output = String::new();
+    let mut frame = 0;
+    headless::run_with(&mut chip, &config, |chip| {
+        frame += 1;
+        if checkpoints.contains(&frame) {
+            output.push_str(&format!("frame {}\n", frame));
+            output.push_str(&headless::display_to_text(chip));
+        }
+    })
+    .unwrap_or_else(|e| panic!("{} raised an error: {}", rom, e));
+
+    output
+}
+
+fn check(rom: &str, keys: &str, checkpoints: &[u32]) {
+    let actual = run(rom, keys, checkpoints);
+
+    let name = rom.trim_end_matches(".ch8");
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.txt", name));
+
+    if env::var_os("BLESS").is_some() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "missing reference {:?}, create it with `BLESS=1 cargo test --test golden`",
+            path
+        )
+    });
+
+    // report the first checkpoint that differs
+    let split = |s: &str| {
+        s.split("frame ")
+            .filter(|block| !block.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    for (actual, expected) in split(&actual).iter().zip(split(&expected).iter()) {
+        assert!(
+            actual == expected,
+            "{} differs from the reference at frame {}\nexpected:\n{}\nactual:\n{}",
+            rom,
+            expected.lines().next().unwrap_or(""),
+            expected,
+            actual
+        );
+    }
+    assert_eq!(actual, expected, "{} checkpoints differ from the reference", rom);
+}
+
+#[test]
+fn display_number() {
+    check("display_number.ch8", "30:5,32:,90:a,92:,150:f,152:", &[20, 60, 120, 200]);
+}
+
+#[test]
+fn pong() {
+    check("pong.ch8", "0:1,40:,60:4,100:,120:d,160:c,200:", &[30, 120, 240, 480]);
+}
+
+#[test]
+fn snake() {
+    check("snake.ch8", "", &[60, 150, 240, 300]);
+}
+
+#[test]
+fn octojam2title() {
+    check("octojam2title.ch8", "", &[30, 120, 300, 600]);
+}
@@ -0,0 +1,35 @@
+// Replays the movies in tests/movies against the example roms.
+//
+// Each movie was recorded in the player with `--record-movie`, and has to
+// end on the display and machine state it was recorded with. Record more with:
+//
+//     cargo run -- examples/<rom>.ch8 --record-movie tests/movies/<name>.toml
+
+use chip8::database::sha1_hex;
+use chip8::movie::Movie;
+
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn movies_replay_exactly() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let roms: Vec<Vec<u8>> = fs::read_dir(root.join("examples"))
+        .unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+
+    let mut replayed = 0;
+    for entry in fs::read_dir(root.join("tests").join("movies")).unwrap() {
+        let path = entry.unwrap().path();
+        let movie = Movie::parse(&fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|e| panic!("unable to read {:?}: {}", path, e));
+        let rom = roms
+            .iter()
+            .find(|rom| sha1_hex(rom) == movie.rom)
+            .unwrap_or_else(|| panic!("{:?} is not a movie of an example", path));
+        movie.replay(rom).unwrap_or_else(|e| panic!("{:?} did not replay: {}", path, e));
+        replayed += 1;
+    }
+    assert!(replayed > 0);
+}